@@ -0,0 +1,24 @@
+create table follows
+(
+    -- The user who is following another user
+    follower_id uuid        not null references users (user_id) on delete cascade,
+    -- The user being followed
+    followed_id uuid        not null references users (user_id) on delete cascade,
+    created_at  timestamptz not null default now(),
+    primary key (follower_id, followed_id),
+    check (follower_id <> followed_id)
+);
+
+create index on follows (followed_id);
+
+create table tag_follows
+(
+    user_id    uuid        not null references users (user_id) on delete cascade,
+    tag_id     bigint      not null references tags (tag_id)   on delete cascade,
+    created_at timestamptz not null default now(),
+    primary key (user_id, tag_id)
+);
+
+-- The feed is ordered by the last time a schematic was created or updated so
+-- index on the same expression to avoid sorting every followed schematic
+create index on schematics (author, (coalesce(updated_at, created_at)));
@@ -0,0 +1,6 @@
+-- Comments with replies are not removed outright as that would cascade to every
+-- reply beneath them, instead their body is cleared and they are flagged so they
+-- can be shown as a placeholder keeping the rest of the thread intact
+alter table comments add column is_deleted boolean not null default false;
+
+create index on comments (parent);
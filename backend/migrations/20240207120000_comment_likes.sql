@@ -0,0 +1,11 @@
+create table comment_likes
+(
+    comment_id uuid        not null references comments (comment_id) on delete cascade,
+    user_id    uuid        not null references users    (user_id)    on delete cascade,
+    positive   boolean     not null,
+    created_at timestamptz not null default now(),
+    updated_at timestamptz,
+    primary key (comment_id, user_id)
+);
+
+select trigger_updated_at('comment_likes');
@@ -0,0 +1,7 @@
+-- Markdown is rendered to sanitized html when content is written so it does not
+-- need to be rendered on every request, the raw markdown is kept so that it can
+-- still be edited. Content written before this was introduced will not have any
+-- rendered html
+alter table schematics add column body_html text;
+alter table comments add column comment_html text;
+alter table users add column about_html text;
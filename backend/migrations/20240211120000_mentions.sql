@@ -0,0 +1,27 @@
+-- Mentions are stored against the user rather than the username they were written
+-- with, this means clients can still link to the correct profile after a user has
+-- changed their username. Spans are character offsets into the body covering the
+-- leading '@' and are replaced entirely whenever the body is edited
+create table comment_mentions
+(
+    comment_id uuid        not null references comments (comment_id) on delete cascade,
+    user_id    uuid        not null references users (user_id)       on delete cascade,
+    span_start integer     not null,
+    span_end   integer     not null,
+    created_at timestamptz not null default now(),
+    primary key (comment_id, span_start)
+);
+
+create index on comment_mentions (user_id);
+
+create table schematic_mentions
+(
+    schematic_id uuid        not null references schematics (schematic_id) on delete cascade,
+    user_id      uuid        not null references users (user_id)           on delete cascade,
+    span_start   integer     not null,
+    span_end     integer     not null,
+    created_at   timestamptz not null default now(),
+    primary key (schematic_id, span_start)
+);
+
+create index on schematic_mentions (user_id);
@@ -0,0 +1,3 @@
+-- Only top level comments can be pinned, this is enforced when pinning rather than
+-- with a constraint so that replies can be pinned in the future if wanted
+alter table comments add column is_pinned boolean not null default false;
@@ -0,0 +1,5 @@
+-- The slow mode is the minimum number of seconds a user must wait between posting
+-- comments on the schematic, if it is null then there is no limit
+alter table schematics 
+    add column comments_locked   boolean not null default false,
+    add column comment_slow_mode integer check (comment_slow_mode > 0);
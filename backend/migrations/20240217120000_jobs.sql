@@ -0,0 +1,27 @@
+-- Jobs are claimed by workers using `for update skip locked` so that any number of
+-- workers can run against the same database, if a worker dies while running a job
+-- it will be picked up again once its lock has expired
+create table jobs
+(
+    job_id       uuid        primary key default uuid_generate_v1mc(),
+    -- The user who caused the job to be queued, if any, only they are able to
+    -- view the status of the job
+    user_id      uuid                    references users (user_id) on delete set null,
+    payload      jsonb       not null,
+    status       text        not null    default 'pending',
+    attempts     integer     not null    default 0,
+    max_attempts integer     not null    default 5,
+    last_error   text,
+    run_at       timestamptz not null    default now(),
+    locked_at    timestamptz,
+    created_at   timestamptz not null    default now(),
+    updated_at   timestamptz
+);
+
+select trigger_updated_at('jobs');
+
+create index on jobs (run_at) where status = 'pending';
+
+-- Schematics are only published once all of their files and images have been
+-- processed, existing schematics have already been processed
+alter table schematics add column status text not null default 'published';
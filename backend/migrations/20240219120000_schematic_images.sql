@@ -0,0 +1,13 @@
+-- Metadata about processed images, each image is stored in several sizes and formats
+-- which are named after the original file, see `storage::images`. Rows are only
+-- created once an image has been processed
+create table schematic_images
+(
+    schematic_id uuid        not null references schematics (schematic_id) on delete cascade,
+    file_name    text        not null,
+    width        integer     not null,
+    height       integer     not null,
+    blurhash     text        not null,
+    created_at   timestamptz not null default now(),
+    primary key (schematic_id, file_name)
+);
@@ -0,0 +1,43 @@
+-- Images are now tracked entirely within schematic_images rather than the images
+-- array on schematics, rows are created when an image is uploaded so the details
+-- only known once it has been processed are nullable
+alter table schematic_images
+    alter column width drop not null,
+    alter column height drop not null,
+    alter column blurhash drop not null,
+    add column position integer not null default 0,
+    add column caption  text,
+    add column alt_text text,
+    add column is_cover boolean not null default false;
+
+insert into schematic_images (schematic_id, file_name, position, is_cover)
+select distinct on (schematic_id, file_name)
+    schematic_id, file_name, (position - 1)::integer, position = 1
+from schematics
+cross join unnest(images) with ordinality as image(file_name, position)
+order by schematic_id, file_name, position
+on conflict (schematic_id, file_name) do update
+    set position = excluded.position,
+        is_cover = excluded.is_cover;
+
+alter table schematics drop column images;
+
+-- Deferred so that images can be reordered within a single statement
+alter table schematic_images
+    add constraint schematic_images_position_key unique (schematic_id, position)
+    deferrable initially deferred;
+
+create unique index schematic_images_cover_idx on schematic_images (schematic_id) where is_cover;
+
+-- Used to return a schematics images alongside it, see `SchematicImage`
+create type schematic_image as
+(
+    file_name text,
+    position  integer,
+    caption   text,
+    alt_text  text,
+    is_cover  boolean,
+    width     integer,
+    height    integer,
+    blurhash  text
+);
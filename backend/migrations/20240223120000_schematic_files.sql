@@ -0,0 +1,35 @@
+-- Schematic files were previously only tracked by name in the files array, details
+-- read from the file itself are nullable as they are only known once processed
+create table schematic_files
+(
+    schematic_id uuid        not null references schematics (schematic_id) on delete cascade,
+    file_name    text        not null,
+    display_name text,
+    description  text,
+    position     integer     not null default 0,
+    format       text        not null default 'structure',
+    file_size    integer,
+    width        integer,
+    height       integer,
+    length       integer,
+    block_count  integer,
+    created_at   timestamptz not null default now(),
+    updated_at   timestamptz,
+    primary key (schematic_id, file_name)
+);
+
+select trigger_updated_at('schematic_files');
+
+insert into schematic_files (schematic_id, file_name, position)
+select distinct on (schematic_id, file_name)
+    schematic_id, file_name, (position - 1)::integer
+from schematics
+cross join unnest(files) with ordinality as file(file_name, position)
+order by schematic_id, file_name, position;
+
+alter table schematics drop column files;
+
+-- Deferred so that files can be reordered within a single statement
+alter table schematic_files
+    add constraint schematic_files_position_key unique (schematic_id, position)
+    deferrable initially deferred;
@@ -0,0 +1,16 @@
+-- The mods required by each schematic file, mod_dependencies is kept as the set of
+-- mods required by any of a schematics files. Existing files are not backfilled here
+-- as this requires reading them from disk, run the `rebuild-dependencies` command
+-- after migrating to populate this and correct mod_dependencies
+create table file_dependencies
+(
+    schematic_id uuid not null,
+    file_name    text not null,
+    mod_id       uuid not null references mods (mod_id) on delete cascade,
+    primary key (schematic_id, file_name, mod_id),
+    foreign key (schematic_id, file_name)
+        references schematic_files (schematic_id, file_name)
+        on delete cascade
+);
+
+create index file_dependencies_mod_id_idx on file_dependencies (mod_id);
@@ -0,0 +1,13 @@
+-- Details fetched from Modrinth and CurseForge by the sync_mod job, these are
+-- replaced each time a mod is synced whereas names and slugs set by moderators
+-- or approved proposals are kept
+alter table mods
+    add column icon_url       text,
+    add column source_url     text,
+    add column issues_url     text,
+    add column wiki_url       text,
+    add column loaders        text[]      not null default '{}',
+    add column game_versions  text[]      not null default '{}',
+    add column last_synced_at timestamptz;
+
+create index mods_last_synced_at_idx on mods (last_synced_at nulls first);
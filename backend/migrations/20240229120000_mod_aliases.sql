@@ -0,0 +1,24 @@
+-- Namespaces which belong to another mod, such as those used by addons bundled within
+-- the same jar, these are recorded as a dependency on the mod they are aliased to
+create table mod_aliases
+(
+    namespace  text        primary key,
+    mod_id     uuid        not null    references mods (mod_id) on delete cascade,
+    created_at timestamptz not null    default now()
+);
+
+create index mod_aliases_mod_id_idx on mod_aliases (mod_id);
+
+-- Namespaces which are never treated as a mod, for instance vanilla blocks
+create table ignored_namespaces
+(
+    namespace  text        primary key,
+    reason     text,
+    created_at timestamptz not null    default now()
+);
+
+insert into ignored_namespaces (namespace, reason)
+values ('minecraft', 'Vanilla blocks do not require any mods');
+
+-- Removing the mod also removes any dependencies on it
+delete from mods where mod_slug in (select namespace from ignored_namespaces);
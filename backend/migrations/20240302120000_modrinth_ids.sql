@@ -0,0 +1,5 @@
+-- Modpack indexes refer to Modrinth projects by their id rather than their slug, this
+-- is filled in by the sync_mod job so every mod is synced again to fetch it
+alter table mods add column modrinth_id text unique;
+
+update mods set last_synced_at = null;
@@ -0,0 +1,9 @@
+-- The block ids added by each version of Create, used to warn about schematics using
+-- blocks which do not exist in their selected version. This is filled in using the
+-- import-blocks command, versions without any blocks are not checked
+create table create_version_blocks
+(
+    create_version_id integer not null references create_versions (create_version_id) on delete cascade,
+    block_id          text    not null,
+    primary key (create_version_id, block_id)
+);
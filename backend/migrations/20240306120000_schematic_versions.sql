@@ -0,0 +1,35 @@
+-- Schematics previously had a single game and Create version, most builds work across
+-- several so these are now stored as the set of versions a schematic supports
+create table schematic_game_versions
+(
+    schematic_id    uuid    not null references schematics (schematic_id) on delete cascade,
+    game_version_id integer not null references game_versions (game_version_id),
+    primary key (schematic_id, game_version_id)
+);
+
+create index schematic_game_versions_game_version_id_idx on schematic_game_versions (game_version_id);
+
+create table schematic_create_versions
+(
+    schematic_id      uuid    not null references schematics (schematic_id) on delete cascade,
+    create_version_id integer not null references create_versions (create_version_id),
+    primary key (schematic_id, create_version_id)
+);
+
+create index schematic_create_versions_create_version_id_idx on schematic_create_versions (create_version_id);
+
+insert into schematic_game_versions (schematic_id, game_version_id)
+select schematic_id, game_version_id from schematics;
+
+insert into schematic_create_versions (schematic_id, create_version_id)
+select schematic_id, create_version_id from schematics;
+
+alter table schematics
+    drop column game_version_id,
+    drop column create_version_id;
+
+create type schematic_version as
+(
+    version_id   integer,
+    version_name text
+);
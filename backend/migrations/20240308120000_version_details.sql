@@ -0,0 +1,32 @@
+-- Versions can now be managed through the api, the sort order is used to list them as
+-- version names cannot be reliably compared and release dates are not always known
+alter table create_versions
+    add constraint create_versions_create_version_name_key unique (create_version_name),
+    add column release_date date,
+    add column sort_order   integer     not null default 0,
+    add column deprecated   boolean     not null default false,
+    add column updated_at   timestamptz;
+
+alter table game_versions
+    add constraint game_versions_game_version_name_key unique (game_version_name),
+    add column release_date date,
+    add column sort_order   integer     not null default 0,
+    add column deprecated   boolean     not null default false,
+    add column updated_at   timestamptz;
+
+update create_versions
+    set sort_order = array_position(
+        array['0.2.4', '0.3', '0.3.1', '0.3.2', '0.4', '0.4.1', '0.5', '0.5.1'],
+        create_version_name
+    )
+    where create_version_name in ('0.2.4', '0.3', '0.3.1', '0.3.2', '0.4', '0.4.1', '0.5', '0.5.1');
+
+update game_versions
+    set sort_order = array_position(
+        array['1.16.5', '1.18', '1.18.1', '1.18.2', '1.19.2', '1.20.1'],
+        game_version_name
+    )
+    where game_version_name in ('1.16.5', '1.18', '1.18.1', '1.18.2', '1.19.2', '1.20.1');
+
+select trigger_updated_at('create_versions');
+select trigger_updated_at('game_versions');
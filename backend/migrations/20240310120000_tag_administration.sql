@@ -0,0 +1,45 @@
+create table tag_categories
+(
+    category_id   bigserial   primary key,
+    category_name text        not null     unique collate "case_insensitive",
+    created_at    timestamptz not null     default now(),
+    updated_at    timestamptz
+);
+
+select trigger_updated_at('tag_categories');
+
+alter table tags
+    add column description text,
+    add column category_id bigint references tag_categories (category_id) on delete set null;
+
+create index tags_category_id_idx on tags (category_id);
+
+-- Tags suggested by users, these are only created once approved by an administrator
+create table tag_proposals
+(
+    proposal_id uuid        primary key default uuid_generate_v1mc(),
+    user_id     uuid        not null    references users (user_id)              on delete cascade,
+    tag_name    text        not null    unique collate "case_insensitive",
+    description text,
+    category_id bigint                  references tag_categories (category_id) on delete set null,
+    created_at  timestamptz not null    default now()
+);
+
+insert into tag_categories
+    (category_name)
+values
+    ('Trains'),
+    ('Farms'),
+    ('Power');
+
+update tags
+    set category_id = (select category_id from tag_categories where category_name = 'Trains')
+    where tag_name in ('Steam Train', 'Deisel Train', 'Electric Train');
+
+update tags
+    set category_id = (select category_id from tag_categories where category_name = 'Farms')
+    where tag_name in ('Crop Farm', 'Mob Farm');
+
+update tags
+    set category_id = (select category_id from tag_categories where category_name = 'Power')
+    where tag_name in ('Windmill', 'Steam Engine');
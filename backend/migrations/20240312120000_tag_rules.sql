@@ -0,0 +1,40 @@
+-- The number of each block placed within each schematic file, these are used to
+-- suggest tags. Existing files are not backfilled here as this requires reading
+-- them from disk, run the `rebuild-dependencies` command after migrating
+create table file_blocks
+(
+    schematic_id uuid    not null,
+    file_name    text    not null,
+    block_id     text    not null,
+    block_count  integer not null,
+    primary key (schematic_id, file_name, block_id),
+    foreign key (schematic_id, file_name)
+        references schematic_files (schematic_id, file_name)
+        on delete cascade
+);
+
+-- A tag is suggested for a schematic when at least `min_count` of a block are
+-- placed across all of its files, tags may have several rules
+create table tag_rules
+(
+    rule_id    bigserial   primary key,
+    tag_id     bigint      not null    references tags (tag_id) on delete cascade,
+    block_id   text        not null,
+    min_count  integer     not null    default 1 check (min_count > 0),
+    created_at timestamptz not null    default now(),
+    unique (tag_id, block_id)
+);
+
+create index tag_rules_block_id_idx on tag_rules (block_id);
+
+insert into tag_rules
+    (tag_id, block_id, min_count)
+select tag_id, rule.block_id, rule.min_count
+from (
+    values
+        ('Steam Train', 'create:track', 64),
+        ('Windmill', 'create:windmill_bearing', 1),
+        ('Steam Engine', 'create:steam_engine', 1),
+        ('Crop Farm', 'create:mechanical_harvester', 4)
+) as rule(tag_name, block_id, min_count)
+inner join tags using (tag_name);
@@ -0,0 +1,12 @@
+-- The images of a schematic as they are returned alongside it, see `SchematicImage`.
+-- The cover image is always first followed by the rest in order of their position
+create function schematic_images_of(uuid)
+    returns schematic_image[] as
+$$
+    select coalesce(array_agg(
+        row(file_name, position, caption, alt_text, is_cover, width, height, blurhash)::schematic_image
+        order by is_cover desc, position
+    ), array[]::schematic_image[])
+    from schematic_images
+    where schematic_id = $1
+$$ language sql stable;
@@ -0,0 +1,25 @@
+-- The game and Create versions a schematic supports as they are returned alongside
+-- it, see `SchematicVersion`. Versions are ordered from oldest to newest
+create function schematic_game_versions_of(uuid)
+    returns schematic_version[] as
+$$
+    select coalesce(array_agg(
+        row(game_version_id, game_version_name)::schematic_version
+        order by sort_order, game_version_id
+    ), array[]::schematic_version[])
+    from schematic_game_versions
+    inner join game_versions using (game_version_id)
+    where schematic_id = $1
+$$ language sql stable;
+
+create function schematic_create_versions_of(uuid)
+    returns schematic_version[] as
+$$
+    select coalesce(array_agg(
+        row(create_version_id, create_version_name)::schematic_version
+        order by sort_order, create_version_id
+    ), array[]::schematic_version[])
+    from schematic_create_versions
+    inner join create_versions using (create_version_id)
+    where schematic_id = $1
+$$ language sql stable;
@@ -0,0 +1,40 @@
+-- Schematics along with their author, images, versions, tags and like counts as they
+-- are returned when searching or fetching a single schematic, see `FullSchematic`.
+-- Tags and likes are selected in sub-queries as joining both would count each like
+-- once for every tag
+create view full_schematics as
+select
+    schematics.schematic_id,
+    schematics.schematic_name,
+    schematics.body,
+    schematics.body_html,
+    schematics.author,
+    users.avatar as author_avatar,
+    users.displayname as author_displayname,
+    users.username as author_username,
+    schematics.downloads,
+    schematic_images_of(schematics.schematic_id) as images,
+    schematics.status,
+    schematic_game_versions_of(schematics.schematic_id) as game_versions,
+    schematic_create_versions_of(schematics.schematic_id) as create_versions,
+    schematics.created_at,
+    schematics.updated_at,
+    coalesce((
+        select array_agg(tag_id order by tag_id)
+        from applied_tags
+        where applied_tags.schematic_id = schematics.schematic_id
+    ), array[]::bigint[]) as tags,
+    (
+        select count(*)
+        from schematic_likes
+        where schematic_likes.schematic_id = schematics.schematic_id
+        and positive = true
+    ) as like_count,
+    (
+        select count(*)
+        from schematic_likes
+        where schematic_likes.schematic_id = schematics.schematic_id
+        and positive = false
+    ) as dislike_count
+from schematics
+inner join users on users.user_id = schematics.author;
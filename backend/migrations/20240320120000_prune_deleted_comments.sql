@@ -0,0 +1,17 @@
+-- Placeholders for deleted comments are only kept while they still have replies,
+-- previously they were left behind once their last reply was removed
+do
+$$
+begin
+    loop
+        delete from comments
+        where is_deleted
+        and not exists (
+            select 1 from comments replies
+            where replies.parent = comments.comment_id
+        );
+
+        exit when not found;
+    end loop;
+end
+$$;
@@ -0,0 +1,12 @@
+-- The number of likes and dislikes on a comment along with the reaction of a given
+-- user, if the user is null then `liked` is always null
+create function comment_reactions(uuid, uuid)
+    returns table (like_count bigint, dislike_count bigint, liked boolean) as
+$$
+    select
+        count(*) filter (where positive),
+        count(*) filter (where not positive),
+        bool_or(positive) filter (where user_id = $2)
+    from comment_likes
+    where comment_id = $1
+$$ language sql stable;
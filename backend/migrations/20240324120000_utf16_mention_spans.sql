@@ -0,0 +1,34 @@
+-- Mention spans were measured in characters, they are now measured in UTF-16 code
+-- units to match string indices in javascript. These only differ when characters
+-- outside of the basic multilingual plane appear before or within the mention
+create function utf16_offset(text, integer)
+    returns integer as
+$$
+    select $2 + (
+        select count(*)::integer
+        from regexp_matches(left($1, $2), '[\U00010000-\U0010FFFF]', 'g')
+    )
+$$ language sql immutable;
+
+-- Spans are shifted in place which could briefly overlap with the next mention
+alter table comment_mentions drop constraint comment_mentions_pkey;
+alter table schematic_mentions drop constraint schematic_mentions_pkey;
+
+update comment_mentions
+    set
+        span_start = utf16_offset(comment_body, span_start),
+        span_end = utf16_offset(comment_body, span_end)
+    from comments
+    where comments.comment_id = comment_mentions.comment_id;
+
+update schematic_mentions
+    set
+        span_start = utf16_offset(body, span_start),
+        span_end = utf16_offset(body, span_end)
+    from schematics
+    where schematics.schematic_id = schematic_mentions.schematic_id;
+
+alter table comment_mentions add primary key (comment_id, span_start);
+alter table schematic_mentions add primary key (schematic_id, span_start);
+
+drop function utf16_offset;
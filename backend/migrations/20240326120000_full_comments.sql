@@ -0,0 +1,47 @@
+-- Comments as they are returned by the api, see `FullComment`, along with the reaction
+-- of a given user who may be null. Deleted comments are kept as placeholders while
+-- they have replies so their body and author are hidden
+create function full_comments(uuid)
+    returns table (
+        comment_id         uuid,
+        parent             uuid,
+        comment_author     uuid,
+        comment_body       text,
+        comment_html       text,
+        schematic_id       uuid,
+        author_username    text,
+        author_displayname text,
+        author_avatar      text,
+        is_deleted         boolean,
+        is_pinned          boolean,
+        is_author          boolean,
+        like_count         bigint,
+        dislike_count      bigint,
+        liked              boolean,
+        created_at         timestamptz,
+        updated_at         timestamptz
+    ) as
+$$
+    select
+        comments.comment_id,
+        comments.parent,
+        case when comments.is_deleted then null else comments.comment_author end,
+        case when comments.is_deleted then '[deleted]' else comments.comment_body end,
+        case when comments.is_deleted then null else comments.comment_html end,
+        comments.schematic_id,
+        case when comments.is_deleted then null else users.username end,
+        case when comments.is_deleted then null else users.displayname end,
+        case when comments.is_deleted then null else users.avatar end,
+        comments.is_deleted,
+        comments.is_pinned,
+        not comments.is_deleted and schematics.author = comments.comment_author,
+        reactions.like_count,
+        reactions.dislike_count,
+        reactions.liked,
+        comments.created_at,
+        comments.updated_at
+    from comments
+    inner join users on users.user_id = comments.comment_author
+    inner join schematics on schematics.schematic_id = comments.schematic_id
+    cross join lateral comment_reactions(comments.comment_id, $1) reactions
+$$ language sql stable;
@@ -0,0 +1,8 @@
+-- Files are fingerprinted by a hash of their decompressed contents so that the
+-- same structure can be recognised however it was compressed. Files processed
+-- before this have neither a fingerprint nor a preview until they are replaced
+alter table schematic_files
+    add column fingerprint text,
+    add column preview     text;
+
+create index on schematic_files (fingerprint);
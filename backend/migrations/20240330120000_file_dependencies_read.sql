@@ -0,0 +1,6 @@
+-- Files uploaded before file_dependencies have no dependencies recorded, which is
+-- indistinguishable from a file requiring no mods. Until every file of a schematic
+-- has been read, either when processed or by the `rebuild-dependencies` command,
+-- its mod_dependencies are only ever added to so the mods it requires are not lost
+alter table schematic_files
+    add column dependencies_read boolean not null default false;
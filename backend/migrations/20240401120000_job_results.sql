@@ -0,0 +1,5 @@
+-- What a job produced once it has completed, such as the block compatibility
+-- and suggested tags of a newly uploaded schematic, which can only be worked
+-- out once its files have been validated
+alter table jobs
+    add column result jsonb;
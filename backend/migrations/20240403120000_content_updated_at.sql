@@ -0,0 +1,66 @@
+-- `updated_at` is bumped on any write to a schematic, including locking its
+-- comments or counting a download, which would otherwise push old schematics
+-- back to the top of the feed. `content_updated_at` only moves when the name or
+-- body changes, or when new versions are set, and is what the feed orders by
+alter table schematics
+    add column content_updated_at timestamptz;
+
+update schematics
+    set content_updated_at = updated_at;
+
+create or replace function set_content_updated_at()
+    returns trigger as
+$$
+begin
+    NEW.content_updated_at = now();
+    return NEW;
+end;
+$$ language plpgsql;
+
+create trigger set_content_updated_at
+    before update
+    on schematics
+    for each row
+    when ((OLD.schematic_name, OLD.body) is distinct from (NEW.schematic_name, NEW.body))
+execute function set_content_updated_at();
+
+create or replace view full_schematics as
+select
+    schematics.schematic_id,
+    schematics.schematic_name,
+    schematics.body,
+    schematics.body_html,
+    schematics.author,
+    users.avatar as author_avatar,
+    users.displayname as author_displayname,
+    users.username as author_username,
+    schematics.downloads,
+    schematic_images_of(schematics.schematic_id) as images,
+    schematics.status,
+    schematic_game_versions_of(schematics.schematic_id) as game_versions,
+    schematic_create_versions_of(schematics.schematic_id) as create_versions,
+    schematics.created_at,
+    schematics.updated_at,
+    coalesce((
+        select array_agg(tag_id order by tag_id)
+        from applied_tags
+        where applied_tags.schematic_id = schematics.schematic_id
+    ), array[]::bigint[]) as tags,
+    (
+        select count(*)
+        from schematic_likes
+        where schematic_likes.schematic_id = schematics.schematic_id
+        and positive = true
+    ) as like_count,
+    (
+        select count(*)
+        from schematic_likes
+        where schematic_likes.schematic_id = schematics.schematic_id
+        and positive = false
+    ) as dislike_count,
+    schematics.content_updated_at
+from schematics
+inner join users on users.user_id = schematics.author;
+
+drop index schematics_author_coalesce_idx;
+create index on schematics (author, (coalesce(content_updated_at, created_at)));
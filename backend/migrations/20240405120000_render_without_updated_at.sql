@@ -0,0 +1,27 @@
+-- Rendering stored markdown again, such as after changing the image proxy, is
+-- not an edit so updates which only change the rendered html should leave
+-- `updated_at` as it is. This was previously done by disabling the trigger but
+-- that locks the whole table while everything is rendered
+drop trigger set_updated_at on schematics;
+create trigger set_updated_at
+    before update
+    on schematics
+    for each row
+    when (to_jsonb(OLD) - 'body_html' is distinct from to_jsonb(NEW) - 'body_html')
+execute function set_updated_at();
+
+drop trigger set_updated_at on comments;
+create trigger set_updated_at
+    before update
+    on comments
+    for each row
+    when (to_jsonb(OLD) - 'comment_html' is distinct from to_jsonb(NEW) - 'comment_html')
+execute function set_updated_at();
+
+drop trigger set_updated_at on users;
+create trigger set_updated_at
+    before update
+    on users
+    for each row
+    when (to_jsonb(OLD) - 'about_html' is distinct from to_jsonb(NEW) - 'about_html')
+execute function set_updated_at();
@@ -11,6 +11,7 @@ use uuid::Uuid;
 use crate::authentication::oauth::{OauthUser, OauthProvider};
 use crate::authentication::session::UserSession;
 use crate::error::{ApiError, ResultExt};
+use crate::middleware::ratelimit::auth_limit;
 use crate::redirect::RedirectResponse;
 use crate::response::ApiResult;
 
@@ -35,7 +36,7 @@ pub struct AuthApi;
 
 #[OpenApi]
 impl AuthApi {
-    #[oai(path = "/auth/:provider", method = "get")]
+    #[oai(path = "/auth/:provider", method = "get", transform = "auth_limit")]
     async fn oauth_authorization(
         &self,
         Path(provider): Path<OauthProvider>
@@ -51,7 +52,7 @@ impl AuthApi {
         Ok(RedirectResponse::to(auth_url))
     }
 
-    #[oai(path = "/auth/:provider/callback", method = "get")]
+    #[oai(path = "/auth/:provider/callback", method = "get", transform = "auth_limit")]
     async fn oauth_callback(
         &self,
         Data(ctx): Data<&ApiContext>,
@@ -95,7 +96,7 @@ impl AuthApi {
         Ok(RedirectResponse::to("/"))
     }
 
-    #[oai(path = "/auth/refresh", method = "post")]
+    #[oai(path = "/auth/refresh", method = "post", transform = "auth_limit")]
     async fn refresh(
         &self,
         Data(ctx): Data<&ApiContext>,
@@ -117,7 +118,7 @@ impl AuthApi {
         Ok(())
     }
 
-    #[oai(path = "/auth/logout", method = "post")]
+    #[oai(path = "/auth/logout", method = "post", transform = "auth_limit")]
     async fn logout(
         &self,
         Data(ctx): Data<&ApiContext>,
@@ -14,7 +14,12 @@ use crate::database::postgres;
 use crate::database::postgres::DatabaseArguments;
 use crate::database::redis;
 use crate::database::redis::{RedisPool, RedisArguments};
+use crate::helpers::markdown;
+use crate::helpers::markdown::MarkdownArguments;
 use crate::middleware::logging::middleware_log;
+use crate::middleware::ratelimit::{middleware_ratelimit, RateLimitArguments};
+use crate::jobs::worker;
+use crate::jobs::worker::WorkerArguments;
 
 pub mod auth;
 pub mod v1;
@@ -35,12 +40,25 @@ pub struct StartCommandServerArguments {
     #[command(next_help_heading = "Database")]
     #[command(flatten)]
     pub postgres: DatabaseArguments,
+
+    #[command(next_help_heading = "Rate limiting")]
+    #[command(flatten)]
+    pub ratelimits: RateLimitArguments,
+
+    #[command(next_help_heading = "Workers")]
+    #[command(flatten)]
+    pub workers: WorkerArguments,
+
+    #[command(next_help_heading = "Markdown")]
+    #[command(flatten)]
+    pub markdown: MarkdownArguments,
 }
 
 #[derive(Clone)]
 pub struct ApiContext {
     pub pool: PgPool,
-    pub redis_pool: RedisPool
+    pub redis_pool: RedisPool,
+    pub ratelimits: RateLimitArguments
 }
 
 pub fn configure() -> impl OpenApi {
@@ -69,12 +87,19 @@ pub async fn serve(
         listen_address,
         redis,
         postgres,
+        ratelimits,
+        workers,
+        markdown,
         ..
     }: StartCommandServerArguments,
 ) -> Result<(), anyhow::Error> {
+    markdown::configure(markdown);
+
     let pool = postgres::connect(postgres).await?;
     let redis_pool = redis::connect(redis).await?;
 
+    let _workers = worker::spawn(pool.clone(), workers)?;
+
     let api_service = build_openapi_service();
 
     let swagger = api_service.swagger_ui();
@@ -90,6 +115,7 @@ pub async fn serve(
             .at("/openapi.yaml", yaml_spec)
         )
         .nest("/upload", StaticFilesEndpoint::new("./static/upload"))
+        .around(middleware_ratelimit)
         .with(Cors::new()
             .allow_headers([
                 header::AUTHORIZATION,
@@ -112,7 +138,7 @@ pub async fn serve(
         )
         .with(CookieJarManager::new())
         .around(middleware_log)
-        .data(ApiContext { pool, redis_pool });
+        .data(ApiContext { pool, redis_pool, ratelimits });
 
     Server::new(TcpListener::bind(listen_address))
         .run(app)
@@ -1,34 +1,120 @@
+use core::fmt;
+use std::collections::HashMap;
+use std::time::Duration;
+
 use poem::web::Data;
 use poem_openapi::OpenApi;
 use poem_openapi::param::{Path, Query};
 use poem_openapi::payload::Json;
-use poem_openapi_derive::{Object, Multipart};
+use poem_openapi_derive::{Object, Multipart, Enum};
 use time::OffsetDateTime;
 use uuid::Uuid;
 
 use crate::api::ApiContext;
+use crate::api::v1::mentions;
 use crate::middleware::validators::Profanity;
 use crate::response::ApiResult;
 use crate::models::comment::Comment;
-use crate::error::ApiError;
-use crate::authentication::schemes::Session;
+use crate::error::{ApiError, Cooldown};
+use crate::helpers::markdown;
+use crate::authentication::schemes::{Session, OptionalSession};
 
 pub (in crate::api::v1) struct CommentsApi;
 
+pub const COMMENT_COOLDOWN_NAMESPACE: &str = "comment_cooldown";
+
 #[derive(Serialize, Debug, Object)]
 pub (in crate::api::v1) struct FullComment {
     pub comment_id: Uuid,
     pub parent: Option<Uuid>,
-    pub comment_author: Uuid,
+    /// The author of the comment and their details are null once the comment
+    /// has been deleted
+    pub comment_author: Option<Uuid>,
     pub comment_body: String,
+    pub comment_html: Option<String>,
     pub schematic_id: String,
-    pub author_username: String,
+    pub author_username: Option<String>,
     pub author_displayname: Option<String>,
     pub author_avatar: Option<String>,
+    pub is_deleted: bool,
+    /// Whether this comment has been pinned to the top of the schematics
+    /// comments by its author or a moderator
+    pub is_pinned: bool,
+    /// Whether this comment was written by the author of the schematic
+    pub is_author: bool,
+    pub like_count: i64,
+    pub dislike_count: i64,
+    /// The current users reaction to this comment, `true` if they liked it
+    /// and `false` if they disliked it. This is always null if the user is
+    /// not logged in
+    pub liked: Option<bool>,
     pub created_at: OffsetDateTime,
     pub updated_at: Option<OffsetDateTime>
 }
 
+/// A comment within a comment tree, see `GET /api/v1/schematics/:id/comments/tree`
+/// 
+/// The replies will be empty if the comment has no replies or if the depth
+/// limit has been reached, in which case the reply count can be used to tell
+/// if there are more replies to fetch
+/// 
+#[derive(Serialize, Debug, Object)]
+pub (in crate::api::v1) struct CommentNode {
+    #[oai(flatten)]
+    #[serde(flatten)]
+    pub comment: FullComment,
+    pub reply_count: i64,
+    pub replies: Vec<CommentNode>
+}
+
+struct CommentTreeRow {
+    comment_id: Uuid,
+    parent: Option<Uuid>,
+    comment_author: Option<Uuid>,
+    comment_body: String,
+    comment_html: Option<String>,
+    schematic_id: String,
+    author_username: Option<String>,
+    author_displayname: Option<String>,
+    author_avatar: Option<String>,
+    is_deleted: bool,
+    is_pinned: bool,
+    is_author: bool,
+    like_count: i64,
+    dislike_count: i64,
+    liked: Option<bool>,
+    reply_count: i64,
+    created_at: OffsetDateTime,
+    updated_at: Option<OffsetDateTime>
+}
+
+#[derive(Enum, Deserialize, Debug)]
+#[serde(rename_all="snake_case")]
+pub enum CommentSort {
+    /// Fetch the most recent comments first
+    /// 
+    Newest,
+
+    /// Fetch the oldest comments first
+    /// 
+    Oldest,
+
+    /// Fetch the comments with the highest score first, being the number
+    /// of likes minus the number of dislikes
+    /// 
+    Top
+}
+
+impl fmt::Display for CommentSort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommentSort::Newest => write!(f, "newest"),
+            CommentSort::Oldest => write!(f, "oldest"),
+            CommentSort::Top => write!(f, "top")
+        }
+    }
+}
+
 #[derive(Multipart, Debug)]
 pub (in crate::api::v1) struct CommentBuilder {
     #[oai(validator(max_length=1024, custom="Profanity"))]
@@ -42,6 +128,26 @@ pub (in crate::api::v1) struct UpdateComment {
     pub comment_body: Option<String>
 }
 
+#[derive(Serialize, Debug, Object)]
+pub (in crate::api::v1) struct CommentSettings {
+    /// Whether new comments are prevented from being posted, moderators are
+    /// still able to comment on locked schematics
+    pub comments_locked: bool,
+    /// The minimum number of seconds users must wait between posting comments
+    /// on this schematic, the author of the schematic and moderators are not
+    /// affected by this
+    pub comment_slow_mode: Option<i32>
+}
+
+#[derive(Multipart, Debug)]
+pub (in crate::api::v1) struct UpdateCommentSettings {
+    pub comments_locked: Option<bool>,
+    /// The new slow mode interval in seconds, setting this to 0 will disable
+    /// slow mode entirely
+    #[oai(validator(minimum(value="0"), maximum(value="86400")))]
+    pub comment_slow_mode: Option<i32>
+}
+
 #[OpenApi(prefix_path="/v1")]
 impl CommentsApi {
 
@@ -49,10 +155,17 @@ impl CommentsApi {
     /// additional information about their author such as their avatar url
     /// and usesrname to prevent the need for subsequent requests. By default
     /// if no limit for comments is set then up to 20 will be returned at a
-    /// time.
+    /// time. If no sort is given comments are ordered by their parent.
+    /// 
+    /// Pinned comments are always placed before any other comments regardless
+    /// of the requested sort, so they will appear at the start of the first
+    /// page
     /// 
-    /// Note that comment bodies can contain markdown which will need to be 
-    /// handled accordingly
+    /// If the current user is logged in then their own reaction to each 
+    /// comment will also be included
+    /// 
+    /// Note that comment bodies can contain markdown, a sanitized html 
+    /// rendering of it is included as `comment_html`
     /// 
     #[oai(path = "/schematics/:schematic_id/comments", method = "get")]
     async fn get_comments_by_schematic(
@@ -60,30 +173,47 @@ impl CommentsApi {
         Data(ctx): Data<&ApiContext>,        
         #[oai(validator(maximum(value="50")))] Query(limit): Query<Option<i64>>,
         #[oai(validator(minimum(value="0")))] Query(offset): Query<Option<i64>>,
+        Query(sort): Query<Option<CommentSort>>,
         Path(schematic_id): Path<Uuid>,
+        OptionalSession(user_id): OptionalSession
     ) -> ApiResult<Json<Vec<FullComment>>> {
         let schematics = sqlx::query_as!(
             FullComment,
             r#"
             select 
-                comment_id, comment_author,
-                comment_body, schematic_id,
-                username as author_username,
-                displayname as author_displayname,
-                avatar as author_avatar,
-                parent, comments.created_at, 
-                comments.updated_at
+                comment_id as "comment_id!",
+                parent,
+                comment_author,
+                comment_body as "comment_body!",
+                comment_html,
+                schematic_id as "schematic_id!",
+                author_username,
+                author_displayname,
+                author_avatar,
+                is_deleted as "is_deleted!",
+                is_pinned as "is_pinned!",
+                is_author as "is_author!",
+                like_count as "like_count!",
+                dislike_count as "dislike_count!",
+                liked,
+                created_at as "created_at!",
+                updated_at
             from 
-                comments
-                inner join users on comment_author = user_id
+                full_comments($2) comments
             where 
                 schematic_id = $1
             order by 
+                is_pinned desc,
+                case when $3 = 'newest' then comments.created_at end desc,
+                case when $3 = 'oldest' then comments.created_at end asc,
+                case when $3 = 'top' then like_count - dislike_count end desc,
                 parent
-            limit $2 
-            offset $3
+            limit $4 
+            offset $5
             "#,
             schematic_id,
+            user_id,
+            sort.map(|sort| sort.to_string()),
             limit.unwrap_or(20),
             offset.unwrap_or(0)
         )
@@ -99,8 +229,8 @@ impl CommentsApi {
     /// default to 0, similarly if no limit is given it will default to 20. THe
     /// maximum limit is 50. 
     /// 
-    /// Note that comment bodies can contain markdown which will need to be 
-    /// handled accordingly
+    /// Note that comment bodies can contain markdown, a sanitized html 
+    /// rendering of it is included as `comment_html`
     /// 
     #[oai(path = "/comments/:comment_id/replies", method = "get")]
     async fn get_replies_to_comment(
@@ -109,27 +239,38 @@ impl CommentsApi {
         #[oai(validator(maximum(value="50")))] Query(limit): Query<Option<i64>>,
         #[oai(validator(minimum(value="0")))] Query(offset): Query<Option<i64>>,
         Path(comment_id): Path<Uuid>,
+        OptionalSession(user_id): OptionalSession
     ) -> ApiResult<Json<Vec<FullComment>>> {
         let replies = sqlx::query_as!(
             FullComment,
             r#"
             select 
-                comment_id, comment_author,
-                comment_body, schematic_id,
-                username as author_username,
-                displayname as author_displayname,
-                avatar as author_avatar,
-                parent, comments.created_at, 
-                comments.updated_at
+                comment_id as "comment_id!",
+                parent,
+                comment_author,
+                comment_body as "comment_body!",
+                comment_html,
+                schematic_id as "schematic_id!",
+                author_username,
+                author_displayname,
+                author_avatar,
+                is_deleted as "is_deleted!",
+                is_pinned as "is_pinned!",
+                is_author as "is_author!",
+                like_count as "like_count!",
+                dislike_count as "dislike_count!",
+                liked,
+                created_at as "created_at!",
+                updated_at
             from 
-                comments
-                inner join users on comment_author = user_id
+                full_comments($2) comments
             where 
                 parent = $1
-            limit $2 
-            offset $3
+            limit $3 
+            offset $4
             "#,
             comment_id,
+            user_id,
             limit.unwrap_or(20),
             offset.unwrap_or(0)
         )
@@ -139,9 +280,124 @@ impl CommentsApi {
         Ok(Json(replies))
     }
     
+    /// Fetches the comments on a schematic as a tree, with each comment
+    /// containing its replies up to a given depth. This avoids needing to
+    /// walk `GET /api/v1/comments/:id/replies` one level at a time. 
+    /// 
+    /// Only top level comments are paginated, if no limit is given then up
+    /// to 20 will be returned, similarly if no depth is given then up to three
+    /// levels of comments will be returned, the maximum depth is 10. Comments
+    /// are ordered by the given sort at every level of the tree, by default
+    /// the newest comments are returned first.
+    /// 
+    /// Comments that have been removed while still having replies will be
+    /// included with their body replaced with `[deleted]` and without their
+    /// author so that their replies are not lost
+    /// 
+    /// Note that comment bodies can contain markdown, a sanitized html 
+    /// rendering of it is included as `comment_html`
+    /// 
+    #[allow(clippy::too_many_arguments)]
+    #[oai(path = "/schematics/:schematic_id/comments/tree", method = "get")]
+    async fn get_comment_tree_by_schematic(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        #[oai(validator(minimum(value="1"), maximum(value="10")))] Query(depth): Query<Option<i32>>,
+        #[oai(validator(maximum(value="50")))] Query(limit): Query<Option<i64>>,
+        #[oai(validator(minimum(value="0")))] Query(offset): Query<Option<i64>>,
+        Query(sort): Query<Option<CommentSort>>,
+        Path(schematic_id): Path<Uuid>,
+        OptionalSession(user_id): OptionalSession
+    ) -> ApiResult<Json<Vec<CommentNode>>> {
+        let ordering = sort.unwrap_or(CommentSort::Newest);
+
+        // Rows are returned breadth first in the requested order, so building
+        // the tree by appending each row to its parent preserves the ordering
+        // at every level
+        let rows = sqlx::query_as!(
+            CommentTreeRow,
+            r#"
+            with recursive tree as (
+                (
+                    select comment_id, 1 as depth
+                    from comments
+                    where 
+                        schematic_id = $1
+                        and parent is null
+                    order by
+                        is_pinned desc,
+                        case when $3 = 'newest' then created_at end desc,
+                        case when $3 = 'oldest' then created_at end asc,
+                        case when $3 = 'top' then (
+                            select like_count - dislike_count
+                            from comment_reactions(comments.comment_id, null)
+                        ) end desc,
+                        created_at desc
+                    limit $4
+                    offset $5
+                )
+                union all
+                select comments.comment_id, tree.depth + 1
+                from 
+                    comments
+                    inner join tree on comments.parent = tree.comment_id
+                where 
+                    tree.depth < $2
+            )
+            select 
+                comment_id as "comment_id!",
+                parent,
+                comment_author,
+                comment_body as "comment_body!",
+                comment_html,
+                schematic_id as "schematic_id!",
+                author_username,
+                author_displayname,
+                author_avatar,
+                is_deleted as "is_deleted!",
+                is_pinned as "is_pinned!",
+                is_author as "is_author!",
+                like_count as "like_count!",
+                dislike_count as "dislike_count!",
+                liked,
+                created_at as "created_at!",
+                updated_at,
+                (
+                    select count(*) from comments replies 
+                    where replies.parent = comments.comment_id
+                ) as "reply_count!"
+            from 
+                tree
+                inner join full_comments($6) comments using (comment_id)
+            order by
+                depth,
+                is_pinned desc,
+                case when $3 = 'newest' then comments.created_at end desc,
+                case when $3 = 'oldest' then comments.created_at end asc,
+                case when $3 = 'top' then like_count - dislike_count end desc,
+                comments.created_at desc
+            "#,
+            schematic_id,
+            depth.unwrap_or(3),
+            ordering.to_string(),
+            limit.unwrap_or(20),
+            offset.unwrap_or(0),
+            user_id
+        )
+        .fetch_all(&ctx.pool)
+        .await?;
+
+        Ok(Json(build_comment_tree(rows)))
+    }
+    
     /// Uploads a comment to a given schematic for the current user returning
     /// information about the new comment including its id. 
     /// 
+    /// If the schematics comments have been locked then a `423 Locked` error
+    /// will be returned, similarly if slow mode is enabled and the current user
+    /// has commented too recently a `429 Too Many Requests` error will be
+    /// returned including when they will next be able to comment. 
+    /// 
     /// The comments body can contain markdown which will be sanitized
     /// accordingly, however it cannot contain profanity wich will result in
     /// a `422 Conflict` being returned. 
@@ -155,10 +411,23 @@ impl CommentsApi {
         &self,
         Data(ctx): Data<&ApiContext>,  
         Path(schematic_id): Path<Uuid>,
-        Session(user_id): Session,
+        session: Session,
         form: CommentBuilder
     ) -> ApiResult<Json<Comment>> {
         let mut transaction = ctx.pool.begin().await?;
+        let user_id = session.user_id();
+
+        let schematic_meta = sqlx::query!(
+            r#"
+            select author, comments_locked, comment_slow_mode 
+            from schematics 
+            where schematic_id = $1
+            "#,
+            schematic_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
 
         // Check that the parent comment both exists and is on this schematic
         if let Some(parent_id) = form.parent {
@@ -175,44 +444,44 @@ impl CommentsApi {
             }
         } 
 
-        let comment = sqlx::query_as!(
-            Comment,
-            r#"
-            insert into comments (
-                comment_author, comment_body,
-                parent, schematic_id
-            )
-            values (
-                $1, $2, $3, $4
-            )
-            returning
-                comment_id,
-                parent,
-                comment_author,
-                comment_body,
-                schematic_id,
-                created_at,
-                updated_at
-            "#,
-            user_id,
-            form.comment_body,
-            form.parent,
-            schematic_id
-        )
-        .fetch_one(&mut *transaction)
-        .await?;
+        let mut slow_mode_started = false;
 
-        transaction.commit().await?;
+        if schematic_meta.comments_locked || schematic_meta.comment_slow_mode.is_some() {
+            let is_moderator = session.is_moderator(&mut *transaction).await?;
 
-        Ok(Json(comment))
+            if schematic_meta.comments_locked && !is_moderator {
+                return Err(ApiError::Locked);
+            }
+
+            if let Some(slow_mode) = schematic_meta.comment_slow_mode {
+                if schematic_meta.author != user_id && !is_moderator {
+                    enforce_slow_mode(ctx, schematic_id, user_id, slow_mode).await?;
+                    slow_mode_started = true;
+                }
+            }
+        }
+
+        let result = write_comment(transaction, schematic_id, user_id, &form).await;
+
+        // The cooldown is started before the comment is written so that concurrent
+        // requests cannot both get through, if writing fails it is lifted again
+        if result.is_err() && slow_mode_started {
+            let key = format!("{schematic_id}:{user_id}");
+
+            if let Err(e) = ctx.redis_pool.delete(COMMENT_COOLDOWN_NAMESPACE, key).await {
+                tracing::warn!("Failed to lift the comment cooldown after writing failed: {:?}", e);
+            }
+        }
+
+        result.map(Json)
     }
 
     /// Fetches a specific comment by it's id aswell as some additional
     /// information about it's author such as their username and avatar url
     /// to avoid subsequent requests. 
     /// 
-    /// Note the comemnts body can contain markdown which will need to be
-    /// displayed accordingly to the user
+    /// Note the comemnts body can contain markdown, a sanitized html rendering
+    /// of it is included as `comment_html`
     /// 
     /// If you are looking to fetch comments from a schematic see 
     /// `GET /schematics/:id/comments`
@@ -222,25 +491,36 @@ impl CommentsApi {
         &self,
         Data(ctx): Data<&ApiContext>,  
         Path(comment_id): Path<Uuid>,
+        OptionalSession(user_id): OptionalSession
     ) -> ApiResult<Json<FullComment>> {
         sqlx::query_as!(
             FullComment,
             r#"
             select 
-                comment_id, comment_author,
-                comment_body, schematic_id,
-                username as author_username,
-                displayname as author_displayname,
-                avatar as author_avatar,
-                parent, comments.created_at,
-                comments.updated_at
+                comment_id as "comment_id!",
+                parent,
+                comment_author,
+                comment_body as "comment_body!",
+                comment_html,
+                schematic_id as "schematic_id!",
+                author_username,
+                author_displayname,
+                author_avatar,
+                is_deleted as "is_deleted!",
+                is_pinned as "is_pinned!",
+                is_author as "is_author!",
+                like_count as "like_count!",
+                dislike_count as "dislike_count!",
+                liked,
+                created_at as "created_at!",
+                updated_at
             from 
-                comments
-                inner join users on comment_author = user_id
+                full_comments($2) comments
             where 
                 comment_id = $1
             "#,
             comment_id,
+            user_id
         )
         .fetch_optional(&ctx.pool)
         .await?
@@ -269,7 +549,7 @@ impl CommentsApi {
         let mut transaction = ctx.pool.begin().await?;
         
         let user_meta = sqlx::query!(
-            r#"select comment_author from comments where comment_id = $1"#,
+            r#"select comment_author, schematic_id from comments where comment_id = $1 and is_deleted = false"#,
             comment_id
         )
         .fetch_optional(&mut *transaction)
@@ -285,24 +565,38 @@ impl CommentsApi {
             r#"
             update comments
                 set 
-                    comment_body = coalesce($1, comment_body)
+                    comment_body = coalesce($1, comment_body),
+                    comment_html = coalesce($2, comment_html)
                 where 
-                    comment_id = $2
+                    comment_id = $3
                 returning
                     comment_id,
                     parent,
                     comment_author,
                     comment_body,
+                    comment_html,
                     schematic_id,
                     created_at,
                     updated_at
             "#,
             form.comment_body,
+            form.comment_body.as_deref().map(markdown::render),
             comment_id
         )
         .fetch_optional(&mut *transaction)
         .await?
         .ok_or(ApiError::NotFound)?;
+
+        if let Some(body) = &form.comment_body {
+            mentions::store_comment_mentions(
+                &mut transaction,
+                comment_id,
+                user_meta.schematic_id,
+                user_id,
+                body
+            )
+            .await?;
+        }
     
         transaction.commit().await?;
     
@@ -313,6 +607,9 @@ impl CommentsApi {
     /// current user to either own the comment or have permission to moderate
     /// comments
     /// 
+    /// Comments with replies are kept as a placeholder without their body or
+    /// author until their last reply is removed
+    /// 
     #[oai(path = "/comments/:comment_id", method = "delete")]
     async fn delete_comment_by_id(
         &self,
@@ -322,33 +619,391 @@ impl CommentsApi {
     ) -> ApiResult<()> {
         let mut transaction = ctx.pool.begin().await?;
     
-        let user_meta = sqlx::query!(
-            r#"select comment_author from comments where comment_id = $1"#,
+        let comment_meta = sqlx::query!(
+            r#"
+            select 
+                comment_author, parent,
+                exists(select 1 from comments replies where replies.parent = $1) as "has_replies!"
+            from comments 
+            where comment_id = $1 
+            and is_deleted = false
+            "#,
             comment_id
         )
         .fetch_optional(&mut *transaction)
         .await?
         .ok_or(ApiError::NotFound)?;
 
-        if user_meta.comment_author != session.user_id()
+        if comment_meta.comment_author != session.user_id()
                 && !session.is_moderator(&mut *transaction).await? {
             return Err(ApiError::Forbidden)
         }
 
-        // We dont need to validate the the comment previously existed here as that was implicitly
-        // checked when ensuring the user was the author of the comment
-        sqlx::query!(
-            r#"
-            delete from comments
-            where comment_id = $1
-            "#,
-            comment_id
-        )
-        .execute(&mut *transaction)
-        .await?;
+        // Removing a comment would also remove all of its replies, so if there are any
+        // we clear the body and keep it as a placeholder instead. We dont need to validate
+        // the the comment previously existed here as that was implicitly checked when 
+        // ensuring the user was the author of the comment
+        if comment_meta.has_replies {
+            sqlx::query!(
+                r#"
+                update comments
+                    set
+                        comment_body = '',
+                        comment_html = null,
+                        is_deleted = true,
+                        is_pinned = false
+                    where
+                        comment_id = $1
+                "#,
+                comment_id
+            )
+            .execute(&mut *transaction)
+            .await?;
+
+            sqlx::query!(
+                r#"
+                delete from comment_mentions
+                where comment_id = $1
+                "#,
+                comment_id
+            )
+            .execute(&mut *transaction)
+            .await?;
+        } else {
+            sqlx::query!(
+                r#"
+                delete from comments
+                where comment_id = $1
+                "#,
+                comment_id
+            )
+            .execute(&mut *transaction)
+            .await?;
+
+            // Placeholders are only kept for their replies, so once the last one
+            // is removed they can be removed too, along with any placeholders
+            // above them left without replies as a result
+            let mut parent = comment_meta.parent;
+
+            while let Some(parent_id) = parent {
+                parent = sqlx::query!(
+                    r#"
+                    delete from comments
+                    where comment_id = $1
+                    and is_deleted
+                    and not exists (
+                        select 1 from comments replies
+                        where replies.parent = $1
+                    )
+                    returning parent
+                    "#,
+                    parent_id
+                )
+                .fetch_optional(&mut *transaction)
+                .await?
+                .and_then(|removed| removed.parent);
+            }
+        }
     
         transaction.commit().await?;
     
         Ok(())
     }    
-}
\ No newline at end of file
+
+    /// Fetches the comment settings of a given schematic, being whether its
+    /// comments have been locked and its slow mode interval if any
+    /// 
+    #[oai(path = "/schematics/:schematic_id/comments/settings", method = "get")]
+    async fn get_comment_settings(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(schematic_id): Path<Uuid>
+    ) -> ApiResult<Json<CommentSettings>> {
+        sqlx::query_as!(
+            CommentSettings,
+            r#"
+            select comments_locked, comment_slow_mode
+            from schematics
+            where schematic_id = $1
+            "#,
+            schematic_id
+        )
+        .fetch_optional(&ctx.pool)
+        .await?
+        .ok_or(ApiError::NotFound)
+        .map(Json)
+    }
+
+    /// Locks or unlocks a schematics comments or changes its slow mode
+    /// interval, all fields are optional but at least one is required
+    /// 
+    /// This requires for the current user to either be the author of the
+    /// schematic or to have permission to moderate comments
+    /// 
+    #[oai(path = "/schematics/:schematic_id/comments/settings", method = "patch")]
+    async fn update_comment_settings(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(schematic_id): Path<Uuid>,
+        session: Session,
+        form: UpdateCommentSettings
+    ) -> ApiResult<Json<CommentSettings>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        let schematic_meta = sqlx::query!(
+            r#"select author from schematics where schematic_id = $1"#,
+            schematic_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        if schematic_meta.author != session.user_id()
+                && !session.is_moderator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden)
+        }
+
+        let settings = sqlx::query_as!(
+            CommentSettings,
+            r#"
+            update schematics
+                set
+                    comments_locked = coalesce($1, comments_locked),
+                    comment_slow_mode = case 
+                        when $2 = 0 then null 
+                        else coalesce($2, comment_slow_mode) 
+                    end
+                where
+                    schematic_id = $3
+                returning
+                    comments_locked,
+                    comment_slow_mode
+            "#,
+            form.comments_locked,
+            form.comment_slow_mode,
+            schematic_id
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(Json(settings))
+    }
+
+    /// Pins a comment to the top of a schematics comments, see 
+    /// `GET /api/v1/schematics/:id/comments`. Only top level comments can be
+    /// pinned, attempting to pin a reply will result in a `400 Bad Request`
+    /// 
+    /// This requires for the current user to either be the author of the
+    /// schematic or to have permission to moderate comments
+    /// 
+    #[oai(path = "/comments/:comment_id/pin", method = "put")]
+    async fn pin_comment(
+        &self,
+        Data(ctx): Data<&ApiContext>, 
+        Path(comment_id): Path<Uuid>,
+        session: Session,
+    ) -> ApiResult<()> {
+        set_comment_pinned(ctx, comment_id, session, true).await
+    }
+
+    /// Unpins a comment that was previously pinned, this has the same
+    /// requirements as pinning a comment
+    /// 
+    #[oai(path = "/comments/:comment_id/pin", method = "delete")]
+    async fn unpin_comment(
+        &self,
+        Data(ctx): Data<&ApiContext>, 
+        Path(comment_id): Path<Uuid>,
+        session: Session,
+    ) -> ApiResult<()> {
+        set_comment_pinned(ctx, comment_id, session, false).await
+    }
+}
+
+/// Starts the current users cooldown for commenting on a given schematic, if
+/// they are still on cooldown from a previous comment then an error is returned
+/// with when they will next be able to comment
+async fn enforce_slow_mode(
+    ctx: &ApiContext,
+    schematic_id: Uuid,
+    user_id: Uuid,
+    slow_mode: i32
+) -> ApiResult<()> {
+    let key = format!("{schematic_id}:{user_id}");
+    let seconds = slow_mode as u64;
+
+    if ctx.redis_pool.set_if_absent(COMMENT_COOLDOWN_NAMESPACE, &key, 1, seconds).await? {
+        return Ok(());
+    }
+
+    let remaining = ctx.redis_pool
+        .ttl(COMMENT_COOLDOWN_NAMESPACE, &key)
+        .await?
+        .unwrap_or(seconds);
+
+    let until = OffsetDateTime::now_utc() + Duration::from_secs(remaining);
+
+    Err(ApiError::TooManyRequests(Json(Cooldown { until })))
+}
+
+/// Writes a new comment along with any mentions within it, committing the
+/// transaction
+async fn write_comment(
+    mut transaction: sqlx::Transaction<'_, sqlx::Postgres>,
+    schematic_id: Uuid,
+    user_id: Uuid,
+    form: &CommentBuilder
+) -> ApiResult<Comment> {
+    let comment = sqlx::query_as!(
+        Comment,
+        r#"
+        insert into comments (
+            comment_author, comment_body,
+            comment_html, parent, schematic_id
+        )
+        values (
+            $1, $2, $3, $4, $5
+        )
+        returning
+            comment_id,
+            parent,
+            comment_author,
+            comment_body,
+            comment_html,
+            schematic_id,
+            created_at,
+            updated_at
+        "#,
+        user_id,
+        form.comment_body,
+        markdown::render(&form.comment_body),
+        form.parent,
+        schematic_id
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    mentions::store_comment_mentions(
+        &mut transaction,
+        comment.comment_id,
+        schematic_id,
+        user_id,
+        &form.comment_body
+    )
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(comment)
+}
+
+/// Pins or unpins a top level comment, ensuring that the current user is either
+/// the author of the schematic or a moderator
+async fn set_comment_pinned(
+    ctx: &ApiContext,
+    comment_id: Uuid,
+    session: Session,
+    pinned: bool
+) -> ApiResult<()> {
+    let mut transaction = ctx.pool.begin().await?;
+
+    let comment_meta = sqlx::query!(
+        r#"
+        select 
+            parent,
+            author as schematic_author
+        from 
+            comments
+            inner join schematics using (schematic_id)
+        where 
+            comment_id = $1
+            and is_deleted = false
+        "#,
+        comment_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+
+    if comment_meta.schematic_author != session.user_id()
+            && !session.is_moderator(&mut *transaction).await? {
+        return Err(ApiError::Forbidden)
+    }
+
+    if comment_meta.parent.is_some() {
+        return Err(ApiError::BadRequest)
+    }
+
+    sqlx::query!(
+        r#"
+        update comments
+            set is_pinned = $1
+            where comment_id = $2
+        "#,
+        pinned,
+        comment_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Builds a tree of comments from a list of rows, the rows are expected to be
+/// ordered such that siblings appear in the order they should be shown and for
+/// every reply to have its parent included. 
+fn build_comment_tree(rows: Vec<CommentTreeRow>) -> Vec<CommentNode> {
+    let mut roots = Vec::new();
+    let mut replies: HashMap<Uuid, Vec<CommentTreeRow>> = HashMap::new();
+
+    for row in rows {
+        match row.parent {
+            Some(parent) => replies.entry(parent).or_default().push(row),
+            None => roots.push(row)
+        }
+    }
+
+    roots.into_iter()
+        .map(|row| build_comment_node(row, &mut replies))
+        .collect()
+}
+
+fn build_comment_node(
+    row: CommentTreeRow, 
+    replies: &mut HashMap<Uuid, Vec<CommentTreeRow>>
+) -> CommentNode {
+    let children = replies
+        .remove(&row.comment_id)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|reply| build_comment_node(reply, replies))
+        .collect();
+
+    CommentNode {
+        reply_count: row.reply_count,
+        replies: children,
+        comment: FullComment {
+            comment_id: row.comment_id,
+            parent: row.parent,
+            comment_author: row.comment_author,
+            comment_body: row.comment_body,
+            comment_html: row.comment_html,
+            schematic_id: row.schematic_id,
+            author_username: row.author_username,
+            author_displayname: row.author_displayname,
+            author_avatar: row.author_avatar,
+            is_deleted: row.is_deleted,
+            is_pinned: row.is_pinned,
+            is_author: row.is_author,
+            like_count: row.like_count,
+            dislike_count: row.dislike_count,
+            liked: row.liked,
+            created_at: row.created_at,
+            updated_at: row.updated_at
+        }
+    }
+}
@@ -1,13 +1,25 @@
+use std::collections::HashMap;
+
 use poem::web::Data;
 use poem_openapi::param::Path;
 use poem_openapi::payload::Json;
 use poem_openapi_derive::{Object, Multipart, OpenApi};
+use sqlx::PgConnection;
 use uuid::Uuid;
 
 use crate::authentication::schemes::Session;
-use crate::error::ApiError;
+use crate::error::{ApiError, ResultExt};
 use crate::middleware::files::FileUpload;
+use crate::middleware::ratelimit::upload_limit;
+use crate::middleware::validators::Profanity;
+use crate::api::v1::jobs::QueuedJob;
+use crate::api::v1::ordering::{self, Ordered};
+use crate::api::v1::mods::Mod;
+use crate::jobs::Job;
+use crate::jobs::uploads::UploadKind;
+use crate::models::schematic::{FileFormat, SchematicFile};
 use crate::storage;
+use crate::storage::{blocks, dependencies, upload};
 use crate::response::ApiResult;
 use crate::api::ApiContext;
 
@@ -16,7 +28,20 @@ pub (in crate::api::v1) struct FileApi;
 #[derive(Serialize, Debug, Object)]
 pub struct Files {
     #[oai(validator(min_items=1))]
-    pub files: Vec<String>
+    pub files: Vec<SchematicFile>
+}
+
+#[derive(Serialize, Debug, Object)]
+pub (in crate::api::v1) struct FileDependencies {
+    pub files: Vec<FileDependency>
+}
+
+#[derive(Serialize, Debug, Object)]
+pub (in crate::api::v1) struct FileDependency {
+    pub file_name: String,
+    /// The mods this file requires, files which have not been processed yet
+    /// will have none
+    pub mods: Vec<Mod>
 }
 
 #[derive(Multipart, Debug)]
@@ -29,55 +54,161 @@ pub struct DeleteFile {
     pub file_name: String
 }
 
+#[derive(Multipart, Debug)]
+pub struct ReplaceFile {
+    pub file: FileUpload
+}
+
+#[derive(Multipart, Debug)]
+pub struct UpdateFile {
+    /// Setting this to an empty string will remove the display name
+    #[oai(validator(max_length=50, custom="Profanity"))]
+    pub display_name: Option<String>,
+    /// Setting this to an empty string will remove the description
+    #[oai(validator(max_length=512, custom="Profanity"))]
+    pub description: Option<String>
+}
+
+#[derive(Multipart, Debug)]
+pub struct FileOrder {
+    /// The file names of every file on the schematic in their new order
+    pub file_names: Vec<String>
+}
+
 #[OpenApi(prefix_path="/v1")]
 impl FileApi {
 
-    /// Fetches the name of all uploaded schematic files on a given schematic
+    /// Fetches all of the schematic files on a given schematic in order of
+    /// their position, including details such as their dimensions and the
+    /// number of blocks they contain
     /// 
-    /// Note this does not return the schematic files themselves, they can be 
+    /// Note this does not return the schematic files themselves, they can be
     /// retrieved from the static file endpoint like so filling in the schematic
     /// id for the given schematic and file_name for one of the values returned
-    /// here `GET /upload/schematics/{schematic_id}/files/{file_name}.nbt`
+    /// here `GET /upload/schematics/{schematic_id}/files/{file_name}.nbt`, the
+    /// top down preview of each file is stored alongside it under its `preview`
+    /// file name
     /// 
     #[oai(path = "/schematics/:schematic_id/files", method = "get")]
     async fn get_files_from_schematic(
         &self,
-        Data(ctx): Data<&ApiContext>,    
+        Data(ctx): Data<&ApiContext>,
         Path(schematic_id): Path<Uuid>,
     ) -> ApiResult<Json<Files>> {
-        sqlx::query_as!(
-            Files,
+        let mut transaction = ctx.pool.begin().await?;
+
+        sqlx::query!(
+            r#"select schematic_id from schematics where schematic_id = $1"#,
+            schematic_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        let files = fetch_files(&mut transaction, schematic_id).await?;
+
+        Ok(Json(files))
+    }
+
+    /// Fetches the mods required by each of the files on a given schematic in
+    /// order of their position. A schematic requires every mod required by
+    /// any of its files
+    /// 
+    #[oai(path = "/schematics/:schematic_id/files/dependencies", method = "get")]
+    async fn get_file_dependencies(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(schematic_id): Path<Uuid>,
+    ) -> ApiResult<Json<FileDependencies>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        sqlx::query!(
+            r#"select schematic_id from schematics where schematic_id = $1"#,
+            schematic_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        let rows = sqlx::query!(
             r#"
-            select files
-            from schematics
-            where schematic_id = $1
+            select
+                schematic_files.file_name,
+                mods.mod_id as "mod_id?",
+                mods.mod_slug as "mod_slug?",
+                mods.mod_name,
+                mods.curseforge_slug,
+                mods.modrinth_slug,
+                mods.icon_url,
+                mods.source_url,
+                mods.issues_url,
+                mods.wiki_url,
+                mods.loaders as "loaders?",
+                mods.game_versions as "game_versions?",
+                mods.last_synced_at
+            from
+                schematic_files
+                left join file_dependencies using (schematic_id, file_name)
+                left join mods using (mod_id)
+            where
+                schematic_files.schematic_id = $1
+            order by
+                schematic_files.position, mods.mod_slug
             "#,
             schematic_id
         )
-        .fetch_optional(&ctx.pool)
-        .await?
-        .ok_or(ApiError::NotFound)
-        .map(Json)
+        .fetch_all(&mut *transaction)
+        .await?;
+
+        let mut files: Vec<FileDependency> = Vec::new();
+
+        for row in rows {
+            if files.last().map(|file| &file.file_name) != Some(&row.file_name) {
+                files.push(FileDependency { file_name: row.file_name, mods: Vec::new() });
+            }
+
+            if let (Some(mod_id), Some(mod_slug), Some(file)) = (row.mod_id, row.mod_slug, files.last_mut()) {
+                file.mods.push(Mod {
+                    mod_id,
+                    mod_slug,
+                    mod_name: row.mod_name,
+                    curseforge_slug: row.curseforge_slug,
+                    modrinth_slug: row.modrinth_slug,
+                    icon_url: row.icon_url,
+                    source_url: row.source_url,
+                    issues_url: row.issues_url,
+                    wiki_url: row.wiki_url,
+                    loaders: row.loaders.unwrap_or_default(),
+                    game_versions: row.game_versions.unwrap_or_default(),
+                    last_synced_at: row.last_synced_at
+                });
+            }
+        }
+
+        Ok(Json(FileDependencies { files }))
     }
 
     /// Uploads a new schematic file to a schematic, use this for schematics
-    /// with multiple variations or parts not for many entirely different 
-    /// schematics. 
+    /// with multiple variations or parts not for many entirely different
+    /// schematics. New files are added after all existing files
     /// 
     /// This requires for the current user to be the owner of the given schematic
     /// and for this file name (after sanitization) to not be used already. If
     /// there are conflicting file names `422 Unprocessable Entity` will be returned
     /// with a message explaining this
-    ///  
-    #[oai(path = "/schematics/:schematic_id/files", method = "post")]
+    /// 
+    /// The file is processed in the background so will not be available right
+    /// away, the returned job can be used to check on its progress. If another
+    /// file is added at the same time then a `409 Conflict` response is returned
+    /// 
+    #[oai(path = "/schematics/:schematic_id/files", method = "post", transform = "upload_limit")]
     async fn upload_file_to_schematic(
         &self,
-        Data(ctx): Data<&ApiContext>,    
+        Data(ctx): Data<&ApiContext>,
         Path(schematic_id): Path<Uuid>,
         Session(user_id): Session,
         form: UploadFile
-    ) -> ApiResult<()> {
-        let file_name = form.file.file_name.ok_or(ApiError::BadRequest)?;
+    ) -> ApiResult<Json<QueuedJob>> {
         let mut transaction = ctx.pool.begin().await?;
 
         let schematic_meta = sqlx::query!(
@@ -92,25 +223,276 @@ impl FileApi {
             return Err(ApiError::Forbidden);
         }
 
-        // Todo update dependencies list here
+        let pending_dir = upload::build_pending_directory(&schematic_id)?;
+        let files = upload::stage_schematics(&pending_dir, vec![form.file]).await?;
+
         sqlx::query!(
             r#"
-            update schematics
-            set files = array_append(files, $1)
-            where schematic_id = $2
+            insert into schematic_files (
+                schematic_id, file_name, position
+            )
+            select
+                $1, file_name,
+                coalesce((
+                    select max(position)
+                    from schematic_files
+                    where schematic_id = $1
+                ), -1) + position::integer
+            from
+                unnest($2::text[]) with ordinality as file(file_name, position)
             "#,
-            file_name,
             schematic_id,
+            &files[..]
         )
         .execute(&mut *transaction)
+        .await
+        .on_constraint("schematic_files_pkey", |_| {
+            ApiError::unprocessable_entity([("file", "a file with this name already exists")])
+        })?;
+
+        let directory = upload::pending_directory_name(&pending_dir)?;
+
+        let job_id = Job::ProcessUpload { schematic_id, directory, upload: UploadKind::Files }
+            .enqueue(&mut *transaction, Some(user_id))
+            .await?;
+
+        ordering::commit(transaction, Ordered::Files).await?;
+        let _persist = pending_dir.into_path();
+
+        Ok(Json(QueuedJob { job_id }))
+    }
+
+    /// Replaces the contents of an existing schematic file while keeping its
+    /// name, display name and description. The new file goes through the same
+    /// validation as newly uploaded files and the mods the schematic depends
+    /// on are updated to match
+    /// 
+    /// Unlike uploading a file this happens straight away, the file is only
+    /// replaced once it has been validated so if the request fails the original
+    /// file is left untouched. Files which are still being processed cannot be
+    /// replaced and will return a `422 Unprocessable Entity` response
+    /// 
+    /// This requires for the current user to be the owner of the given schematic
+    /// 
+    #[oai(path = "/schematics/:schematic_id/files/:file_name", method = "put", transform = "upload_limit")]
+    async fn replace_file(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(schematic_id): Path<Uuid>,
+        Path(file_name): Path<String>,
+        Session(user_id): Session,
+        form: ReplaceFile
+    ) -> ApiResult<Json<SchematicFile>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        let schematic_meta = sqlx::query!(
+            r#"select author from schematics where schematic_id = $1"#,
+            schematic_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        if schematic_meta.author != user_id {
+            return Err(ApiError::Forbidden);
+        }
+
+        // Locking the row prevents the file being replaced by two requests at
+        // once, with the last to commit not being the last file written
+        let file_meta = sqlx::query!(
+            r#"
+            select file_size
+            from schematic_files
+            where schematic_id = $1 and file_name = $2
+            for update
+            "#,
+            schematic_id,
+            file_name
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        if file_meta.file_size.is_none() {
+            return Err(ApiError::unprocessable_entity([("file", "this file is still being processed")]));
+        }
+
+        let name = file_name.clone();
+        let contents = form.file.contents;
+
+        let (stored, metadata) = tokio::task::spawn_blocking(move || {
+            upload::prepare_schematic(&name, &contents)
+        })
+        .await
+        .map_err(anyhow::Error::new)??;
+
+        let schematic = metadata.schematic.as_ref();
+
+        let mods: Vec<String> = schematic
+            .map(|schematic| schematic.mods.iter().cloned().collect())
+            .unwrap_or_default();
+
+        let file = sqlx::query_as!(
+            SchematicFile,
+            r#"
+            update schematic_files
+                set
+                    file_size = $1,
+                    width = $2,
+                    height = $3,
+                    length = $4,
+                    block_count = $5,
+                    fingerprint = $6,
+                    preview = $7
+                where schematic_id = $8
+                and file_name = $9
+                returning
+                    file_name, display_name, description, position,
+                    format as "format: FileFormat", file_size, width, height, length, block_count,
+                    fingerprint, preview
+            "#,
+            metadata.file_size,
+            schematic.map(|s| s.width),
+            schematic.map(|s| s.height),
+            schematic.map(|s| s.length),
+            schematic.map(|s| s.block_count),
+            metadata.fingerprint,
+            metadata.preview.as_ref().map(|_| upload::preview_file_name(&file_name)),
+            schematic_id,
+            file_name
+        )
+        .fetch_one(&mut *transaction)
         .await?;
 
-        let location = storage::schematic_file_path(&schematic_id);
+        dependencies::store_file_dependencies(&mut transaction, schematic_id, &file_name, &mods).await?;
+        dependencies::update_schematic_dependencies(&mut transaction, schematic_id).await?;
+
+        let no_blocks = HashMap::new();
+        let block_counts = schematic
+            .map_or(&no_blocks, |schematic| &schematic.block_counts);
+
+        blocks::store_file_blocks(&mut transaction, schematic_id, &file_name, block_counts).await?;
+
+        // The new file is written alongside the original before committing but
+        // only moved over it once committed, if anything fails before then the
+        // original is left untouched and the new file is removed
+        let path = storage::schematic_file_path(&schematic_id);
+        let preview_name = upload::preview_file_name(&file_name);
+
+        let replacement = upload::stage_replacement(&path, &file_name, &stored).await?;
+
+        let preview = match &metadata.preview {
+            Some(preview) => Some(upload::stage_replacement(&path, &preview_name, preview).await?),
+            None => None
+        };
 
-        storage::upload::save_schematic(&location, &file_name, &form.file.contents)?;
         transaction.commit().await?;
-    
-        Ok(())
+
+        replacement.apply().await?;
+
+        match preview {
+            Some(preview) => preview.apply().await?,
+            None => remove_if_exists(&path.join(preview_name)).await?
+        }
+
+        Ok(Json(file))
+    }
+
+    /// Updates the display name or description of a schematic file, these
+    /// can be used to describe each part of a schematic split into several
+    /// files. All fields are optional but at least one is required
+    /// 
+    /// This requires for the current user to be the owner of the given schematic
+    /// 
+    #[oai(path = "/schematics/:schematic_id/files/:file_name", method = "patch")]
+    async fn update_file(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(schematic_id): Path<Uuid>,
+        Path(file_name): Path<String>,
+        Session(user_id): Session,
+        form: UpdateFile
+    ) -> ApiResult<Json<SchematicFile>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        let schematic_meta = sqlx::query!(
+            r#"select author from schematics where schematic_id = $1"#,
+            schematic_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        if schematic_meta.author != user_id {
+            return Err(ApiError::Forbidden);
+        }
+
+        let file = sqlx::query_as!(
+            SchematicFile,
+            r#"
+            update schematic_files
+                set
+                    display_name = case when $1::text is null then display_name else nullif($1, '') end,
+                    description = case when $2::text is null then description else nullif($2, '') end
+                where schematic_id = $3
+                and file_name = $4
+                returning
+                    file_name, display_name, description, position,
+                    format as "format: FileFormat", file_size, width, height, length, block_count,
+                    fingerprint, preview
+            "#,
+            form.display_name,
+            form.description,
+            schematic_id,
+            file_name
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        transaction.commit().await?;
+
+        Ok(Json(file))
+    }
+
+    /// Changes the order of the files on a schematic, every file must be
+    /// included exactly once otherwise a `422 Unprocessable Entity` response
+    /// will be returned
+    /// 
+    /// If a file is added while reordering then a `409 Conflict` response
+    /// is returned
+    /// 
+    /// This requires for the current user to be the owner of the given schematic
+    /// 
+    #[oai(path = "/schematics/:schematic_id/files/order", method = "put")]
+    async fn reorder_files(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(schematic_id): Path<Uuid>,
+        Session(user_id): Session,
+        form: FileOrder
+    ) -> ApiResult<Json<Files>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        let schematic_meta = sqlx::query!(
+            r#"select author from schematics where schematic_id = $1"#,
+            schematic_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        if schematic_meta.author != user_id {
+            return Err(ApiError::Forbidden);
+        }
+
+        ordering::reorder(&mut transaction, Ordered::Files, schematic_id, &form.file_names).await?;
+
+        let files = fetch_files(&mut transaction, schematic_id).await?;
+
+        ordering::commit(transaction, Ordered::Files).await?;
+
+        Ok(Json(files))
     }
 
     /// Removes a schematic file from a schematic, at least one file must be
@@ -123,19 +505,17 @@ impl FileApi {
     #[oai(path = "/schematics/:schematic_id/files", method = "delete")]
     async fn remove_file_by_id(
         &self,
-        Data(ctx): Data<&ApiContext>,   
+        Data(ctx): Data<&ApiContext>,
         Path(schematic_id): Path<Uuid>,
         session: Session,
         form: DeleteFile
     ) -> ApiResult<()> {
         let mut transaction = ctx.pool.begin().await?;
-        
+
+        // Locking the schematic prevents two requests removing different files
+        // at once from both seeing another file left and removing the last one
         let schematic_meta = sqlx::query!(
-            r#"
-            select author 
-            from schematics 
-            where schematic_id = $1
-            "#,
+            r#"select author from schematics where schematic_id = $1 for update"#,
             schematic_id
         )
         .fetch_optional(&mut *transaction)
@@ -147,33 +527,75 @@ impl FileApi {
             return Err(ApiError::Unauthorized);
         }
 
+        // Counted once the lock is held so that files removed by a request
+        // which held it before are not included
+        let file_count = sqlx::query_scalar!(
+            r#"select count(*) as "count!" from schematic_files where schematic_id = $1"#,
+            schematic_id
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        if file_count <= 1 {
+            return Err(ApiError::BadRequest);
+        }
+
         sqlx::query!(
             r#"
-            update schematics
-                set 
-                    files = array_remove(files, $1)
-                where 
-                    schematic_id = $2
-                    and array_length(files, 1) > 2
-            returning files
+            delete from schematic_files
+                where schematic_id = $1
+                and file_name = $2
+            returning file_name
             "#,
-            form.file_name,
-            schematic_id
+            schematic_id,
+            form.file_name
         )
         .fetch_optional(&mut *transaction)
         .await?
-        .ok_or(ApiError::BadRequest)?;
-    
+        .ok_or(ApiError::NotFound)?;
+
+        // The dependencies of the file itself are removed along with it
+        dependencies::update_schematic_dependencies(&mut transaction, schematic_id).await?;
+
         let path = storage::schematic_file_path(&schematic_id);
-        
+
         // Remove the file last since it's the hardest part to rollback if something
-        // else goes wrong
-        tokio::fs::remove_file(path.join(form.file_name))
-            .await
-            .map_err(anyhow::Error::new)?;
-    
+        // else goes wrong, files which were never processed will not exist
+        remove_if_exists(&path.join(upload::preview_file_name(&form.file_name))).await?;
+        remove_if_exists(&path.join(form.file_name)).await?;
+
         transaction.commit().await?;
-    
+
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+async fn fetch_files(conn: &mut PgConnection, schematic_id: Uuid) -> ApiResult<Files> {
+    let files = sqlx::query_as!(
+        SchematicFile,
+        r#"
+        select
+            file_name, display_name, description, position,
+            format as "format: FileFormat", file_size, width, height, length, block_count,
+            fingerprint, preview
+        from
+            schematic_files
+        where
+            schematic_id = $1
+        order by
+            position
+        "#,
+        schematic_id
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(Files { files })
+}
+
+async fn remove_if_exists(path: &std::path::Path) -> ApiResult<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(anyhow::Error::new(e).into()),
+        _ => Ok(())
+    }
+}
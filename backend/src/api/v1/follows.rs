@@ -0,0 +1,247 @@
+use poem::web::Data;
+use poem_openapi::OpenApi;
+use poem_openapi::param::{Path, Query};
+use poem_openapi::payload::Json;
+
+use crate::api::v1::schematics::FullSchematic;
+use crate::authentication::schemes::Session;
+use crate::error::{ApiError, ResultExt};
+use crate::response::ApiResult;
+use crate::models::schematic::{SchematicImage, SchematicVersion};
+use crate::api::ApiContext;
+
+pub (in crate::api::v1) struct FollowsApi;
+
+#[OpenApi(prefix_path="/v1")]
+impl FollowsApi {
+
+    /// Follows a given user as the current user, schematics uploaded or
+    /// updated by them will then appear in the current users feed, see
+    /// `GET /api/v1/feed`
+    ///
+    /// Following a user that is already followed has no effect, however users
+    /// cannot follow themselves, attempting to do so will result in a `400 Bad
+    /// Request` response
+    ///
+    #[oai(path = "/users/:username/follow", method = "put")]
+    async fn follow_user(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(username): Path<String>,
+        Session(user_id): Session
+    ) -> ApiResult<()> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        let user_meta = sqlx::query!(
+            r#"select user_id from users where username = $1"#,
+            username
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        if user_meta.user_id == user_id {
+            return Err(ApiError::BadRequest);
+        }
+
+        sqlx::query!(
+            r#"
+            insert into follows (
+                follower_id, followed_id
+            )
+            values (
+                $1, $2
+            )
+            on conflict do nothing
+            "#,
+            user_id,
+            user_meta.user_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Unfollows a given user as the current user
+    ///
+    /// If the current user does not follow the given user or the given user
+    /// does not exist then a `404 Not Found` error will be returned
+    ///
+    #[oai(path = "/users/:username/follow", method = "delete")]
+    async fn unfollow_user(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(username): Path<String>,
+        Session(user_id): Session
+    ) -> ApiResult<()> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        let result = sqlx::query!(
+            r#"
+            delete from follows
+            where follower_id = $1
+            and followed_id = (select user_id from users where username = $2)
+            "#,
+            user_id,
+            username
+        )
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+
+        transaction.commit().await?;
+
+        if result == 0 {
+            return Err(ApiError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    /// Follows a given tag as the current user, schematics with this tag will
+    /// then appear in the current users feed, see `GET /api/v1/feed`
+    ///
+    /// If the tag does not exist a `404 Not Found` error will be returned
+    ///
+    #[oai(path = "/tags/:tag_id/follow", method = "put")]
+    async fn follow_tag(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(tag_id): Path<i64>,
+        Session(user_id): Session
+    ) -> ApiResult<()> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        sqlx::query!(
+            r#"
+            insert into tag_follows (
+                user_id, tag_id
+            )
+            values (
+                $1, $2
+            )
+            on conflict do nothing
+            "#,
+            user_id,
+            tag_id
+        )
+        .execute(&mut *transaction)
+        .await
+        .on_constraint("tag_follows_tag_id_fkey", |_| ApiError::NotFound)?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Unfollows a given tag as the current user
+    ///
+    /// If the current user does not follow the given tag then a `404 Not Found`
+    /// error will be returned
+    ///
+    #[oai(path = "/tags/:tag_id/follow", method = "delete")]
+    async fn unfollow_tag(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(tag_id): Path<i64>,
+        Session(user_id): Session
+    ) -> ApiResult<()> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        let result = sqlx::query!(
+            r#"
+            delete from tag_follows
+            where user_id = $1
+            and tag_id = $2
+            "#,
+            user_id,
+            tag_id
+        )
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+
+        transaction.commit().await?;
+
+        if result == 0 {
+            return Err(ApiError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    /// Fetches the current users feed, this contains schematics uploaded by
+    /// users they follow as well as schematics with tags they follow, newest
+    /// first. Changing the name, body or versions of a schematic will move it
+    /// back to the top of the feed so new versions of schematics are also
+    /// shown, other changes such as locking comments will not.
+    ///
+    /// Schematics are returned in the same format as `GET /api/v1/schematics`
+    ///
+    /// If no limit is specified for the number of schematics to return it will
+    /// default to 20
+    ///
+    #[oai(path = "/feed", method = "get")]
+    async fn get_feed(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        #[oai(validator(maximum(value="50")))] Query(limit): Query<Option<i64>>,
+        #[oai(validator(minimum(value="0")))] Query(offset): Query<Option<i64>>,
+        Session(user_id): Session
+    ) -> ApiResult<Json<Vec<FullSchematic>>> {
+        let schematics = sqlx::query_as!(
+            FullSchematic,
+            r#"
+            select
+                schematic_id as "schematic_id!",
+                schematic_name as "schematic_name!",
+                body as "body!",
+                body_html,
+                author as "author!",
+                author_avatar,
+                author_displayname as "author_displayname!",
+                author_username as "author_username!",
+                downloads as "downloads!",
+                images as "images!: Vec<SchematicImage>",
+                status as "status!",
+                game_versions as "game_versions!: Vec<SchematicVersion>",
+                create_versions as "create_versions!: Vec<SchematicVersion>",
+                created_at as "created_at!",
+                updated_at,
+                tags as "tags!",
+                like_count as "like_count!",
+                dislike_count as "dislike_count!"
+            from
+                full_schematics
+            where
+                status = 'published'
+                and (
+                    author in (
+                        select followed_id
+                        from follows
+                        where follower_id = $1
+                    )
+                    or schematic_id in (
+                        select schematic_id
+                        from applied_tags
+                        inner join tag_follows using (tag_id)
+                        where tag_follows.user_id = $1
+                    )
+                )
+            order by
+                coalesce(content_updated_at, created_at) desc
+            limit $2 offset $3
+            "#,
+            user_id,
+            limit.unwrap_or(20),
+            offset.unwrap_or(0)
+        )
+        .fetch_all(&ctx.pool)
+        .await?;
+
+        Ok(Json(schematics))
+    }
+}
@@ -2,20 +2,49 @@ use poem::web::Data;
 use poem_openapi::param::Path;
 use poem_openapi::payload::Json;
 use poem_openapi_derive::{Object, Multipart, OpenApi};
+use sqlx::PgConnection;
 use uuid::Uuid;
 
 use crate::authentication::schemes::Session;
 use crate::middleware::files::FileUpload;
+use crate::middleware::ratelimit::upload_limit;
+use crate::middleware::validators::Profanity;
+use crate::api::v1::jobs::QueuedJob;
+use crate::api::v1::ordering::{self, Ordered};
+use crate::jobs::Job;
+use crate::jobs::uploads::UploadKind;
+use crate::models::schematic::SchematicImage;
 use crate::storage;
+use crate::storage::images::{self, ImageFormat, ImageSize};
+use crate::storage::upload;
 use crate::api::ApiContext;
 use crate::response::ApiResult;
-use crate::error::ApiError;
+use crate::error::{ApiError, ResultExt};
 
 pub struct ImageApi;
 
 #[derive(Serialize, Debug, Object)]
 pub struct Images {
-    pub images: Vec<String>
+    pub images: Vec<Image>
+}
+
+#[derive(Serialize, Debug, Object)]
+pub struct Image {
+    #[oai(flatten)]
+    #[serde(flatten)]
+    pub image: SchematicImage,
+    /// Every size and format the image is available in, this is empty until
+    /// the image has been processed
+    pub variants: Vec<ImageVariant>
+}
+
+#[derive(Serialize, Debug, Object)]
+pub struct ImageVariant {
+    pub size: ImageSize,
+    pub format: ImageFormat,
+    pub width: i32,
+    pub height: i32,
+    pub url: String
 }
 
 #[derive(Multipart, Debug)]
@@ -28,57 +57,92 @@ pub struct DeleteImage {
     pub file_name: String
 }
 
+#[derive(Multipart, Debug)]
+pub struct UpdateImage {
+    /// Setting this to an empty string will remove the caption
+    #[oai(validator(max_length=200, custom="Profanity"))]
+    pub caption: Option<String>,
+    /// A description of the image for screen readers, setting this to an
+    /// empty string will remove it
+    #[oai(validator(max_length=300, custom="Profanity"))]
+    pub alt_text: Option<String>,
+    /// Only `true` is accepted, to change the cover select another image
+    pub is_cover: Option<bool>
+}
+
+#[derive(Multipart, Debug)]
+pub struct ImageOrder {
+    /// The file names of every image on the schematic in their new order
+    pub file_names: Vec<String>
+}
+
 #[OpenApi(prefix_path="/v1")]
 impl ImageApi {
 
-    /// Fetches the file names of all images associated with a given schematic
+    /// Fetches all images associated with a given schematic, the cover image
+    /// is always first followed by the rest in order of their position. Each
+    /// image includes the sizes and formats it is available in
     /// 
     /// Note this does not return the image files themselves they can be
-    /// retrieved from the static file endpoint here
-    /// `GET /upload/schematics/{schematic_id}/images/{image_name}.{extension}`
+    /// retrieved from the static file endpoint using the url of each variant
+    /// `GET /upload/schematics/{schematic_id}/images/{variant_name}`
+    /// 
+    /// Clients should prefer the `avif` variants where supported as these are
+    /// typically much smaller than their `webp` equivalents
     /// 
     #[oai(path="/schematics/:schematic_id/images", method="get")]
     async fn get_images_from_schematic(
         &self,
-        Data(ctx): Data<&ApiContext>,    
+        Data(ctx): Data<&ApiContext>,
         Path(schematic_id): Path<Uuid>
     ) -> ApiResult<Json<Images>> {
-        sqlx::query_as!(
-            Images,
-            r#"
-            select images
-            from schematics
-            where schematic_id = $1
-            "#,
+        let mut transaction = ctx.pool.begin().await?;
+
+        sqlx::query!(
+            r#"select schematic_id from schematics where schematic_id = $1"#,
             schematic_id
         )
-        .fetch_optional(&ctx.pool)
+        .fetch_optional(&mut *transaction)
         .await?
-        .ok_or(ApiError::NotFound)
-        .map(Json)
+        .ok_or(ApiError::NotFound)?;
+
+        let images = fetch_images(&mut transaction, schematic_id).await?;
+
+        Ok(Json(images))
     }
 
-    /// Uploads a new image to an existing schematic, for supported image formats
-    /// see the image crate as this is used to ensure that images are valid.
+    /// Uploads a new image to an existing schematic, only still png, jpeg, gif
+    /// and webp images are supported. Animated images, images over 8192 pixels
+    /// in either dimension and files with data hidden after the image will be
+    /// rejected with a `422 Unprocessable Entity` response explaining why.
     /// 
-    /// https://github.com/image-rs/image?tab=readme-ov-file#supported-image-formats
+    /// Any metadata such as EXIF or GPS information is removed from the image
+    /// when it is processed, along with the original file.
     /// 
     /// File names cannot overlap, if an image with a given name is already added
-    /// to the schematic then the request will be rejected with a `409 Conflict`
-    /// response.
+    /// to the schematic then the request will be rejected with a `422 Unprocessable
+    /// Entity` response.
     /// 
     /// Aswell as this file names cannot contain profanity if the file name is deemed
-    /// to be profane the request will be rejected 
+    /// to be profane the request will be rejected
+    /// 
+    /// New images are added after all existing images, if the schematic has no
+    /// cover image then this will become the cover.
+    /// 
+    /// If another image is added to the schematic at the same time then a `409
+    /// Conflict` response is returned and the request can be retried
     /// 
-    #[oai(path="/schematics/:schematic_id/images", method="post")]
+    /// The image is processed in the background so will not be available right
+    /// away, the returned job can be used to check on its progress
+    /// 
+    #[oai(path="/schematics/:schematic_id/images", method="post", transform="upload_limit")]
     async fn upload_image_to_schematic(
         &self,
-        Data(ctx): Data<&ApiContext>,  
+        Data(ctx): Data<&ApiContext>,
         Path(schematic_id): Path<Uuid>,
         Session(user_id): Session,
         form: UploadImage
-    ) -> ApiResult<()> {
-        let file_name = form.image.file_name.ok_or(ApiError::BadRequest)?;
+    ) -> ApiResult<Json<QueuedJob>> {
         let mut transaction = ctx.pool.begin().await?;
 
         let schematic_meta = sqlx::query!(
@@ -93,30 +157,178 @@ impl ImageApi {
             return Err(ApiError::Forbidden);
         }
 
+        let pending_dir = upload::build_pending_directory(&schematic_id)?;
+        let images = upload::stage_images(&pending_dir, vec![form.image]).await?;
+
         sqlx::query!(
             r#"
-            update schematics
-            set images = array_append(images, $1)
-            where schematic_id = $2
+            insert into schematic_images (
+                schematic_id, file_name, position, is_cover
+            )
+            select
+                $1, file_name,
+                coalesce((
+                    select max(position)
+                    from schematic_images
+                    where schematic_id = $1
+                ), -1) + position::integer,
+                not exists (
+                    select 1
+                    from schematic_images
+                    where schematic_id = $1 and is_cover
+                ) and position = 1
+            from
+                unnest($2::text[]) with ordinality as image(file_name, position)
             "#,
-            file_name,
-            schematic_id
+            schematic_id,
+            &images[..]
         )
         .execute(&mut *transaction)
-        .await?;
+        .await
+        .on_constraint("schematic_images_pkey", |_| {
+            ApiError::unprocessable_entity([("image", "an image with that name already exists")])
+        })?;
+
+        let directory = upload::pending_directory_name(&pending_dir)?;
+
+        let job_id = Job::ProcessUpload { schematic_id, directory, upload: UploadKind::Images }
+            .enqueue(&mut *transaction, Some(user_id))
+            .await?;
 
-        let location = storage::schematic_image_path(&schematic_id);
+        ordering::commit(transaction, Ordered::Images).await?;
+
+        let _persist = pending_dir.into_path();
+
+        Ok(Json(QueuedJob { job_id }))
+    }
+
+    /// Updates the caption or alt text of an image, or makes it the cover
+    /// image for the schematic. All fields are optional but at least one is
+    /// required
+    /// 
+    /// Setting `is_cover` to `true` will replace the existing cover image, a
+    /// schematic always has a cover so it cannot be set to `false`
+    /// 
+    /// This endpoint requires the user to own the schematic
+    /// 
+    #[oai(path="/schematics/:schematic_id/images/:file_name", method="patch")]
+    async fn update_image(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(schematic_id): Path<Uuid>,
+        Path(file_name): Path<String>,
+        Session(user_id): Session,
+        form: UpdateImage
+    ) -> ApiResult<Json<SchematicImage>> {
+        if form.is_cover == Some(false) {
+            return Err(ApiError::unprocessable_entity([("is_cover", "select another image as the cover instead")]));
+        }
+
+        let mut transaction = ctx.pool.begin().await?;
+
+        let schematic_meta = sqlx::query!(
+            r#"select author from schematics where schematic_id = $1"#,
+            schematic_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        if schematic_meta.author != user_id {
+            return Err(ApiError::Forbidden);
+        }
+
+        if form.is_cover == Some(true) {
+            sqlx::query!(
+                r#"
+                update schematic_images
+                    set is_cover = false
+                    where schematic_id = $1
+                    and file_name <> $2
+                    and is_cover
+                "#,
+                schematic_id,
+                file_name
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
+
+        let image = sqlx::query_as!(
+            SchematicImage,
+            r#"
+            update schematic_images
+                set
+                    caption = case when $1::text is null then caption else nullif($1, '') end,
+                    alt_text = case when $2::text is null then alt_text else nullif($2, '') end,
+                    is_cover = coalesce($3, is_cover)
+                where schematic_id = $4
+                and file_name = $5
+                returning
+                    file_name, position, caption, alt_text,
+                    is_cover, width, height, blurhash
+            "#,
+            form.caption,
+            form.alt_text,
+            form.is_cover,
+            schematic_id,
+            file_name
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
 
-        storage::upload::save_image(&location, &file_name, &form.image.contents)?;
         transaction.commit().await?;
-    
-        Ok(())
+
+        Ok(Json(image))
+    }
+
+    /// Changes the order of the images on a schematic, every image must be
+    /// included exactly once otherwise a `422 Unprocessable Entity` response
+    /// will be returned. The cover image is always returned first regardless
+    /// of its position
+    /// 
+    /// If an image is added while reordering then a `409 Conflict` response
+    /// is returned
+    /// 
+    /// This endpoint requires the user to own the schematic
+    /// 
+    #[oai(path="/schematics/:schematic_id/images/order", method="put")]
+    async fn reorder_images(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(schematic_id): Path<Uuid>,
+        Session(user_id): Session,
+        form: ImageOrder
+    ) -> ApiResult<Json<Images>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        let schematic_meta = sqlx::query!(
+            r#"select author from schematics where schematic_id = $1"#,
+            schematic_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        if schematic_meta.author != user_id {
+            return Err(ApiError::Forbidden);
+        }
+
+        ordering::reorder(&mut transaction, Ordered::Images, schematic_id, &form.file_names).await?;
+
+        let images = fetch_images(&mut transaction, schematic_id).await?;
+
+        ordering::commit(transaction, Ordered::Images).await?;
+
+        Ok(Json(images))
     }
 
     /// Removes an image from a schematic
     /// 
     /// Each schematic must have at least one image so requests to remove the
-    /// final one will be rejected with a `400 Bad Request` response.
+    /// final one will be rejected with a `400 Bad Request` response. If the
+    /// cover image is removed the first remaining image will become the cover
     /// 
     /// This endpoint requires the user to either own the schematic or have
     /// permissions to moderate schematics.
@@ -124,15 +336,17 @@ impl ImageApi {
     #[oai(path="/schematics/:schematic_id/images", method="delete")]
     async fn remove_image_from_schematic(
         &self,
-        Data(ctx): Data<&ApiContext>,   
+        Data(ctx): Data<&ApiContext>,
         Path(schematic_id): Path<Uuid>,
         session: Session,
         form: DeleteImage
     ) -> ApiResult<()> {
         let mut transaction = ctx.pool.begin().await?;
-        
+
+        // Locking the schematic prevents two requests removing different images
+        // at once from both seeing another image left and removing the last one
         let schematic_meta = sqlx::query!(
-            r#"select author from schematics where schematic_id = $1"#,
+            r#"select author from schematics where schematic_id = $1 for update"#,
             schematic_id
         )
         .fetch_optional(&mut *transaction)
@@ -144,32 +358,127 @@ impl ImageApi {
             return Err(ApiError::Unauthorized);
         }
 
-        sqlx::query_as!(
-            Images,
+        // Counted once the lock is held so that images removed by a request
+        // which held it before are not included
+        let image_count = sqlx::query_scalar!(
+            r#"select count(*) as "count!" from schematic_images where schematic_id = $1"#,
+            schematic_id
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        if image_count <= 1 {
+            return Err(ApiError::BadRequest);
+        }
+
+        let removed = sqlx::query!(
             r#"
-            update schematics
-                set 
-                    images = array_remove(images, $1)
-                where 
-                    schematic_id = $2
-                    and array_length(images, 1) > 2
-            returning images
+            delete from schematic_images
+                where schematic_id = $1
+                and file_name = $2
+            returning is_cover
             "#,
-            form.file_name,
-            schematic_id
+            schematic_id,
+            form.file_name
         )
         .fetch_optional(&mut *transaction)
         .await?
         .ok_or(ApiError::NotFound)?;
-    
+
+        if removed.is_cover {
+            sqlx::query!(
+                r#"
+                update schematic_images
+                    set is_cover = true
+                    where schematic_id = $1
+                    and file_name = (
+                        select file_name
+                        from schematic_images
+                        where schematic_id = $1
+                        order by position
+                        limit 1
+                    )
+                "#,
+                schematic_id
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
+
         let path = storage::schematic_image_path(&schematic_id);
-    
-        tokio::fs::remove_file(path.join(form.file_name))
-            .await
-            .map_err(anyhow::Error::new)?;
-    
+
+        for size in ImageSize::ALL {
+            for format in ImageFormat::ALL {
+                let variant = images::variant_file_name(&form.file_name, size, format);
+
+                // Images which were never processed will have no files to remove
+                match tokio::fs::remove_file(path.join(variant)).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(anyhow::Error::new(e).into());
+                    },
+                    _ => {}
+                }
+            }
+        }
+
         transaction.commit().await?;
-    
+
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+async fn fetch_images(conn: &mut PgConnection, schematic_id: Uuid) -> ApiResult<Images> {
+    let images = sqlx::query_as!(
+        SchematicImage,
+        r#"
+        select
+            file_name, position, caption, alt_text,
+            is_cover, width, height, blurhash
+        from
+            schematic_images
+        where
+            schematic_id = $1
+        order by
+            is_cover desc, position
+        "#,
+        schematic_id
+    )
+    .fetch_all(conn)
+    .await?
+    .into_iter()
+    .map(|image| {
+        let variants = match (image.width, image.height) {
+            (Some(width), Some(height)) => image_variants(&schematic_id, &image.file_name, width, height),
+            _ => Vec::new()
+        };
+
+        Image { image, variants }
+    })
+    .collect();
+
+    Ok(Images { images })
+}
+
+fn image_variants(schematic_id: &Uuid, file_name: &str, width: i32, height: i32) -> Vec<ImageVariant> {
+    let mut variants = Vec::new();
+
+    for size in ImageSize::ALL {
+        let (width, height) = size.dimensions(width as u32, height as u32);
+
+        for format in ImageFormat::ALL {
+            variants.push(ImageVariant {
+                size,
+                format,
+                width: width as i32,
+                height: height as i32,
+                url: format!(
+                    "/upload/schematics/{}/images/{}",
+                    schematic_id,
+                    images::variant_file_name(file_name, size, format)
+                )
+            });
+        }
+    }
+
+    variants
+}
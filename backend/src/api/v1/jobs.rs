@@ -0,0 +1,83 @@
+use poem::web::Data;
+use poem_openapi::OpenApi;
+use poem_openapi::param::Path;
+use poem_openapi::payload::Json;
+use poem_openapi_derive::Object;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::api::ApiContext;
+use crate::authentication::schemes::Session;
+use crate::error::ApiError;
+use crate::jobs::JobStatus;
+use crate::response::ApiResult;
+
+pub (in crate::api::v1) struct JobsApi;
+
+#[derive(Serialize, Debug, Object)]
+pub (in crate::api::v1) struct JobInfo {
+    pub job_id: Uuid,
+    /// The type of work being done, such as `process_upload`
+    pub kind: String,
+    pub status: JobStatus,
+    /// The number of times the job has been started, this includes the
+    /// current attempt if the job is running
+    pub attempts: i32,
+    pub max_attempts: i32,
+    /// What the job produced once completed, uploading a new schematic gives
+    /// the `compatibility` of the Create blocks it uses and `suggested_tags`,
+    /// see `POST /api/v1/schematics`
+    pub result: Option<serde_json::Value>,
+    pub created_at: OffsetDateTime,
+    pub updated_at: Option<OffsetDateTime>
+}
+
+/// Returned by endpoints which queue work to be done in the background, see
+/// `GET /api/v1/jobs/:id`
+#[derive(Serialize, Debug, Object)]
+pub (in crate::api::v1) struct QueuedJob {
+    pub job_id: Uuid
+}
+
+#[OpenApi(prefix_path="/v1")]
+impl JobsApi {
+
+    /// Fetches the status of a job queued by the current user, such as the
+    /// processing of a newly uploaded schematic. Failed jobs are retried a
+    /// number of times before being marked as failed
+    ///
+    /// If the job does not exist or was not queued by the current user then
+    /// a `404 Not Found` error will be returned
+    ///
+    #[oai(path = "/jobs/:job_id", method = "get")]
+    async fn get_job_by_id(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(job_id): Path<Uuid>,
+        Session(user_id): Session
+    ) -> ApiResult<Json<JobInfo>> {
+        sqlx::query_as!(
+            JobInfo,
+            r#"
+            select
+                job_id,
+                payload->>'kind' as "kind!",
+                status, attempts,
+                max_attempts, result,
+                created_at,
+                updated_at
+            from
+                jobs
+            where
+                job_id = $1
+                and user_id = $2
+            "#,
+            job_id,
+            user_id
+        )
+        .fetch_optional(&ctx.pool)
+        .await?
+        .ok_or(ApiError::NotFound)
+        .map(Json)
+    }
+}
@@ -107,5 +107,97 @@ impl LikesApi {
             Err(ApiError::NotFound)
         }
     }
-}
 
+    /// Adds either a like or dislike reaction to a comment by the current user.
+    /// If this user has already reacted to the comment, their reaction will be
+    /// updated instead. Each user can only have one reaction on a given comment.
+    /// 
+    /// Users cannot react to their own comments or to comments that have been
+    /// removed, attempting to do so will result in a `400 Bad Request` response
+    /// 
+    /// If you are looking to remove a reaction see `DELETE /api/v1/comments/:id/like`
+    /// 
+    #[oai(path = "/comments/:comment_id/like", method="put")]
+    async fn like_comment(
+        &self,
+        Data(ctx): Data<&ApiContext>,    
+        Session(user_id): Session,
+        Path(comment_id): Path<Uuid>,
+        Query(query): Query<LikeAction>
+    ) -> ApiResult<()> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        let comment_meta = sqlx::query!(
+            r#"select comment_author, is_deleted from comments where comment_id = $1"#,
+            comment_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        if comment_meta.comment_author == user_id || comment_meta.is_deleted {
+            return Err(ApiError::BadRequest);
+        }
+
+        sqlx::query!(
+            r#"
+            insert into comment_likes (
+                comment_id, user_id,
+                positive
+            )
+            values (
+                $1, $2, $3
+            )
+            on conflict (
+                comment_id, user_id
+            )
+            do update set positive = $3
+            "#,
+            comment_id,
+            user_id,
+            query.positive()
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        transaction.commit().await?;
+    
+        Ok(())
+    }
+
+    /// Removes a like or dislike reaction from a comment by the current user.
+    /// 
+    /// If the user hasnt already reacted to the comment or a comment with the
+    /// given id doesnt exist then a `404 Not Found` error will be returned
+    /// 
+    #[oai(path = "/comments/:comment_id/like", method="delete")]
+    async fn remove_like_from_comment(
+        &self,
+        Data(ctx): Data<&ApiContext>,    
+        Session(user_id): Session,
+        Path(comment_id): Path<Uuid>,
+    ) -> ApiResult<()> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        let result = sqlx::query!(
+            r#"
+            delete from comment_likes
+            where user_id = $1
+            and comment_id = $2
+            "#,
+            user_id,
+            comment_id
+        )
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+
+        transaction.commit().await?;
+
+        if result == 0 {
+            return Err(ApiError::NotFound);
+        }
+
+        Ok(())
+    }
+}
\ No newline at end of file
@@ -0,0 +1,361 @@
+use std::collections::HashSet;
+
+use poem::web::Data;
+use poem_openapi::OpenApi;
+use poem_openapi::param::Path;
+use poem_openapi::payload::Json;
+use poem_openapi_derive::Object;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::api::ApiContext;
+use crate::helpers::mentions;
+use crate::response::ApiResult;
+
+pub (in crate::api::v1) struct MentionsApi;
+
+/// The most users notified about being mentioned by a single comment or
+/// schematic body, any further mentions are still stored but do not send
+/// notifications
+const MAX_MENTION_NOTIFICATIONS: usize = 10;
+
+/// A resolved `@username` mention, the username and displayname are those the
+/// user currently has which may differ from the text within the span if they
+/// have since changed their username
+#[derive(Debug, Serialize, Object)]
+pub (in crate::api::v1) struct Mention {
+    pub user_id: Uuid,
+    pub username: String,
+    pub displayname: String,
+    pub span_start: i32,
+    pub span_end: i32,
+}
+
+#[derive(Debug, Serialize, Object)]
+pub (in crate::api::v1) struct CommentMention {
+    pub comment_id: Uuid,
+    #[oai(flatten)]
+    #[serde(flatten)]
+    pub mention: Mention,
+}
+
+/// The spans of mentions within a body which refer to existing users
+struct ResolvedMentions {
+    user_ids: Vec<Uuid>,
+    span_starts: Vec<i32>,
+    span_ends: Vec<i32>,
+}
+
+#[OpenApi(prefix_path="/v1")]
+impl MentionsApi {
+
+    /// Fetches the users mentioned within the body of a given schematic along
+    /// with where in the body they were mentioned. Spans are measured in UTF-16
+    /// code units, as with javascript string indices, start at the leading `@`
+    /// and are ordered by their position
+    ///
+    /// Mentions of usernames that did not belong to a user at the time the
+    /// body was written are not included
+    ///
+    #[oai(path = "/schematics/:schematic_id/mentions", method = "get")]
+    async fn get_schematic_mentions(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(schematic_id): Path<Uuid>
+    ) -> ApiResult<Json<Vec<Mention>>> {
+        let mentions = sqlx::query_as!(
+            Mention,
+            r#"
+            select
+                user_id, username,
+                displayname, span_start,
+                span_end
+            from
+                schematic_mentions
+                inner join users using (user_id)
+            where
+                schematic_id = $1
+            order by
+                span_start
+            "#,
+            schematic_id
+        )
+        .fetch_all(&ctx.pool)
+        .await?;
+
+        Ok(Json(mentions))
+    }
+
+    /// Fetches the users mentioned within a given comment, in the same format
+    /// as `GET /api/v1/schematics/:id/mentions`
+    ///
+    #[oai(path = "/comments/:comment_id/mentions", method = "get")]
+    async fn get_comment_mentions(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(comment_id): Path<Uuid>
+    ) -> ApiResult<Json<Vec<Mention>>> {
+        let mentions = sqlx::query_as!(
+            Mention,
+            r#"
+            select
+                user_id, username,
+                displayname, span_start,
+                span_end
+            from
+                comment_mentions
+                inner join users using (user_id)
+            where
+                comment_id = $1
+            order by
+                span_start
+            "#,
+            comment_id
+        )
+        .fetch_all(&ctx.pool)
+        .await?;
+
+        Ok(Json(mentions))
+    }
+
+    /// Fetches the users mentioned within every comment on a given schematic
+    /// so that a page of comments can be rendered without needing to request
+    /// the mentions for each comment individually
+    ///
+    #[oai(path = "/schematics/:schematic_id/comments/mentions", method = "get")]
+    async fn get_schematic_comment_mentions(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(schematic_id): Path<Uuid>
+    ) -> ApiResult<Json<Vec<CommentMention>>> {
+        let mentions = sqlx::query!(
+            r#"
+            select
+                comment_id, user_id,
+                username, displayname,
+                span_start, span_end
+            from
+                comment_mentions
+                inner join comments using (comment_id)
+                inner join users using (user_id)
+            where
+                schematic_id = $1
+            order by
+                comment_id, span_start
+            "#,
+            schematic_id
+        )
+        .fetch_all(&ctx.pool)
+        .await?
+        .into_iter()
+        .map(|row| CommentMention {
+            comment_id: row.comment_id,
+            mention: Mention {
+                user_id: row.user_id,
+                username: row.username,
+                displayname: row.displayname,
+                span_start: row.span_start,
+                span_end: row.span_end,
+            }
+        })
+        .collect();
+
+        Ok(Json(mentions))
+    }
+}
+
+/// Replaces the stored mentions for a given comment with those found in its
+/// new body and notifies any users who have been newly mentioned, other than
+/// the author themselves
+pub (in crate::api::v1) async fn store_comment_mentions(
+    conn: &mut PgConnection,
+    comment_id: Uuid,
+    schematic_id: Uuid,
+    author: Uuid,
+    body: &str
+) -> ApiResult<()> {
+    let previous = sqlx::query_scalar!(
+        r#"
+        delete from comment_mentions
+        where comment_id = $1
+        returning user_id
+        "#,
+        comment_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let resolved = resolve_mentions(&mut *conn, body).await?;
+
+    sqlx::query!(
+        r#"
+        insert into comment_mentions (
+            comment_id, user_id,
+            span_start, span_end
+        )
+        select $1, user_id, span_start, span_end
+        from unnest($2::uuid[], $3::integer[], $4::integer[])
+            as mention(user_id, span_start, span_end)
+        "#,
+        comment_id,
+        &resolved.user_ids[..],
+        &resolved.span_starts[..],
+        &resolved.span_ends[..]
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let link = format!("/schematics/{schematic_id}#comment-{comment_id}");
+    let recipients = new_recipients(&resolved, &previous, author);
+
+    notify_mentioned(&mut *conn, &recipients, author, "a comment", &link).await
+}
+
+/// Replaces the stored mentions for a given schematic with those found in its
+/// new body and notifies any users who have been newly mentioned, other than
+/// the author themselves
+pub (in crate::api::v1) async fn store_schematic_mentions(
+    conn: &mut PgConnection,
+    schematic_id: Uuid,
+    author: Uuid,
+    body: &str
+) -> ApiResult<()> {
+    let previous = sqlx::query_scalar!(
+        r#"
+        delete from schematic_mentions
+        where schematic_id = $1
+        returning user_id
+        "#,
+        schematic_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let resolved = resolve_mentions(&mut *conn, body).await?;
+
+    sqlx::query!(
+        r#"
+        insert into schematic_mentions (
+            schematic_id, user_id,
+            span_start, span_end
+        )
+        select $1, user_id, span_start, span_end
+        from unnest($2::uuid[], $3::integer[], $4::integer[])
+            as mention(user_id, span_start, span_end)
+        "#,
+        schematic_id,
+        &resolved.user_ids[..],
+        &resolved.span_starts[..],
+        &resolved.span_ends[..]
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let link = format!("/schematics/{schematic_id}");
+    let recipients = new_recipients(&resolved, &previous, author);
+
+    notify_mentioned(&mut *conn, &recipients, author, "a schematic", &link).await
+}
+
+/// Looks up the users referred to by each mention in the given body, since
+/// usernames are case insensitive `@Someone` and `@someone` both resolve to
+/// the same user. Mentions of usernames that do not exist are dropped
+async fn resolve_mentions(
+    conn: &mut PgConnection,
+    body: &str
+) -> ApiResult<ResolvedMentions> {
+    let mentions = mentions::parse(body);
+    let mut resolved = ResolvedMentions {
+        user_ids: Vec::with_capacity(mentions.len()),
+        span_starts: Vec::with_capacity(mentions.len()),
+        span_ends: Vec::with_capacity(mentions.len()),
+    };
+
+    if mentions.is_empty() {
+        return Ok(resolved);
+    }
+
+    let usernames: Vec<String> = mentions
+        .iter()
+        .map(|mention| mention.username.clone())
+        .collect();
+
+    let users = sqlx::query!(
+        r#"
+        select user_id, username
+        from users
+        where username = any($1)
+        "#,
+        &usernames[..]
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    for mention in mentions {
+        let user = users
+            .iter()
+            .find(|user| user.username.to_lowercase() == mention.username.to_lowercase());
+
+        if let Some(user) = user {
+            resolved.user_ids.push(user.user_id);
+            resolved.span_starts.push(mention.start as i32);
+            resolved.span_ends.push(mention.end as i32);
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// The users who should be notified about being mentioned, this excludes the
+/// author and anyone who was already mentioned before an edit so that editing
+/// a body does not repeatedly notify the same users. Only the first users
+/// mentioned up to `MAX_MENTION_NOTIFICATIONS` are included
+fn new_recipients(
+    resolved: &ResolvedMentions,
+    previous: &[Uuid],
+    author: Uuid
+) -> Vec<Uuid> {
+    let mut seen: HashSet<Uuid> = previous.iter().copied().collect();
+    seen.insert(author);
+
+    resolved.user_ids
+        .iter()
+        .copied()
+        .filter(|user_id| seen.insert(*user_id))
+        .take(MAX_MENTION_NOTIFICATIONS)
+        .collect()
+}
+
+async fn notify_mentioned(
+    conn: &mut PgConnection,
+    recipients: &[Uuid],
+    author: Uuid,
+    location: &str,
+    link: &str
+) -> ApiResult<()> {
+    if recipients.is_empty() {
+        return Ok(());
+    }
+
+    sqlx::query!(
+        r#"
+        insert into notifications (
+            user_id, title, body, link
+        )
+        select
+            recipient,
+            'You were mentioned',
+            (select username from users where user_id = $2) || ' mentioned you in ' || $3,
+            $4
+        from unnest($1::uuid[]) as recipient
+        "#,
+        recipients,
+        author,
+        location,
+        link
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
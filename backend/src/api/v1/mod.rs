@@ -6,13 +6,19 @@ use self::images::ImageApi;
 use self::collections::CollectionsApi;
 use self::moderation::ModerationApi;
 use self::mods::ModApi;
+use self::modpacks::ModpackApi;
 use self::notifications::NotificationApi;
 use self::tags::TagsApi;
 use self::users::UsersApi;
+use self::versions::VersionsApi;
 use self::schematics::SchematicsApi;
 use self::comments::CommentsApi;
+use self::follows::FollowsApi;
+use self::mentions::MentionsApi;
+use self::jobs::JobsApi;
 
 pub mod users;
+pub mod versions;
 pub mod notifications;
 pub mod schematics;
 pub mod comments;
@@ -22,7 +28,12 @@ pub mod images;
 pub mod files;
 pub mod collections;
 pub mod mods;
+pub mod modpacks;
 pub mod moderation;
+pub mod follows;
+pub mod mentions;
+pub mod jobs;
+pub mod ordering;
 
 pub fn configure() -> impl OpenApi {
     (
@@ -36,6 +47,11 @@ pub fn configure() -> impl OpenApi {
         TagsApi, 
         CollectionsApi, 
         ModApi,
+        ModpackApi,
         ModerationApi,
+        FollowsApi,
+        MentionsApi,
+        JobsApi,
+        VersionsApi,
     )
 }
\ No newline at end of file
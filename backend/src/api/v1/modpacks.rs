@@ -0,0 +1,278 @@
+use poem::web::Data;
+use poem_openapi::param::{Path, Query};
+use poem_openapi::payload::Json;
+use poem_openapi::{OpenApi, Object};
+use poem_openapi_derive::Multipart;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::api::ApiContext;
+use crate::api::v1::mods::Mod;
+use crate::error::ApiError;
+use crate::metadata::modpacks;
+use crate::middleware::files::FileUpload;
+use crate::middleware::ratelimit::upload_limit;
+use crate::response::ApiResult;
+
+pub (in crate::api::v1) struct ModpackApi;
+
+#[derive(Multipart, Debug)]
+pub (in crate::api::v1) struct Modpack {
+    /// Either the `modrinth.index.json` file from within a `.mrpack` or the
+    /// `manifest.json` file from within a CurseForge modpack
+    pub manifest: Option<FileUpload>,
+    /// Any mods within the modpack, in addition to those read from the
+    /// manifest. Each may be the id of a mod, its namespace, its Modrinth
+    /// slug or id, or its CurseForge project id
+    #[oai(validator(max_items=1024))]
+    pub mods: Vec<String>
+}
+
+#[derive(Serialize, Object, Debug)]
+pub (in crate::api::v1) struct SchematicCompatibility {
+    /// The ids of mods within the modpack which are known, mods which are not
+    /// used by any schematic may not be included
+    pub mod_ids: Vec<Uuid>,
+    pub schematics: Vec<CompatibleSchematic>,
+    /// The details of every mod missing from one or more schematics
+    pub missing_mods: Vec<Mod>
+}
+
+#[derive(Serialize, Object, Debug)]
+pub (in crate::api::v1) struct CompatibleSchematic {
+    pub schematic_id: Uuid,
+    pub schematic_name: String,
+    pub compatible: bool,
+    pub missing_mod_ids: Vec<Uuid>
+}
+
+#[derive(Serialize, Object, Debug)]
+pub (in crate::api::v1) struct FileCompatibility {
+    /// The ids of mods within the modpack which are known, mods which are not
+    /// used by any schematic may not be included
+    pub mod_ids: Vec<Uuid>,
+    pub files: Vec<CompatibleFile>,
+    /// The details of every mod missing from one or more files
+    pub missing_mods: Vec<Mod>
+}
+
+#[derive(Serialize, Object, Debug)]
+pub (in crate::api::v1) struct CompatibleFile {
+    pub file_name: String,
+    pub display_name: Option<String>,
+    pub compatible: bool,
+    pub missing_mod_ids: Vec<Uuid>
+}
+
+#[OpenApi(prefix_path="/v1")]
+impl ModpackApi {
+    /// Checks which published schematics can be built using only the mods within
+    /// a modpack, returning the mods which are missing for those that cannot. The
+    /// modpack can be given as a manifest, a list of mods or both
+    ///
+    /// Schematics missing the fewest mods are returned first, to only include
+    /// schematics which can or cannot be built set `compatible`. If no limit is
+    /// specified 20 schematics will be returned by default
+    ///
+    /// Mods are matched by their Modrinth or CurseForge project so mods which
+    /// have not been synced yet, or are not published on either, can only be
+    /// included by their id or namespace
+    ///
+    #[oai(path = "/modpacks/schematics", method = "post", transform = "upload_limit")]
+    async fn check_schematics(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        #[oai(validator(maximum(value="50")))] Query(limit): Query<Option<i64>>,
+        Query(offset): Query<Option<i64>>,
+        Query(compatible): Query<Option<bool>>,
+        form: Modpack
+    ) -> ApiResult<Json<SchematicCompatibility>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        let mod_ids = resolve_modpack(&mut transaction, &form).await?;
+
+        let schematics = sqlx::query!(
+            r#"
+            select
+                schematic_id,
+                schematic_name,
+                coalesce(
+                    array_agg(mod_id) filter (where mod_id is not null and not mod_id = any($1)),
+                    array[]::uuid[]
+                ) as "missing_mod_ids!"
+            from
+                schematics
+                left join mod_dependencies using (schematic_id)
+            where
+                schematics.status = 'published'
+            group by
+                schematic_id
+            having
+                $2::bool is null
+                or (count(mod_id) filter (where not mod_id = any($1)) = 0) = $2
+            order by
+                count(mod_id) filter (where not mod_id = any($1)),
+                schematics.created_at desc
+            limit $3 offset $4
+            "#,
+            &mod_ids[..],
+            compatible,
+            limit.unwrap_or(20),
+            offset.unwrap_or(0)
+        )
+        .fetch_all(&mut *transaction)
+        .await?
+        .into_iter()
+        .map(|row| CompatibleSchematic {
+            schematic_id: row.schematic_id,
+            schematic_name: row.schematic_name,
+            compatible: row.missing_mod_ids.is_empty(),
+            missing_mod_ids: row.missing_mod_ids
+        })
+        .collect::<Vec<_>>();
+
+        let missing: Vec<Uuid> = schematics.iter()
+            .flat_map(|schematic| schematic.missing_mod_ids.iter().copied())
+            .collect();
+
+        let missing_mods = fetch_mods(&mut transaction, &missing).await?;
+
+        Ok(Json(SchematicCompatibility { mod_ids, schematics, missing_mods }))
+    }
+
+    /// Checks which files of a schematic can be built using only the mods within
+    /// a modpack, returning the mods which are missing for those that cannot. The
+    /// modpack can be given as a manifest, a list of mods or both, see
+    /// `POST /api/v1/modpacks/schematics`
+    ///
+    #[oai(path = "/modpacks/schematics/:schematic_id/files", method = "post", transform = "upload_limit")]
+    async fn check_schematic_files(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(schematic_id): Path<Uuid>,
+        form: Modpack
+    ) -> ApiResult<Json<FileCompatibility>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        sqlx::query!(
+            r#"select schematic_id from schematics where schematic_id = $1"#,
+            schematic_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        let mod_ids = resolve_modpack(&mut transaction, &form).await?;
+
+        let files = sqlx::query!(
+            r#"
+            select
+                file_name,
+                display_name,
+                coalesce(
+                    array_agg(mod_id) filter (where mod_id is not null and not mod_id = any($2)),
+                    array[]::uuid[]
+                ) as "missing_mod_ids!"
+            from
+                schematic_files
+                left join file_dependencies using (schematic_id, file_name)
+            where
+                schematic_files.schematic_id = $1
+            group by
+                file_name, display_name, position
+            order by
+                position
+            "#,
+            schematic_id,
+            &mod_ids[..]
+        )
+        .fetch_all(&mut *transaction)
+        .await?
+        .into_iter()
+        .map(|row| CompatibleFile {
+            file_name: row.file_name,
+            display_name: row.display_name,
+            compatible: row.missing_mod_ids.is_empty(),
+            missing_mod_ids: row.missing_mod_ids
+        })
+        .collect::<Vec<_>>();
+
+        let missing: Vec<Uuid> = files.iter()
+            .flat_map(|file| file.missing_mod_ids.iter().copied())
+            .collect();
+
+        let missing_mods = fetch_mods(&mut transaction, &missing).await?;
+
+        Ok(Json(FileCompatibility { mod_ids, files, missing_mods }))
+    }
+}
+
+/// Finds the known mods within a modpack from its manifest and any mods given
+/// alongside it, at least one of these must be included. Mods which are not
+/// known are ignored
+async fn resolve_modpack(conn: &mut PgConnection, form: &Modpack) -> ApiResult<Vec<Uuid>> {
+    let included = match &form.manifest {
+        Some(manifest) => modpacks::read_manifest(manifest)?,
+        None if form.mods.is_empty() => {
+            return Err(ApiError::unprocessable_entity([("manifest", "either a manifest or mods are required")]));
+        }
+        None => Default::default()
+    };
+
+    let mods: Vec<String> = form.mods.iter()
+        .map(|identifier| identifier.trim().to_string())
+        .collect();
+
+    // Namespaces and slugs are always lowercase, as are ids once formatted,
+    // whereas Modrinth ids are case sensitive
+    let lowercase: Vec<String> = mods.iter()
+        .map(|identifier| identifier.to_lowercase())
+        .collect();
+
+    let mod_ids = sqlx::query_scalar!(
+        r#"
+        select mod_id
+        from mods
+        where
+            mod_id::text = any($1)
+            or mod_slug = any($1)
+            or modrinth_slug = any($1)
+            or curseforge_slug::text = any($1)
+            or mod_id in (select mod_id from mod_aliases where namespace = any($1))
+            or modrinth_id = any($2)
+            or curseforge_slug = any($3)
+        "#,
+        &lowercase[..],
+        &[&mods[..], &included.modrinth_ids[..]].concat(),
+        &included.curseforge_ids[..]
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(mod_ids)
+}
+
+async fn fetch_mods(conn: &mut PgConnection, mod_ids: &[Uuid]) -> ApiResult<Vec<Mod>> {
+    let mods = sqlx::query_as!(
+        Mod,
+        r#"
+        select
+            mod_id, curseforge_slug,
+            mod_name, modrinth_slug,
+            mod_slug, icon_url, source_url,
+            issues_url, wiki_url, loaders,
+            game_versions, last_synced_at
+        from
+            mods
+        where
+            mod_id = any($1)
+        order by
+            coalesce(mod_name, mod_slug)
+        "#,
+        mod_ids
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(mods)
+}
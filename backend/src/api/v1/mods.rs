@@ -1,12 +1,19 @@
+use core::fmt;
+
 use poem::web::Data;
 use poem_openapi::param::{Path, Query};
 use poem_openapi::payload::Json;
 use poem_openapi::{OpenApi, Object};
-use poem_openapi_derive::Multipart;
+use poem_openapi_derive::{Multipart, Enum};
+use time::OffsetDateTime;
 use uuid::Uuid;
 
 use crate::authentication::schemes::Session;
-use crate::error::ApiError;
+use crate::error::{ApiError, ResultExt};
+use crate::jobs::Job;
+use crate::storage::dependencies;
+use crate::models::schematic::{Schematic, SchematicImage, SchematicVersion};
+use crate::api::v1::schematics::SortBy;
 use crate::{response::ApiResult, api::ApiContext};
 
 pub (in crate::api::v1) struct ModApi;
@@ -17,7 +24,22 @@ pub (in crate::api::v1) struct Mod {
     pub mod_slug: String,
     pub mod_name: Option<String>,
     pub curseforge_slug: Option<i32>,
-    pub modrinth_slug: Option<String>
+    pub modrinth_slug: Option<String>,
+    pub icon_url: Option<String>,
+    pub source_url: Option<String>,
+    pub issues_url: Option<String>,
+    pub wiki_url: Option<String>,
+    pub loaders: Vec<String>,
+    pub game_versions: Vec<String>,
+    pub last_synced_at: Option<OffsetDateTime>
+}
+
+#[derive(Serialize, Object, Debug)]
+pub (in crate::api::v1) struct ModListing {
+    #[oai(flatten)]
+    #[serde(flatten)]
+    pub details: Mod,
+    pub schematic_count: i64
 }
 
 #[derive(Deserialize, Multipart, Debug)]
@@ -39,8 +61,144 @@ pub (in crate::api::v1) struct ModProposal {
     pub modrinth_slug: Option<String>
 }
 
+#[derive(Serialize, Object, Debug)]
+pub (in crate::api::v1) struct ModAlias {
+    pub namespace: String,
+    pub mod_id: Uuid,
+    pub created_at: OffsetDateTime
+}
+
+#[derive(Deserialize, Multipart, Debug)]
+pub (in crate::api::v1) struct CreateModAlias {
+    #[oai(validator(pattern=r"^[a-z0-9_.-]+$", max_length=64))]
+    pub namespace: String
+}
+
+#[derive(Serialize, Object, Debug)]
+pub (in crate::api::v1) struct IgnoredNamespace {
+    pub namespace: String,
+    pub reason: Option<String>,
+    pub created_at: OffsetDateTime
+}
+
+#[derive(Deserialize, Multipart, Debug)]
+pub (in crate::api::v1) struct IgnoreNamespace {
+    #[oai(validator(pattern=r"^[a-z0-9_.-]+$", max_length=64))]
+    pub namespace: String,
+    #[oai(validator(max_length=256))]
+    pub reason: Option<String>
+}
+
+#[derive(Enum, Deserialize, Debug)]
+#[serde(rename_all="snake_case")]
+#[oai(rename_all="snake_case")]
+pub enum ModSortBy {
+    /// Fetch the mods required by the most published schematics first
+    /// 
+    SchematicCount,
+
+    /// Fetch mods in alphabetical order of their name, or their namespace
+    /// where their name is not known
+    /// 
+    Name,
+
+    /// Fetch the most recently discovered mods first
+    /// 
+    CreatedAt
+}
+
+impl fmt::Display for ModSortBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModSortBy::SchematicCount => write!(f, "schematic_count"),
+            ModSortBy::Name => write!(f, "name"),
+            ModSortBy::CreatedAt => write!(f, "created_at")
+        }
+    }
+}
+
 #[OpenApi(prefix_path="/v1")]
 impl ModApi {
+    /// Lists mods along with the number of published schematics which require
+    /// them, by default the mods used by the most schematics are returned first
+    /// 
+    /// If a search term is given only mods whose name or namespace is similar
+    /// to it will be returned. If no limit is specified 20 will be returned
+    /// 
+    #[oai(path = "/mods", method = "get")]
+    async fn search_mods(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        #[oai(validator(maximum(value="50")))] Query(limit): Query<Option<i64>>,
+        Query(offset): Query<Option<i64>>,
+        Query(term): Query<Option<String>>,
+        Query(sort): Query<Option<ModSortBy>>,
+    ) -> ApiResult<Json<Vec<ModListing>>> {
+        let ordering = sort.unwrap_or(ModSortBy::SchematicCount);
+
+        let mods = sqlx::query!(
+            r#"
+            select
+                mod_id, curseforge_slug,
+                mod_name, modrinth_slug,
+                mod_slug, icon_url, source_url,
+                issues_url, wiki_url, loaders,
+                game_versions, last_synced_at,
+                coalesce(counts.schematic_count, 0) as "schematic_count!"
+            from
+                mods
+                left join (
+                    select mod_id, count(*) as schematic_count
+                    from mod_dependencies
+                    inner join schematics using (schematic_id)
+                    where schematics.status = 'published'
+                    group by mod_id
+                ) counts using (mod_id)
+            where
+                $1::text is null
+                or mod_slug ilike '%' || $1 || '%'
+                or mod_name ilike '%' || $1 || '%'
+                or mod_name % $1
+            order by
+                case when $2 = 'schematic_count' then coalesce(counts.schematic_count, 0) end desc,
+                case when $2 = 'created_at' then mods.created_at end desc,
+                coalesce(mod_name, mod_slug)
+            limit $3 offset $4
+            "#,
+            term,
+            ordering.to_string(),
+            limit.unwrap_or(20),
+            offset.unwrap_or(0)
+        )
+        .fetch_all(&ctx.pool)
+        .await?
+        .into_iter()
+        .map(|row| ModListing {
+            details: Mod {
+                mod_id: row.mod_id,
+                mod_slug: row.mod_slug,
+                mod_name: row.mod_name,
+                curseforge_slug: row.curseforge_slug,
+                modrinth_slug: row.modrinth_slug,
+                icon_url: row.icon_url,
+                source_url: row.source_url,
+                issues_url: row.issues_url,
+                wiki_url: row.wiki_url,
+                loaders: row.loaders,
+                game_versions: row.game_versions,
+                last_synced_at: row.last_synced_at
+            },
+            schematic_count: row.schematic_count
+        })
+        .collect();
+
+        Ok(Json(mods))
+    }
+
+    /// Fetches a mod by its id, details such as its icon, links and supported
+    /// loaders and versions are fetched from Modrinth and CurseForge in the
+    /// background so will be empty until the mod has been synced, as shown by
+    /// `last_synced_at`
     #[oai(path = "/mods/:mod_id", method = "get")]
     async fn fetch_mod_by_id(
         &self,
@@ -53,7 +211,9 @@ impl ModApi {
             select
                 mod_id, curseforge_slug, 
                 mod_name, modrinth_slug,
-                mod_slug
+                mod_slug, icon_url, source_url,
+                issues_url, wiki_url, loaders,
+                game_versions, last_synced_at
             from 
                 mods
             where
@@ -67,6 +227,69 @@ impl ModApi {
         .map(Json)
     }
 
+    /// Fetches published schematics which require the given mod, by default
+    /// the most recently created schematics are returned first
+    /// 
+    /// If a limit is not specified 20 will be fetched by default.
+    /// 
+    #[oai(path = "/mods/:mod_id/schematics", method = "get")]
+    async fn fetch_mod_schematics(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(mod_id): Path<Uuid>,
+        #[oai(validator(maximum(value="50")))] Query(limit): Query<Option<i64>>,
+        Query(offset): Query<Option<i64>>,
+        Query(sort): Query<Option<SortBy>>,
+    ) -> ApiResult<Json<Vec<Schematic>>> {
+        let mut transaction = ctx.pool.begin().await?;
+        let ordering = sort.unwrap_or(SortBy::CreatedAt);
+
+        sqlx::query!(
+            r#"select mod_id from mods where mod_id = $1"#,
+            mod_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        let schematics = sqlx::query_as!(
+            Schematic,
+            r#"
+            select 
+                schematic_id, schematic_name, body, body_html,
+                schematic_images_of(schematics.schematic_id) as "images!: Vec<SchematicImage>",
+                author, downloads, status,
+                schematic_game_versions_of(schematics.schematic_id) as "game_versions!: Vec<SchematicVersion>",
+                schematic_create_versions_of(schematics.schematic_id) as "create_versions!: Vec<SchematicVersion>",
+                created_at, updated_at
+            from 
+                schematics
+                inner join mod_dependencies using (schematic_id)
+            where 
+                mod_dependencies.mod_id = $1
+                and schematics.status = 'published'
+            order by
+                case when $2 = 'downloads' then downloads end desc,
+                case when $2 = 'likes' then (
+                    select count(*)
+                    from schematic_likes
+                    where schematic_likes.schematic_id = schematics.schematic_id
+                    and positive = true
+                ) end desc,
+                created_at desc
+            limit $3 offset $4
+            "#,
+            mod_id,
+            ordering.to_string(),
+            limit.unwrap_or(20),
+            offset.unwrap_or(0)
+        )
+        .fetch_all(&mut *transaction)
+        .await?;
+
+        Ok(Json(schematics))
+    }
+
     #[oai(path = "/mods/:mod_id", method = "patch")]
     async fn update_mod_by_id(
         &self,
@@ -98,6 +321,9 @@ impl ModApi {
             )
             .execute(&mut *transaction)
             .await?;
+
+            // Fetch the details of the mod again in case its slugs changed
+            Job::SyncMod { mod_id }.enqueue(&mut *transaction, Some(user.user_id)).await?;
         } else {
             // If the user doesnt have sufficient permissions we create a proposal
             // that can then be later approved by a moderator or other user with
@@ -249,6 +475,8 @@ impl ModApi {
         .execute(&mut *transaction)
         .await?;
 
+        Job::SyncMod { mod_id: proposal.mod_id }.enqueue(&mut *transaction, None).await?;
+
         transaction.commit().await?;
 
         Ok(())
@@ -281,4 +509,267 @@ impl ModApi {
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Fetches every namespace which is treated as an alias of another mod,
+    /// this requires for the current user to be a moderator
+    /// 
+    #[oai(path = "/mods/aliases", method = "get")]
+    async fn fetch_aliases(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        session: Session
+    ) -> ApiResult<Json<Vec<ModAlias>>> {
+        if !session.is_moderator(&ctx.pool).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        let aliases = sqlx::query_as!(
+            ModAlias,
+            r#"
+            select namespace, mod_id, created_at
+            from mod_aliases
+            order by namespace
+            "#
+        )
+        .fetch_all(&ctx.pool)
+        .await?;
+
+        Ok(Json(aliases))
+    }
+
+    /// Treats a namespace as belonging to the given mod, for instance where an
+    /// addon is bundled within the same jar as the mod. Schematics using blocks
+    /// from this namespace will then depend on the given mod instead.
+    /// 
+    /// If a mod was previously created for the namespace it is merged into the
+    /// given mod, moving over its dependencies and aliases. This requires for the
+    /// current user to be a moderator
+    /// 
+    /// Ignored namespaces cannot be aliased and each namespace can only be an
+    /// alias of a single mod, otherwise a `422 Unprocessable Entity` error will
+    /// be returned
+    /// 
+    #[oai(path = "/mods/:mod_id/aliases", method = "post")]
+    async fn create_alias(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(mod_id): Path<Uuid>,
+        form: CreateModAlias,
+        session: Session
+    ) -> ApiResult<Json<ModAlias>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_moderator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        let mod_slug = sqlx::query_scalar!(
+            r#"select mod_slug from mods where mod_id = $1"#,
+            mod_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        if mod_slug == form.namespace {
+            return Err(ApiError::unprocessable_entity([("namespace", "a mod cannot be an alias of itself")]));
+        }
+
+        let ignored = sqlx::query_scalar!(
+            r#"select exists (select 1 from ignored_namespaces where namespace = $1) as "ignored!""#,
+            form.namespace
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        if ignored {
+            return Err(ApiError::unprocessable_entity([("namespace", "this namespace is ignored")]));
+        }
+
+        let alias = sqlx::query_as!(
+            ModAlias,
+            r#"
+            insert into mod_aliases (
+                namespace, mod_id
+            )
+            values (
+                $1, $2
+            )
+            returning namespace, mod_id, created_at
+            "#,
+            form.namespace,
+            mod_id
+        )
+        .fetch_one(&mut *transaction)
+        .await
+        .on_constraint("mod_aliases_pkey", |_| {
+            ApiError::unprocessable_entity([("namespace", "this namespace is already an alias")])
+        })?;
+
+        dependencies::merge_aliased_mod(&mut transaction, &alias.namespace, mod_id).await?;
+
+        transaction.commit().await?;
+
+        Ok(Json(alias))
+    }
+
+    /// Removes an alias, this only affects schematics uploaded afterwards unless
+    /// dependencies are rebuilt with the `rebuild-dependencies` command. This
+    /// requires for the current user to be a moderator
+    /// 
+    #[oai(path = "/mods/aliases/:namespace", method = "delete")]
+    async fn delete_alias(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(namespace): Path<String>,
+        session: Session
+    ) -> ApiResult<()> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_moderator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        sqlx::query!(
+            r#"
+            delete from mod_aliases
+            where namespace = $1
+            returning namespace
+            "#,
+            namespace
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Fetches every namespace which is never treated as a mod, this requires
+    /// for the current user to be a moderator
+    /// 
+    #[oai(path = "/mods/ignored", method = "get")]
+    async fn fetch_ignored_namespaces(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        session: Session
+    ) -> ApiResult<Json<Vec<IgnoredNamespace>>> {
+        if !session.is_moderator(&ctx.pool).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        let ignored = sqlx::query_as!(
+            IgnoredNamespace,
+            r#"
+            select namespace, reason, created_at
+            from ignored_namespaces
+            order by namespace
+            "#
+        )
+        .fetch_all(&ctx.pool)
+        .await?;
+
+        Ok(Json(ignored))
+    }
+
+    /// Stops a namespace from being treated as a mod, such as `minecraft`. If a
+    /// mod was previously created for the namespace it is removed along with
+    /// any dependencies on it. This requires for the current user to be a
+    /// moderator
+    /// 
+    /// Namespaces which are an alias of another mod cannot be ignored until the
+    /// alias is removed, otherwise a `422 Unprocessable Entity` error will be
+    /// returned
+    /// 
+    #[oai(path = "/mods/ignored", method = "post")]
+    async fn ignore_namespace(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        form: IgnoreNamespace,
+        session: Session
+    ) -> ApiResult<Json<IgnoredNamespace>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_moderator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        let aliased = sqlx::query_scalar!(
+            r#"select exists (select 1 from mod_aliases where namespace = $1) as "aliased!""#,
+            form.namespace
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        if aliased {
+            return Err(ApiError::unprocessable_entity([("namespace", "this namespace is an alias of another mod")]));
+        }
+
+        let ignored = sqlx::query_as!(
+            IgnoredNamespace,
+            r#"
+            insert into ignored_namespaces (
+                namespace, reason
+            )
+            values (
+                $1, $2
+            )
+            returning namespace, reason, created_at
+            "#,
+            form.namespace,
+            form.reason
+        )
+        .fetch_one(&mut *transaction)
+        .await
+        .on_constraint("ignored_namespaces_pkey", |_| {
+            ApiError::unprocessable_entity([("namespace", "this namespace is already ignored")])
+        })?;
+
+        sqlx::query!(
+            r#"delete from mods where mod_slug = $1"#,
+            ignored.namespace
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        transaction.commit().await?;
+
+        Ok(Json(ignored))
+    }
+
+    /// Stops ignoring a namespace, this only affects schematics uploaded
+    /// afterwards unless dependencies are rebuilt with the `rebuild-dependencies`
+    /// command. This requires for the current user to be a moderator
+    /// 
+    #[oai(path = "/mods/ignored/:namespace", method = "delete")]
+    async fn delete_ignored_namespace(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(namespace): Path<String>,
+        session: Session
+    ) -> ApiResult<()> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_moderator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        sqlx::query!(
+            r#"
+            delete from ignored_namespaces
+            where namespace = $1
+            returning namespace
+            "#,
+            namespace
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use sqlx::{PgConnection, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::error::{ApiError, ResultExt};
+use crate::response::ApiResult;
+
+/// The images and files of a schematic are both kept in an order chosen by
+/// its author, positions are unique within a schematic but the constraint is
+/// deferred so that they can be reordered within a single statement
+#[derive(Debug, Clone, Copy)]
+pub (in crate::api::v1) enum Ordered {
+    Images,
+    Files
+}
+
+impl Ordered {
+    fn position_key(&self) -> &'static str {
+        match self {
+            Ordered::Images => "schematic_images_position_key",
+            Ordered::Files => "schematic_files_position_key"
+        }
+    }
+
+    fn order_error(&self) -> ApiError {
+        let message = match self {
+            Ordered::Images => "every image must be included exactly once",
+            Ordered::Files => "every file must be included exactly once"
+        };
+
+        ApiError::unprocessable_entity([("file_names", message)])
+    }
+}
+
+/// Moves the images or files of a schematic to the position of their name
+/// within `file_names`, every one of them must be included exactly once
+/// otherwise a `422 Unprocessable Entity` error is returned
+pub (in crate::api::v1) async fn reorder(
+    conn: &mut PgConnection,
+    ordered: Ordered,
+    schematic_id: Uuid,
+    file_names: &[String]
+) -> ApiResult<()> {
+    let (updated, total) = match ordered {
+        Ordered::Images => {
+            let updated = sqlx::query!(
+                r#"
+                update schematic_images
+                    set position = (image.position - 1)::integer
+                    from unnest($2::text[]) with ordinality as image(file_name, position)
+                    where schematic_images.schematic_id = $1
+                    and schematic_images.file_name = image.file_name
+                "#,
+                schematic_id,
+                file_names
+            )
+            .execute(&mut *conn)
+            .await?
+            .rows_affected();
+
+            let total = sqlx::query_scalar!(
+                r#"select count(*) as "count!" from schematic_images where schematic_id = $1"#,
+                schematic_id
+            )
+            .fetch_one(&mut *conn)
+            .await?;
+
+            (updated, total)
+        }
+        Ordered::Files => {
+            let updated = sqlx::query!(
+                r#"
+                update schematic_files
+                    set position = (file.position - 1)::integer
+                    from unnest($2::text[]) with ordinality as file(file_name, position)
+                    where schematic_files.schematic_id = $1
+                    and schematic_files.file_name = file.file_name
+                "#,
+                schematic_id,
+                file_names
+            )
+            .execute(&mut *conn)
+            .await?
+            .rows_affected();
+
+            let total = sqlx::query_scalar!(
+                r#"select count(*) as "count!" from schematic_files where schematic_id = $1"#,
+                schematic_id
+            )
+            .fetch_one(&mut *conn)
+            .await?;
+
+            (updated, total)
+        }
+    };
+
+    // Duplicate names will only update a row once, so this also ensures
+    // that each one was included only once
+    if updated as i64 != total || file_names.len() as i64 != total {
+        return Err(ordered.order_error());
+    }
+
+    Ok(())
+}
+
+/// Commits a transaction which changed the positions of images or files,
+/// positions are only checked once committed so this fails with `409 Conflict`
+/// if another was added at the same time
+pub (in crate::api::v1) async fn commit(
+    transaction: Transaction<'_, Postgres>,
+    ordered: Ordered
+) -> ApiResult<()> {
+    transaction.commit()
+        .await
+        .on_constraint(ordered.position_key(), |_| ApiError::Conflict)?;
+
+    Ok(())
+}
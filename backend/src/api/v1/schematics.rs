@@ -7,16 +7,22 @@ use poem_openapi::param::{Path, Query};
 use poem_openapi::payload::Json;
 use poem_openapi_derive::{Object, Multipart, Enum};
 use time::OffsetDateTime;
+use sqlx::PgConnection;
 use uuid::Uuid;
 
 use crate::authentication::schemes::Session;
 use crate::error::{ApiError, ResultExt};
+use crate::helpers::markdown;
 use crate::middleware::files::FileUpload;
+use crate::middleware::ratelimit::upload_limit;
 use crate::middleware::validators::Profanity;
 use crate::response::ApiResult;
-use crate::models::schematic::Schematic;
+use crate::models::schematic::{Schematic, SchematicImage, SchematicStatus, SchematicVersion};
 use crate::api::ApiContext;
+use crate::api::v1::mentions;
 use crate::storage::upload;
+use crate::jobs::Job;
+use crate::jobs::uploads::UploadKind;
 
 pub (in crate::api::v1) struct SchematicsApi;
 
@@ -25,6 +31,7 @@ pub (in crate::api::v1) struct FullSchematic {
     pub schematic_id: String,
     pub schematic_name: String,
     pub body: String,
+    pub body_html: Option<String>,
     pub author: Uuid,
     pub author_displayname: String,
     pub author_username: String,
@@ -33,25 +40,36 @@ pub (in crate::api::v1) struct FullSchematic {
     pub dislike_count: i64,
     pub downloads: i64,
     pub tags: Vec<i64>,
-    pub images: Vec<String>,
-    pub game_version_id: i64,
-    pub game_version_name: String,
-    pub create_version_id: i64,
-    pub create_version_name: String,
+    pub images: Vec<SchematicImage>,
+    pub status: SchematicStatus,
+    pub game_versions: Vec<SchematicVersion>,
+    pub create_versions: Vec<SchematicVersion>,
     pub created_at: OffsetDateTime,
     pub updated_at: Option<OffsetDateTime>
 }
 
+#[derive(Debug, Serialize, Object)]
+pub (in crate::api::v1) struct UploadedSchematic {
+    #[oai(flatten)]
+    #[serde(flatten)]
+    pub schematic: Schematic,
+    pub job_id: Uuid
+}
+
 #[derive(Multipart, Debug)]
 pub (in crate::api::v1) struct SchematicBuilder {
     #[oai(validator(min_length=3, max_length=50, custom="Profanity"))]
     pub schematic_name: String,
     #[oai(validator(max_length=2048, custom="Profanity"))]
     pub schematic_body: String,
-    #[oai(validator(minimum(value = "1")))]
-    pub game_version: i32,
-    #[oai(validator(minimum(value = "1")))]
-    pub create_version: i32,
+    /// The ids of every game version the schematic works with, see
+    /// `GET /api/v1/versions/game`
+    #[oai(validator(min_items=1, max_items=32))]
+    pub game_versions: Vec<i32>,
+    /// The ids of every Create version the schematic works with, see
+    /// `GET /api/v1/versions/create`
+    #[oai(validator(min_items=1, max_items=32))]
+    pub create_versions: Vec<i32>,
     #[oai(validator(max_length=10))]
     pub tags: Vec<String>,
     pub files: Vec<FileUpload>,
@@ -64,10 +82,14 @@ pub (in crate::api::v1) struct UpdateSchematic {
     pub schematic_name: Option<String>,
     #[oai(validator(max_length=2048, custom="Profanity"))]
     pub schematic_body: Option<String>,
-    #[oai(validator(minimum(value = "1")))]
-    pub game_version: Option<i32>,
-    #[oai(validator(minimum(value = "1")))]
-    pub create_version: Option<i32>,
+    /// Replaces the game versions the schematic works with, these are left
+    /// unchanged if none are given
+    #[oai(validator(max_items=32))]
+    pub game_versions: Vec<i32>,
+    /// Replaces the Create versions the schematic works with, these are left
+    /// unchanged if none are given
+    #[oai(validator(max_items=32))]
+    pub create_versions: Vec<i32>,
 }
 
 #[derive(Enum, Deserialize, Debug)]
@@ -114,60 +136,32 @@ impl SchematicsApi {
         Data(ctx): Data<&ApiContext>,
         Path(schematic_id): Path<Uuid>
     ) -> ApiResult<Json<FullSchematic>> {
-        // This needs some testing, overall we have two options for
-        // selecting the number of favourites, likes and dislikes. 
-        // We can either join the respective tables then filter them
-        // as done in this query or select them in a sub-query
-        //
-        // coalesce((
-        //    select count(*) from schematic_likes likes 
-        //    where likes.schematic_id = schematics.schematic_id 
-        //    and positive = false
-        // ), 0) as "dislike_count!"
-        // 
-        // It's not clear which would actually perform better so some
-        // testing would be useful. The other other option we have is
-        // to just store a count in the table then update that
-        //
         sqlx::query_as!(
             FullSchematic,
             r#"
-            select 
-                schematic_id, 
-                schematic_name, 
-                body,
-                author, 
-                avatar as author_avatar,
-                displayname as author_displayname,
-                username as author_username,
-                downloads,
-                images,
-                create_version_id, 
-                create_version_name,
-                game_version_id, 
-                game_version_name, 
-                schematics.created_at,
-                schematics.updated_at,
-                coalesce(array_agg(tag_id) filter (where tag_id is not null), array []::bigint[]) as "tags!",
-                coalesce(count(likes.schematic_id) filter (where positive = true), 0) as "like_count!",
-                coalesce(count(likes.schematic_id) filter (where positive = false), 0) as "dislike_count!"
-            from 
-                schematics
-                inner join create_versions using (create_version_id)
-                inner join game_versions using (game_version_id)
-                inner join users on user_id = author
-                left join schematic_likes likes using (schematic_id)
-                left join applied_tags using (schematic_id)
-            where 
+            select
+                schematic_id as "schematic_id!",
+                schematic_name as "schematic_name!",
+                body as "body!",
+                body_html,
+                author as "author!",
+                author_avatar,
+                author_displayname as "author_displayname!",
+                author_username as "author_username!",
+                downloads as "downloads!",
+                images as "images!: Vec<SchematicImage>",
+                status as "status!",
+                game_versions as "game_versions!: Vec<SchematicVersion>",
+                create_versions as "create_versions!: Vec<SchematicVersion>",
+                created_at as "created_at!",
+                updated_at,
+                tags as "tags!",
+                like_count as "like_count!",
+                dislike_count as "dislike_count!"
+            from
+                full_schematics
+            where
                 schematic_id = $1
-            group by 
-                schematic_id,
-                avatar,
-                game_version_id,
-                game_version_name,
-                displayname,
-                username,
-                create_version_name
             "#,
             schematic_id
         )
@@ -209,6 +203,14 @@ impl SchematicsApi {
             return Err(ApiError::Unauthorized);
         }
 
+        if !form.game_versions.is_empty() {
+            set_game_versions(&mut transaction, schematic_id, &form.game_versions).await?;
+        }
+
+        if !form.create_versions.is_empty() {
+            set_create_versions(&mut transaction, schematic_id, &form.create_versions).await?;
+        }
+
         let schematic = sqlx::query_as!(
             Schematic,
             r#"
@@ -216,31 +218,43 @@ impl SchematicsApi {
                 set
                     schematic_name = coalesce($1, schematic_name),
                     body = coalesce($2, body),
-                    game_version_id = coalesce($3, game_version_id),
-                    create_version_id = coalesce($4, create_version_id)
-                where schematic_id = $5
+                    body_html = coalesce($3, body_html),
+                    content_updated_at = case when $5 then now() else content_updated_at end
+                where schematic_id = $4
                 returning
                     schematic_id,
                     schematic_name,
                     body,
-                    game_version_id,
-                    create_version_id,
-                    images,
+                    body_html,
+                    schematic_game_versions_of(schematics.schematic_id) as "game_versions!: Vec<SchematicVersion>",
+                    schematic_create_versions_of(schematics.schematic_id) as "create_versions!: Vec<SchematicVersion>",
+                    schematic_images_of(schematics.schematic_id) as "images!: Vec<SchematicImage>",
                     author,
                     downloads,
+                    status,
                     created_at,
                     updated_at
             "#,
             form.schematic_name,
             form.schematic_body,
-            form.game_version,
-            form.create_version,
-            schematic_id
+            form.schematic_body.as_deref().map(markdown::render),
+            schematic_id,
+            !form.game_versions.is_empty() || !form.create_versions.is_empty()
         )
         .fetch_optional(&mut *transaction)
         .await?
         .ok_or(ApiError::NotFound)?;
 
+        if let Some(body) = &form.schematic_body {
+            mentions::store_schematic_mentions(
+                &mut transaction,
+                schematic_id,
+                user_id,
+                body
+            )
+            .await?;
+        }
+
         transaction.commit().await?;
 
         Ok(Json(schematic))
@@ -307,9 +321,13 @@ impl SchematicsApi {
     /// If tags are included in the query then only schematics with one or more of
     /// the selected tags will be searched for. 
     /// 
+    /// If a game or Create version is included then only schematics which work
+    /// with that version will be searched for
+    /// 
     /// If no limit is specified for the number of schematics to return it will
     /// default to 20
     /// 
+    #[allow(clippy::too_many_arguments)]
     #[oai(path = "/schematics", method = "get")]
     async fn search_schematics(
         &self,
@@ -319,6 +337,8 @@ impl SchematicsApi {
         Query(tag_ids): Query<Option<Vec<i64>>>,
         Query(term): Query<Option<String>>,
         Query(sort): Query<Option<SortBy>>,
+        Query(game_version): Query<Option<i32>>,
+        Query(create_version): Query<Option<i32>>,
     ) -> ApiResult<Json<Vec<FullSchematic>>> {
         let tags = tag_ids.unwrap_or_default();
         let ordering = sort.unwrap_or(SortBy::CreatedAt);
@@ -326,44 +346,41 @@ impl SchematicsApi {
         let schematics = sqlx::query_as!(
             FullSchematic,
             r#"
-            select 
-                schematic_id,
-                schematic_name, 
-                author, 
-                body,
-                avatar as author_avatar,
-                displayname as author_displayname,
-                username as author_username,
-                downloads,
-                images,
-                create_version_id, 
-                create_version_name,
-                game_version_id,
-                game_version_name,
-                schematics.created_at,
-                schematics.updated_at,
-                coalesce(array_agg(tag_id) filter (where tag_id is not null), array []::bigint[]) as "tags!",
-                coalesce(count(likes.schematic_id) filter (where positive = true), 0) as "like_count!",
-                coalesce(count(likes.schematic_id) filter (where positive = false), 0) as "dislike_count!"
-            from 
-                schematics
-                inner join create_versions using (create_version_id)
-                inner join game_versions using (game_version_id)
-                inner join users on user_id = author
-                left join schematic_likes likes using (schematic_id)
-                left join applied_tags using (schematic_id)
-            where 
-                ($1::text is null or schematic_name % $1)
-                and (array_length($2::bigint[], 1) is null or tag_id = any($2))
-            group by 
-                schematic_id,
-                game_version_id,
-                game_version_name,
-                avatar,
-                displayname,
-                username,
-                create_version_id,
-                create_version_name
+            select
+                schematic_id as "schematic_id!",
+                schematic_name as "schematic_name!",
+                body as "body!",
+                body_html,
+                author as "author!",
+                author_avatar,
+                author_displayname as "author_displayname!",
+                author_username as "author_username!",
+                downloads as "downloads!",
+                images as "images!: Vec<SchematicImage>",
+                status as "status!",
+                game_versions as "game_versions!: Vec<SchematicVersion>",
+                create_versions as "create_versions!: Vec<SchematicVersion>",
+                created_at as "created_at!",
+                updated_at,
+                tags as "tags!",
+                like_count as "like_count!",
+                dislike_count as "dislike_count!"
+            from
+                full_schematics
+            where
+                status = 'published'
+                and ($1::text is null or schematic_name % $1)
+                and (array_length($2::bigint[], 1) is null or tags && $2)
+                and ($6::int is null or schematic_id in (
+                    select schematic_id
+                    from schematic_game_versions
+                    where game_version_id = $6
+                ))
+                and ($7::int is null or schematic_id in (
+                    select schematic_id
+                    from schematic_create_versions
+                    where create_version_id = $7
+                ))
             order by $3
             limit $4 offset $5
             "#,
@@ -371,7 +388,9 @@ impl SchematicsApi {
             &tags,
             ordering.to_string(),
             limit.unwrap_or(20),
-            offset.unwrap_or(0)
+            offset.unwrap_or(0),
+            game_version,
+            create_version
         )
         .fetch_all(&ctx.pool)
         .await?;
@@ -381,96 +400,133 @@ impl SchematicsApi {
 
     /// Uploads a new schematic for the current user 
     /// 
+    /// Images and schematic files are processed in the background so the new
+    /// schematic will have the `processing` status until this is complete,
+    /// during which time it will not appear in search results. The id of the
+    /// processing job is included which can be used with `GET /api/v1/jobs/:id`
+    /// to check on its progress. If processing fails, for instance if an image
+    /// cannot be decoded, the schematic will instead have the `failed` status
+    /// 
     /// Schematics must have at least one image and file if not the request will
     /// be rejected with `400 Bad Request`. The file names will be preserved but
     /// will be sanitized
     /// 
     /// If an invalid game version or create version is specfied a `422 Unprocessable
-    /// Entity` error will be returned with a message describing the issue.
+    /// Entity` error will be returned with a message describing the issue. The
+    /// same applies to images which are animated, too large or otherwise not
+    /// accepted, see `POST /api/v1/schematics/:id/images`
     /// 
-    #[oai(path = "/schematics", method = "post")]
+    /// Once processed the Create blocks used are checked against those within
+    /// the selected Create versions, any which do not exist within them are
+    /// given as a warning in the `compatibility` of the job's result along with
+    /// the versions which do contain every block used. This does not prevent
+    /// the schematic from being published
+    /// 
+    /// Tags are also suggested from the blocks used, see `GET /api/v1/tags/rules`,
+    /// any which were not already given are included in the `suggested_tags`
+    /// of the job's result so they can be applied
+    /// 
+    #[oai(path = "/schematics", method = "post", transform = "upload_limit")]
     async fn upload_schematic(
         &self,
         Data(ctx): Data<&ApiContext>,
         Session(user_id): Session,
         form: SchematicBuilder
-    ) -> ApiResult<Json<Schematic>> {
+    ) -> ApiResult<Json<UploadedSchematic>> {
         let mut transaction = ctx.pool.begin().await?;
         let schematic_id = Uuid::new_v4();
         
-        let upload_dir = upload::build_upload_directory(&schematic_id)?;
-        
-        let images = upload::save_images(&upload_dir, form.images).await?;
-        let (files, mods) = upload::save_schematics(&upload_dir, form.files).await?;
+        let pending_dir = upload::build_pending_directory(&schematic_id)?;
 
-        let schematic = sqlx::query_as!(
-            Schematic,
+        let images = upload::stage_images(&pending_dir, form.images).await?;
+        let files = upload::stage_schematics(&pending_dir, form.files).await?;
+
+        sqlx::query!(
             r#"
             insert into schematics (
                 schematic_id, schematic_name, 
-                body, author, images, files,
-                game_version_id, create_version_id
+                body, body_html, author,
+                status
             )
             values (
-                $1, $2, $3, $4, $5, $6, $7, $8
+                $1, $2, $3, $4, $5, 'processing'
             )
-            returning
-                schematic_id,
-                schematic_name,
-                body,
-                game_version_id,
-                create_version_id,
-                images,
-                author,
-                downloads,
-                created_at,
-                updated_at
             "#,
             schematic_id,
             form.schematic_name,
             form.schematic_body,
-            user_id,
-            &images[..],
-            &files[..],
-            form.game_version,
-            form.create_version
+            markdown::render(&form.schematic_body),
+            user_id
         )
-        .fetch_one(&mut *transaction)
-        .await
-        .on_constraint("schematics_game_version_id_fkey", |_| {
-            ApiError::unprocessable_entity([("game_version", "that version does not exist")])
-        })?;
+        .execute(&mut *transaction)
+        .await?;
 
-        let mods: Vec<String> = mods.into_iter().collect();
+        set_game_versions(&mut transaction, schematic_id, &form.game_versions).await?;
+        set_create_versions(&mut transaction, schematic_id, &form.create_versions).await?;
 
+        // The first image uploaded is used as the cover, this can be changed
+        // later through `PATCH /api/v1/schematics/:id/images/:file_name`
         sqlx::query!(
             r#"
-            insert into mods (
-                mod_slug
+            insert into schematic_images (
+                schematic_id, file_name, position, is_cover
             )
-            select mod_slug 
-            from unnest($1::text[]) as mod_slug
+            select 
+                $1, file_name, (position - 1)::integer, position = 1
+            from 
+                unnest($2::text[]) with ordinality as image(file_name, position)
             "#,
-            &mods[..]
+            schematic_id,
+            &images[..]
         )
         .execute(&mut *transaction)
-        .await?;
+        .await
+        .on_constraint("schematic_images_pkey", |_| {
+            ApiError::unprocessable_entity([("images", "image names must be unique")])
+        })?;
 
         sqlx::query!(
             r#"
-            insert into mod_dependencies (
-                schematic_id, mod_id
+            insert into schematic_files (
+                schematic_id, file_name, position
             )
-            select $1, mod_id
-            from unnest($2::text[]) as mod_slug
-            inner join mods using (mod_slug)
+            select 
+                $1, file_name, (position - 1)::integer
+            from 
+                unnest($2::text[]) with ordinality as file(file_name, position)
             "#,
-            schematic.schematic_id,
-            &mods[..]
+            schematic_id,
+            &files[..]
         )
         .execute(&mut *transaction)
         .await?;
 
+        let schematic = sqlx::query_as!(
+            Schematic,
+            r#"
+            select
+                schematic_id,
+                schematic_name,
+                body,
+                body_html,
+                schematic_game_versions_of(schematics.schematic_id) as "game_versions!: Vec<SchematicVersion>",
+                schematic_create_versions_of(schematics.schematic_id) as "create_versions!: Vec<SchematicVersion>",
+                schematic_images_of(schematics.schematic_id) as "images!: Vec<SchematicImage>",
+                author,
+                downloads,
+                status,
+                created_at,
+                updated_at
+            from
+                schematics
+            where
+                schematic_id = $1
+            "#,
+            schematic_id
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+
         sqlx::query!(
             // Unfortunately sqlx does not inserting multiple records 
             // directly without using a query builder which would mean 
@@ -494,10 +550,142 @@ impl SchematicsApi {
         )
         .execute(&mut *transaction)
         .await?;
+
+        mentions::store_schematic_mentions(
+            &mut transaction,
+            schematic.schematic_id,
+            user_id,
+            &form.schematic_body
+        )
+        .await?;
+
+        let directory = upload::pending_directory_name(&pending_dir)?;
+
+        let job_id = Job::ProcessUpload { schematic_id, directory, upload: UploadKind::Schematic }
+            .enqueue(&mut *transaction, Some(user_id))
+            .await?;
     
         transaction.commit().await?;
-        let _persist = upload_dir.into_path();
+        let _persist = pending_dir.into_path();
 
-        Ok(Json(schematic))
+        Ok(Json(UploadedSchematic { schematic, job_id }))
+    }
+}
+
+/// Replaces the game versions a schematic works with
+async fn set_game_versions(
+    conn: &mut PgConnection,
+    schematic_id: Uuid,
+    game_versions: &[i32]
+) -> ApiResult<()> {
+    let deprecated = sqlx::query_scalar!(
+        r#"
+        select exists (
+            select 1
+            from game_versions
+            where game_version_id = any($2)
+            and deprecated
+            and not exists (
+                select 1
+                from schematic_game_versions
+                where schematic_game_versions.schematic_id = $1
+                and schematic_game_versions.game_version_id = game_versions.game_version_id
+            )
+        ) as "deprecated!"
+        "#,
+        schematic_id,
+        game_versions
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    if deprecated {
+        return Err(ApiError::unprocessable_entity([("game_versions", "that version is deprecated")]));
+    }
+
+    sqlx::query!(
+        r#"delete from schematic_game_versions where schematic_id = $1"#,
+        schematic_id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        insert into schematic_game_versions (
+            schematic_id, game_version_id
+        )
+        select $1, game_version_id
+        from unnest($2::int[]) as game_version_id
+        on conflict do nothing
+        "#,
+        schematic_id,
+        game_versions
+    )
+    .execute(&mut *conn)
+    .await
+    .on_constraint("schematic_game_versions_game_version_id_fkey", |_| {
+        ApiError::unprocessable_entity([("game_versions", "that version does not exist")])
+    })?;
+
+    Ok(())
+}
+
+/// Replaces the Create versions a schematic works with
+async fn set_create_versions(
+    conn: &mut PgConnection,
+    schematic_id: Uuid,
+    create_versions: &[i32]
+) -> ApiResult<()> {
+    let deprecated = sqlx::query_scalar!(
+        r#"
+        select exists (
+            select 1
+            from create_versions
+            where create_version_id = any($2)
+            and deprecated
+            and not exists (
+                select 1
+                from schematic_create_versions
+                where schematic_create_versions.schematic_id = $1
+                and schematic_create_versions.create_version_id = create_versions.create_version_id
+            )
+        ) as "deprecated!"
+        "#,
+        schematic_id,
+        create_versions
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    if deprecated {
+        return Err(ApiError::unprocessable_entity([("create_versions", "that version is deprecated")]));
     }
-}
\ No newline at end of file
+
+    sqlx::query!(
+        r#"delete from schematic_create_versions where schematic_id = $1"#,
+        schematic_id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        insert into schematic_create_versions (
+            schematic_id, create_version_id
+        )
+        select $1, create_version_id
+        from unnest($2::int[]) as create_version_id
+        on conflict do nothing
+        "#,
+        schematic_id,
+        create_versions
+    )
+    .execute(&mut *conn)
+    .await
+    .on_constraint("schematic_create_versions_create_version_id_fkey", |_| {
+        ApiError::unprocessable_entity([("create_versions", "that version does not exist")])
+    })?;
+
+    Ok(())
+}
@@ -1,18 +1,35 @@
+use core::fmt;
+use std::future::Future;
+
 use poem::web::Data;
 use poem_openapi::OpenApi;
 use poem_openapi::param::{Query, Path};
 use poem_openapi::payload::Json;
-use poem_openapi_derive::Object;
+use poem_openapi_derive::{Enum, Multipart, Object};
+use sqlx::{PgConnection, PgPool};
+use time::OffsetDateTime;
 use uuid::Uuid;
 
 use crate::authentication::schemes::Session;
-use crate::error::ApiError;
+use crate::error::{ApiError, ResultExt};
+use crate::middleware::validators::Profanity;
 use crate::response::ApiResult;
+use crate::storage::blocks::{self, SuggestedTag};
 use crate::api::ApiContext;
 
 
 pub (in crate::api::v1) struct TagsApi;
 
+pub const TAG_STATS_NAMESPACE: &str = "tag_stats";
+
+/// The key within `TAG_STATS_NAMESPACE` holding the current generation of
+/// cached statistics, see `cached_tag_stats`
+const TAG_STATS_GENERATION: &str = "generation";
+
+/// How long statistics about tags, such as how often they are used, are
+/// cached for in seconds
+const TAG_STATS_EXPIRY: u64 = 15 * 60;
+
 #[derive(Debug, Deserialize, Object)]
 pub (in crate::api::v1) struct Tags {
     pub tag_names: Vec<String>,
@@ -23,10 +40,164 @@ pub (in crate::api::v1) struct Tag {
     pub tag_name: String
 }
 
-#[derive(Debug, Serialize, Object)]
+#[derive(Debug, Serialize, Deserialize, Object)]
 pub (in crate::api::v1) struct FullTag {
     pub tag_id: i64,
     pub tag_name: String,
+    pub description: Option<String>,
+    pub category_id: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Object)]
+pub (in crate::api::v1) struct TagListing {
+    #[oai(flatten)]
+    #[serde(flatten)]
+    pub details: FullTag,
+    /// The number of published schematics with this tag
+    pub usage_count: i64
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub (in crate::api::v1) struct RelatedTag {
+    #[oai(flatten)]
+    #[serde(flatten)]
+    pub details: FullTag,
+    /// The number of published schematics with both this tag and the given tag
+    pub shared_count: i64
+}
+
+#[derive(Debug, Serialize, Deserialize, Object)]
+pub (in crate::api::v1) struct TrendingTag {
+    #[oai(flatten)]
+    #[serde(flatten)]
+    pub details: FullTag,
+    /// The number of published schematics given this tag within the window
+    pub recent_count: i64
+}
+
+#[derive(Debug, Serialize, Object)]
+pub (in crate::api::v1) struct TagSuggestion {
+    #[oai(flatten)]
+    #[serde(flatten)]
+    pub details: FullTag,
+    /// The blocks within the schematic which caused this tag to be suggested
+    pub matched_blocks: Vec<String>
+}
+
+impl From<SuggestedTag> for TagSuggestion {
+    fn from(suggested: SuggestedTag) -> Self {
+        TagSuggestion {
+            details: FullTag {
+                tag_id: suggested.tag_id,
+                tag_name: suggested.tag_name,
+                description: suggested.description,
+                category_id: suggested.category_id
+            },
+            matched_blocks: suggested.matched_blocks
+        }
+    }
+}
+
+/// A rule used to suggest a tag for schematics which place at least `min_count`
+/// of a block across all of their files
+#[derive(Debug, Serialize, Object)]
+pub (in crate::api::v1) struct TagRule {
+    pub rule_id: i64,
+    pub tag_id: i64,
+    pub block_id: String,
+    pub min_count: i32,
+    pub created_at: OffsetDateTime
+}
+
+#[derive(Debug, Multipart)]
+pub (in crate::api::v1) struct TagRuleBuilder {
+    /// The full id of the block including its namespace, such as `create:track`
+    #[oai(validator(pattern=r"^[a-z0-9_.-]+:[a-z0-9_./-]+$", max_length=128))]
+    pub block_id: String,
+    /// Defaults to suggesting the tag whenever the block is used
+    #[oai(validator(minimum(value="1")))]
+    pub min_count: Option<i32>
+}
+
+#[derive(Enum, Deserialize, Debug)]
+#[serde(rename_all="snake_case")]
+#[oai(rename_all="snake_case")]
+pub enum TagSortBy {
+    /// Fetch tags in alphabetical order
+    /// 
+    Name,
+
+    /// Fetch the tags applied to the most published schematics first
+    /// 
+    Popularity
+}
+
+impl fmt::Display for TagSortBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TagSortBy::Name => write!(f, "name"),
+            TagSortBy::Popularity => write!(f, "popularity")
+        }
+    }
+}
+
+#[derive(Debug, Multipart)]
+pub (in crate::api::v1) struct TagBuilder {
+    #[oai(validator(min_length=1, max_length=32))]
+    pub tag_name: String,
+    #[oai(validator(max_length=256))]
+    pub description: Option<String>,
+    pub category_id: Option<i64>
+}
+
+#[derive(Debug, Multipart)]
+pub (in crate::api::v1) struct UpdateTag {
+    #[oai(validator(min_length=1, max_length=32))]
+    pub tag_name: Option<String>,
+    /// Setting this to an empty string will remove the description
+    #[oai(validator(max_length=256))]
+    pub description: Option<String>,
+    pub category_id: Option<i64>,
+    /// Setting this to `true` will remove the tag from its category, this
+    /// cannot be given alongside `category_id`
+    pub remove_category: Option<bool>
+}
+
+#[derive(Debug, Multipart)]
+pub (in crate::api::v1) struct MergeTag {
+    /// The tag which will replace the merged tag
+    pub target_tag_id: i64
+}
+
+#[derive(Debug, Serialize, Object)]
+pub (in crate::api::v1) struct TagCategory {
+    pub category_id: i64,
+    pub category_name: String
+}
+
+#[derive(Debug, Multipart)]
+pub (in crate::api::v1) struct TagCategoryBuilder {
+    #[oai(validator(min_length=1, max_length=32))]
+    pub category_name: String
+}
+
+#[derive(Debug, Serialize, Object)]
+pub (in crate::api::v1) struct TagProposal {
+    pub proposal_id: Uuid,
+    pub user_id: Uuid,
+    pub tag_name: String,
+    pub description: Option<String>,
+    pub category_id: Option<i64>,
+    pub created_at: OffsetDateTime
+}
+
+#[derive(Debug, Multipart)]
+pub (in crate::api::v1) struct TagProposalBuilder {
+    #[oai(validator(min_length=1, max_length=32, custom="Profanity"))]
+    pub tag_name: String,
+    #[oai(validator(max_length=256, custom="Profanity"))]
+    pub description: Option<String>,
+    pub category_id: Option<i64>
 }
 
 #[OpenApi(prefix_path="/v1")]
@@ -45,7 +216,7 @@ impl TagsApi {
         let tags = sqlx::query_as!(
             FullTag,
             r#"
-            select tag_id, tag_name
+            select tag_id, tag_name, description, category_id
             from applied_tags
             inner join tags using (tag_id)
             where schematic_id = $1 
@@ -59,34 +230,167 @@ impl TagsApi {
     }
 
     /// Fetch a number of the valid tags available within the api aswell as their
-    /// given names. If no limit is specified 20 will be returned by default.
+    /// given names and the number of published schematics using them. If no
+    /// limit is specified 20 will be returned by default, sorted by name unless
+    /// otherwise specified. Tags can be filtered to a single category, see
+    /// `GET /api/v1/tags/categories`
     /// 
     /// If you are looking to get all of the tags on a specific schematic see
     /// `GET /api/v1/schematics/{id}/tags`
     /// 
+    /// Usage counts are cached for 15 minutes so recently applied tags may not
+    /// be counted immediately
+    /// 
     #[oai(path = "/tags", method = "get")]
     async fn get_valid_tags(
         &self,
         Data(ctx): Data<&ApiContext>,
+        Query(category_id): Query<Option<i64>>,
+        Query(sort): Query<Option<TagSortBy>>,
         Query(limit): Query<Option<i64>>,
         Query(offset): Query<Option<i64>>
-    ) -> ApiResult<Json<Vec<FullTag>>> {
-        let tags = sqlx::query_as!(
-            FullTag,
+    ) -> ApiResult<Json<Vec<TagListing>>> {
+        let ordering = sort.unwrap_or(TagSortBy::Name);
+
+        let usage = cached_tag_stats(ctx, "usage", || fetch_tag_usage(&ctx.pool)).await?;
+        let (tag_ids, usage_counts): (Vec<i64>, Vec<i64>) = usage.into_iter().unzip();
+
+        let tags = sqlx::query!(
             r#"
-            select tag_id, tag_name
-            from tags
-            limit $1 offset $2
+            select
+                tag_id, tag_name, description, category_id,
+                coalesce(counts.usage_count, 0) as "usage_count!"
+            from
+                tags
+                left join unnest($5::bigint[], $6::bigint[])
+                    as counts(tag_id, usage_count) using (tag_id)
+            where
+                $1::bigint is null or category_id = $1
+            order by
+                case when $2 = 'popularity' then coalesce(counts.usage_count, 0) end desc,
+                tag_name
+            limit $3 offset $4
             "#,
+            category_id,
+            ordering.to_string(),
             limit.unwrap_or(20),
-            offset.unwrap_or(0)
+            offset.unwrap_or(0),
+            &tag_ids[..],
+            &usage_counts[..]
         )
         .fetch_all(&ctx.pool)
-        .await?;
+        .await?
+        .into_iter()
+        .map(|row| TagListing {
+            details: FullTag {
+                tag_id: row.tag_id,
+                tag_name: row.tag_name,
+                description: row.description,
+                category_id: row.category_id
+            },
+            usage_count: row.usage_count
+        })
+        .collect();
+
+        Ok(Json(tags))
+    }
+
+    /// Fetches the tags most often applied alongside a given tag on published
+    /// schematics, most shared first. If no limit is specified 10 will be
+    /// returned by default
+    /// 
+    /// These are cached for 15 minutes so recently applied tags may not be
+    /// counted immediately
+    /// 
+    #[oai(path = "/tags/:tag_id/related", method = "get")]
+    async fn get_related_tags(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(tag_id): Path<i64>,
+        #[oai(validator(maximum(value="50")))] Query(limit): Query<Option<i64>>
+    ) -> ApiResult<Json<Vec<RelatedTag>>> {
+        let limit = limit.unwrap_or(10).max(0) as usize;
+
+        sqlx::query!(
+            r#"select tag_id from tags where tag_id = $1"#,
+            tag_id
+        )
+        .fetch_optional(&ctx.pool)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        // The top 50 related tags are always cached so every limit can be
+        // served from the same entry
+        let key = format!("related:{tag_id}");
+        let mut tags = cached_tag_stats(ctx, &key, || fetch_related_tags(&ctx.pool, tag_id)).await?;
+
+        tags.truncate(limit);
 
         Ok(Json(tags))
     }
 
+    /// Fetches the tags given to the most published schematics within the last
+    /// number of days, 7 by default. If no limit is specified 10 will be
+    /// returned by default
+    /// 
+    /// These are cached for 15 minutes so recently applied tags may not be
+    /// counted immediately
+    /// 
+    #[oai(path = "/tags/trending", method = "get")]
+    async fn get_trending_tags(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        #[oai(validator(minimum(value="1"), maximum(value="30")))] Query(days): Query<Option<i32>>,
+        #[oai(validator(maximum(value="50")))] Query(limit): Query<Option<i64>>
+    ) -> ApiResult<Json<Vec<TrendingTag>>> {
+        let days = days.unwrap_or(7);
+        let limit = limit.unwrap_or(10).max(0) as usize;
+
+        // The top 50 tags are always cached so every limit can be served from
+        // the same entry
+        let key = format!("trending:{days}");
+        let mut tags = cached_tag_stats(ctx, &key, || fetch_trending_tags(&ctx.pool, days)).await?;
+
+        tags.truncate(limit);
+
+        Ok(Json(tags))
+    }
+
+    /// Suggests tags for a schematic from the blocks placed within its files,
+    /// see `GET /api/v1/tags/rules`. Tags already applied to the schematic are
+    /// not suggested
+    /// 
+    /// Files are read once they have been processed so suggestions will be
+    /// empty until then, the same suggestions are included in the result of
+    /// the job processing a newly uploaded schematic, see `GET /api/v1/jobs/{id}`
+    /// 
+    #[oai(path = "/schematics/:schematic_id/tag-suggestions", method = "get")]
+    async fn get_tag_suggestions(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(schematic_id): Path<Uuid>
+    ) -> ApiResult<Json<Vec<TagSuggestion>>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        sqlx::query!(
+            r#"select schematic_id from schematics where schematic_id = $1"#,
+            schematic_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        let block_counts = blocks::schematic_block_counts(&mut transaction, schematic_id).await?;
+
+        let suggestions = blocks::suggest_tags(&mut transaction, schematic_id, &block_counts)
+            .await?
+            .into_iter()
+            .map(TagSuggestion::from)
+            .collect();
+
+        Ok(Json(suggestions))
+    }
+
     /// Applies tags to a given schematic given their identifiers see
     /// `GET /api/v1/tags` for a full list of valid tags
     /// 
@@ -175,4 +479,899 @@ impl TagsApi {
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Creates a new tag, this requires for the current user to be an
+    /// administrator
+    /// 
+    /// Tag names must be unique, if the name is already used a `422
+    /// Unprocessable Entity` error will be returned
+    /// 
+    #[oai(path = "/tags", method = "post")]
+    async fn create_tag(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        form: TagBuilder,
+        session: Session
+    ) -> ApiResult<Json<FullTag>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_administrator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        let tag = sqlx::query_as!(
+            FullTag,
+            r#"
+            insert into tags (
+                tag_name, description, category_id
+            )
+            values (
+                $1, $2, $3
+            )
+            returning tag_id, tag_name, description, category_id
+            "#,
+            form.tag_name,
+            form.description,
+            form.category_id
+        )
+        .fetch_one(&mut *transaction)
+        .await
+        .on_constraint("tags_tag_name_key", |_| {
+            ApiError::unprocessable_entity([("tag_name", "a tag with this name already exists")])
+        })
+        .on_constraint("tags_category_id_fkey", |_| {
+            ApiError::unprocessable_entity([("category_id", "that category does not exist")])
+        })?;
+
+        transaction.commit().await?;
+
+        Ok(Json(tag))
+    }
+
+    /// Renames a tag or changes its description or category, all fields are
+    /// optional. This requires for the current user to be an administrator
+    /// 
+    /// If the new name is already used, or the category does not exist, a `422
+    /// Unprocessable Entity` error will be returned
+    /// 
+    #[oai(path = "/tags/:tag_id", method = "patch")]
+    async fn update_tag(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(tag_id): Path<i64>,
+        form: UpdateTag,
+        session: Session
+    ) -> ApiResult<Json<FullTag>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_administrator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        let remove_category = form.remove_category.unwrap_or(false);
+
+        if remove_category && form.category_id.is_some() {
+            return Err(ApiError::unprocessable_entity([("remove_category", "cannot be given alongside a category")]));
+        }
+
+        let tag = sqlx::query_as!(
+            FullTag,
+            r#"
+            update tags
+                set
+                    tag_name = coalesce($1, tag_name),
+                    description = case when $2::text is null then description else nullif($2, '') end,
+                    category_id = case when $4 then null else coalesce($3, category_id) end
+                where tag_id = $5
+                returning tag_id, tag_name, description, category_id
+            "#,
+            form.tag_name,
+            form.description,
+            form.category_id,
+            remove_category,
+            tag_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .on_constraint("tags_tag_name_key", |_| {
+            ApiError::unprocessable_entity([("tag_name", "a tag with this name already exists")])
+        })
+        .on_constraint("tags_category_id_fkey", |_| {
+            ApiError::unprocessable_entity([("category_id", "that category does not exist")])
+        })?
+        .ok_or(ApiError::NotFound)?;
+
+        transaction.commit().await?;
+        invalidate_tag_stats(ctx).await;
+
+        Ok(Json(tag))
+    }
+
+    /// Deletes a tag, removing it from every schematic and user following it.
+    /// This requires for the current user to be an administrator
+    /// 
+    #[oai(path = "/tags/:tag_id", method = "delete")]
+    async fn delete_tag(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(tag_id): Path<i64>,
+        session: Session
+    ) -> ApiResult<()> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_administrator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        sqlx::query!(
+            r#"delete from tags where tag_id = $1 returning tag_id"#,
+            tag_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        transaction.commit().await?;
+        invalidate_tag_stats(ctx).await;
+
+        Ok(())
+    }
+
+    /// Merges a tag into another, every schematic, follower and rule of the
+    /// merged tag is moved to the target tag before the merged tag is deleted.
+    /// This requires for the current user to be an administrator
+    /// 
+    #[oai(path = "/tags/:tag_id/merge", method = "post")]
+    async fn merge_tag(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(tag_id): Path<i64>,
+        form: MergeTag,
+        session: Session
+    ) -> ApiResult<Json<FullTag>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_administrator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        if tag_id == form.target_tag_id {
+            return Err(ApiError::unprocessable_entity([("target_tag_id", "a tag cannot be merged into itself")]));
+        }
+
+        sqlx::query!(
+            r#"select tag_id from tags where tag_id = $1 for update"#,
+            tag_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        let target = sqlx::query_as!(
+            FullTag,
+            r#"
+            select tag_id, tag_name, description, category_id
+            from tags
+            where tag_id = $1
+            for update
+            "#,
+            form.target_tag_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or_else(|| ApiError::unprocessable_entity([("target_tag_id", "that tag does not exist")]))?;
+
+        merge_tags(&mut transaction, tag_id, target.tag_id).await?;
+
+        transaction.commit().await?;
+        invalidate_tag_stats(ctx).await;
+
+        Ok(Json(target))
+    }
+
+    /// Fetches every category tags can be placed within
+    /// 
+    #[oai(path = "/tags/categories", method = "get")]
+    async fn get_tag_categories(
+        &self,
+        Data(ctx): Data<&ApiContext>
+    ) -> ApiResult<Json<Vec<TagCategory>>> {
+        let categories = sqlx::query_as!(
+            TagCategory,
+            r#"
+            select category_id, category_name
+            from tag_categories
+            order by category_name
+            "#
+        )
+        .fetch_all(&ctx.pool)
+        .await?;
+
+        Ok(Json(categories))
+    }
+
+    /// Creates a new tag category, this requires for the current user to be an
+    /// administrator
+    /// 
+    #[oai(path = "/tags/categories", method = "post")]
+    async fn create_tag_category(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        form: TagCategoryBuilder,
+        session: Session
+    ) -> ApiResult<Json<TagCategory>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_administrator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        let category = sqlx::query_as!(
+            TagCategory,
+            r#"
+            insert into tag_categories (category_name)
+            values ($1)
+            returning category_id, category_name
+            "#,
+            form.category_name
+        )
+        .fetch_one(&mut *transaction)
+        .await
+        .on_constraint("tag_categories_category_name_key", |_| {
+            ApiError::unprocessable_entity([("category_name", "a category with this name already exists")])
+        })?;
+
+        transaction.commit().await?;
+
+        Ok(Json(category))
+    }
+
+    /// Renames a tag category, this requires for the current user to be an
+    /// administrator
+    /// 
+    #[oai(path = "/tags/categories/:category_id", method = "patch")]
+    async fn update_tag_category(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(category_id): Path<i64>,
+        form: TagCategoryBuilder,
+        session: Session
+    ) -> ApiResult<Json<TagCategory>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_administrator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        let category = sqlx::query_as!(
+            TagCategory,
+            r#"
+            update tag_categories
+                set category_name = $1
+                where category_id = $2
+                returning category_id, category_name
+            "#,
+            form.category_name,
+            category_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .on_constraint("tag_categories_category_name_key", |_| {
+            ApiError::unprocessable_entity([("category_name", "a category with this name already exists")])
+        })?
+        .ok_or(ApiError::NotFound)?;
+
+        transaction.commit().await?;
+
+        Ok(Json(category))
+    }
+
+    /// Deletes a tag category, any tags within it are kept without a category.
+    /// This requires for the current user to be an administrator
+    /// 
+    #[oai(path = "/tags/categories/:category_id", method = "delete")]
+    async fn delete_tag_category(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(category_id): Path<i64>,
+        session: Session
+    ) -> ApiResult<()> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_administrator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        sqlx::query!(
+            r#"delete from tag_categories where category_id = $1 returning category_id"#,
+            category_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        transaction.commit().await?;
+        invalidate_tag_stats(ctx).await;
+
+        Ok(())
+    }
+
+    /// Proposes a new tag, this will be created once approved by an
+    /// administrator see `PUT /api/v1/tags/proposals/{id}/approve`
+    /// 
+    /// If a tag or proposal with the same name already exists a `422
+    /// Unprocessable Entity` error will be returned
+    /// 
+    #[oai(path = "/tags/proposals", method = "post")]
+    async fn propose_tag(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Session(user_id): Session,
+        form: TagProposalBuilder
+    ) -> ApiResult<Json<TagProposal>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        let proposal = sqlx::query_as!(
+            TagProposal,
+            r#"
+            insert into tag_proposals (
+                user_id, tag_name, description, category_id
+            )
+            select $1, $2, $3, $4
+            where not exists (
+                select 1 from tags where tag_name = $2
+            )
+            returning
+                proposal_id, user_id, tag_name,
+                description, category_id, created_at
+            "#,
+            user_id,
+            form.tag_name,
+            form.description,
+            form.category_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .on_constraint("tag_proposals_tag_name_key", |_| {
+            ApiError::unprocessable_entity([("tag_name", "this tag has already been proposed")])
+        })
+        .on_constraint("tag_proposals_category_id_fkey", |_| {
+            ApiError::unprocessable_entity([("category_id", "that category does not exist")])
+        })?
+        .ok_or_else(|| ApiError::unprocessable_entity([("tag_name", "a tag with this name already exists")]))?;
+
+        transaction.commit().await?;
+
+        Ok(Json(proposal))
+    }
+
+    /// Fetches the tags proposed by users, oldest first. This requires for the
+    /// current user to be an administrator
+    /// 
+    #[oai(path = "/tags/proposals", method = "get")]
+    async fn fetch_tag_proposals(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        #[oai(validator(maximum(value="50")))] Query(limit): Query<Option<i64>>,
+        Query(offset): Query<Option<i64>>,
+        session: Session
+    ) -> ApiResult<Json<Vec<TagProposal>>> {
+        if !session.is_administrator(&ctx.pool).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        let proposals = sqlx::query_as!(
+            TagProposal,
+            r#"
+            select
+                proposal_id, user_id, tag_name,
+                description, category_id, created_at
+            from
+                tag_proposals
+            order by
+                created_at
+            limit $1 offset $2
+            "#,
+            limit.unwrap_or(20),
+            offset.unwrap_or(0)
+        )
+        .fetch_all(&ctx.pool)
+        .await?;
+
+        Ok(Json(proposals))
+    }
+
+    /// Fetches a single tag proposal, this requires for the current user to be
+    /// an administrator
+    /// 
+    /// If the proposal does not exist, or has already been approved or rejected,
+    /// a `404 Not Found` error will be returned
+    /// 
+    #[oai(path = "/tags/proposals/:proposal_id", method = "get")]
+    async fn fetch_tag_proposal_by_id(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(proposal_id): Path<Uuid>,
+        session: Session
+    ) -> ApiResult<Json<TagProposal>> {
+        if !session.is_administrator(&ctx.pool).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        sqlx::query_as!(
+            TagProposal,
+            r#"
+            select
+                proposal_id, user_id, tag_name,
+                description, category_id, created_at
+            from
+                tag_proposals
+            where
+                proposal_id = $1
+            "#,
+            proposal_id
+        )
+        .fetch_optional(&ctx.pool)
+        .await?
+        .ok_or(ApiError::NotFound)
+        .map(Json)
+    }
+
+    /// Creates the tag from a proposal and removes the proposal, this requires
+    /// for the current user to be an administrator
+    /// 
+    #[oai(path = "/tags/proposals/:proposal_id/approve", method = "put")]
+    async fn approve_tag_proposal(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(proposal_id): Path<Uuid>,
+        session: Session
+    ) -> ApiResult<Json<FullTag>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_administrator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        let tag = sqlx::query_as!(
+            FullTag,
+            r#"
+            with proposal as (
+                delete from tag_proposals
+                where proposal_id = $1
+                returning tag_name, description, category_id
+            )
+            insert into tags (
+                tag_name, description, category_id
+            )
+            select tag_name, description, category_id
+            from proposal
+            returning tag_id, tag_name, description, category_id
+            "#,
+            proposal_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .on_constraint("tags_tag_name_key", |_| {
+            ApiError::unprocessable_entity([("tag_name", "a tag with this name already exists")])
+        })
+        .on_constraint("tags_category_id_fkey", |_| {
+            ApiError::unprocessable_entity([("category_id", "that category does not exist")])
+        })?
+        .ok_or(ApiError::NotFound)?;
+
+        transaction.commit().await?;
+
+        Ok(Json(tag))
+    }
+
+    /// Removes a proposal without creating its tag, this requires for the current
+    /// user to be an administrator
+    /// 
+    /// If the proposal does not exist, or has already been approved or rejected,
+    /// a `404 Not Found` error will be returned
+    /// 
+    #[oai(path = "/tags/proposals/:proposal_id/reject", method = "put")]
+    async fn reject_tag_proposal(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(proposal_id): Path<Uuid>,
+        session: Session
+    ) -> ApiResult<()> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_administrator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        sqlx::query!(
+            r#"
+            delete from tag_proposals
+            where proposal_id = $1
+            returning proposal_id
+            "#,
+            proposal_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Fetches every rule used to suggest tags from the blocks within a
+    /// schematic, see `GET /api/v1/schematics/{id}/tag-suggestions`
+    /// 
+    #[oai(path = "/tags/rules", method = "get")]
+    async fn get_tag_rules(
+        &self,
+        Data(ctx): Data<&ApiContext>
+    ) -> ApiResult<Json<Vec<TagRule>>> {
+        let rules = sqlx::query_as!(
+            TagRule,
+            r#"
+            select rule_id, tag_id, block_id, min_count, created_at
+            from tag_rules
+            order by tag_id, block_id
+            "#
+        )
+        .fetch_all(&ctx.pool)
+        .await?;
+
+        Ok(Json(rules))
+    }
+
+    /// Adds a rule which suggests a tag for schematics using a block, this
+    /// requires for the current user to be an administrator
+    /// 
+    /// Each block can only have one rule per tag, if a rule already exists a
+    /// `422 Unprocessable Entity` error will be returned
+    /// 
+    #[oai(path = "/tags/:tag_id/rules", method = "post")]
+    async fn create_tag_rule(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(tag_id): Path<i64>,
+        form: TagRuleBuilder,
+        session: Session
+    ) -> ApiResult<Json<TagRule>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_administrator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        let rule = sqlx::query_as!(
+            TagRule,
+            r#"
+            insert into tag_rules (
+                tag_id, block_id, min_count
+            )
+            values (
+                $1, $2, coalesce($3, 1)
+            )
+            returning rule_id, tag_id, block_id, min_count, created_at
+            "#,
+            tag_id,
+            form.block_id,
+            form.min_count
+        )
+        .fetch_one(&mut *transaction)
+        .await
+        .on_constraint("tag_rules_tag_id_fkey", |_| ApiError::NotFound)
+        .on_constraint("tag_rules_tag_id_block_id_key", |_| {
+            ApiError::unprocessable_entity([("block_id", "this tag already has a rule for this block")])
+        })?;
+
+        transaction.commit().await?;
+
+        Ok(Json(rule))
+    }
+
+    /// Removes a rule used to suggest tags, this requires for the current user
+    /// to be an administrator
+    /// 
+    #[oai(path = "/tags/rules/:rule_id", method = "delete")]
+    async fn delete_tag_rule(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(rule_id): Path<i64>,
+        session: Session
+    ) -> ApiResult<()> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_administrator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        sqlx::query!(
+            r#"delete from tag_rules where rule_id = $1 returning rule_id"#,
+            rule_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+}
+
+/// Fetches a statistic about tags from the cache, otherwise computing it with
+/// `fetch` and caching it for 15 minutes. Entries are stored under the current
+/// generation so that every entry can be invalidated at once, see
+/// `invalidate_tag_stats`
+/// 
+/// Redis errors are logged when they are converted, if Redis cannot be reached
+/// the statistic is computed from Postgres instead
+async fn cached_tag_stats<T, F>(ctx: &ApiContext, key: &str, fetch: impl FnOnce() -> F) -> ApiResult<T>
+where
+    T: serde::Serialize + for<'a> serde::Deserialize<'a>,
+    F: Future<Output = ApiResult<T>>
+{
+    let Ok(generation) = ctx.redis_pool.get::<i64, _>(TAG_STATS_NAMESPACE, TAG_STATS_GENERATION).await else {
+        return fetch().await;
+    };
+
+    let key = format!("{}:{key}", generation.unwrap_or(0));
+
+    if let Ok(Some(cached)) = ctx.redis_pool.get_json::<T, _>(TAG_STATS_NAMESPACE, &key).await {
+        return Ok(cached);
+    }
+
+    let stats = fetch().await?;
+
+    ctx.redis_pool
+        .set_json(TAG_STATS_NAMESPACE, &key, &stats, Some(TAG_STATS_EXPIRY))
+        .await
+        .ok();
+
+    Ok(stats)
+}
+
+/// Invalidates every cached statistic about tags, this should be called once
+/// tags have been renamed, merged or deleted. If Redis cannot be reached the
+/// cached statistics are left to expire
+async fn invalidate_tag_stats(ctx: &ApiContext) {
+    ctx.redis_pool
+        .increment(TAG_STATS_NAMESPACE, TAG_STATS_GENERATION)
+        .await
+        .ok();
+}
+
+/// Counts the published schematics given each tag, tags which have not been
+/// given to any are left out
+async fn fetch_tag_usage(pool: &PgPool) -> ApiResult<Vec<(i64, i64)>> {
+    let usage = sqlx::query!(
+        r#"
+        select tag_id, count(*) as "usage_count!"
+        from applied_tags
+        inner join schematics using (schematic_id)
+        where schematics.status = 'published'
+        group by tag_id
+        "#
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| (row.tag_id, row.usage_count))
+    .collect();
+
+    Ok(usage)
+}
+
+/// Fetches the 50 tags most often applied alongside a given tag on published
+/// schematics
+async fn fetch_related_tags(pool: &PgPool, tag_id: i64) -> ApiResult<Vec<RelatedTag>> {
+    let tags = sqlx::query!(
+        r#"
+        select
+            tags.tag_id, tags.tag_name,
+            tags.description, tags.category_id,
+            count(*) as "shared_count!"
+        from
+            applied_tags given
+            inner join schematics using (schematic_id)
+            inner join applied_tags related using (schematic_id)
+            inner join tags on tags.tag_id = related.tag_id
+        where
+            given.tag_id = $1
+            and related.tag_id <> $1
+            and schematics.status = 'published'
+        group by
+            tags.tag_id
+        order by
+            count(*) desc, tags.tag_name
+        limit 50
+        "#,
+        tag_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| RelatedTag {
+        details: FullTag {
+            tag_id: row.tag_id,
+            tag_name: row.tag_name,
+            description: row.description,
+            category_id: row.category_id
+        },
+        shared_count: row.shared_count
+    })
+    .collect();
+
+    Ok(tags)
+}
+
+/// Fetches the 50 tags given to the most published schematics within the last
+/// number of days, as tags are applied when a schematic is uploaded this also
+/// counts schematics which were published later
+async fn fetch_trending_tags(pool: &PgPool, days: i32) -> ApiResult<Vec<TrendingTag>> {
+    let tags = sqlx::query!(
+        r#"
+        select
+            tags.tag_id, tags.tag_name,
+            tags.description, tags.category_id,
+            count(*) as "recent_count!"
+        from
+            applied_tags
+            inner join schematics using (schematic_id)
+            inner join tags on tags.tag_id = applied_tags.tag_id
+        where
+            schematics.status = 'published'
+            and applied_tags.created_at > now() - make_interval(days => $1)
+        group by
+            tags.tag_id
+        order by
+            count(*) desc, tags.tag_name
+        limit 50
+        "#,
+        days
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| TrendingTag {
+        details: FullTag {
+            tag_id: row.tag_id,
+            tag_name: row.tag_name,
+            description: row.description,
+            category_id: row.category_id
+        },
+        recent_count: row.recent_count
+    })
+    .collect();
+
+    Ok(tags)
+}
+
+/// Moves every schematic, follower and rule of a tag to another tag before
+/// deleting it, schematics and users which already have both only keep the
+/// target, as do blocks which already have a rule for the target
+async fn merge_tags(conn: &mut PgConnection, tag_id: i64, target_tag_id: i64) -> ApiResult<()> {
+    sqlx::query!(
+        r#"
+        insert into applied_tags (
+            tag_id, schematic_id, created_at
+        )
+        select $2, schematic_id, created_at
+        from applied_tags
+        where tag_id = $1
+        on conflict do nothing
+        "#,
+        tag_id,
+        target_tag_id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        insert into tag_follows (
+            user_id, tag_id, created_at
+        )
+        select user_id, $2, created_at
+        from tag_follows
+        where tag_id = $1
+        on conflict do nothing
+        "#,
+        tag_id,
+        target_tag_id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        insert into tag_rules (
+            tag_id, block_id, min_count
+        )
+        select $2, block_id, min_count
+        from tag_rules
+        where tag_id = $1
+        on conflict (tag_id, block_id) do nothing
+        "#,
+        tag_id,
+        target_tag_id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    // Any remaining rows referencing the merged tag are removed along with it
+    sqlx::query!(
+        r#"delete from tags where tag_id = $1"#,
+        tag_id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::*;
+
+    async fn create_tag(pool: &PgPool, tag_name: &str) -> i64 {
+        sqlx::query_scalar!(
+            r#"insert into tags (tag_name) values ($1) returning tag_id"#,
+            tag_name
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn create_rule(pool: &PgPool, tag_id: i64, block_id: &str, min_count: i32) {
+        sqlx::query!(
+            r#"insert into tag_rules (tag_id, block_id, min_count) values ($1, $2, $3)"#,
+            tag_id,
+            block_id,
+            min_count
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    #[sqlx::test]
+    async fn merging_keeps_rules(pool: PgPool) {
+        let merged = create_tag(&pool, "trains").await;
+        let target = create_tag(&pool, "railways").await;
+
+        create_rule(&pool, merged, "create:track", 10).await;
+        create_rule(&pool, merged, "create:station", 1).await;
+        create_rule(&pool, target, "create:track", 50).await;
+
+        let mut conn = pool.acquire().await.unwrap();
+        merge_tags(&mut conn, merged, target).await.unwrap();
+
+        let rules = sqlx::query!(
+            r#"
+            select tag_id, block_id, min_count
+            from tag_rules
+            where tag_id in ($1, $2)
+            order by block_id
+            "#,
+            merged,
+            target
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|rule| (rule.tag_id, rule.block_id, rule.min_count))
+        .collect::<Vec<_>>();
+
+        // The existing rule of the target is kept over the one being merged
+        assert_eq!(rules, vec![
+            (target, "create:station".to_string(), 1),
+            (target, "create:track".to_string(), 50),
+        ]);
+    }
+}
@@ -7,7 +7,8 @@ use uuid::Uuid;
 
 use crate::authentication::schemes::Session;
 use crate::error::{ApiError, ResultExt};
-use crate::models::schematic::Schematic;
+use crate::helpers::markdown;
+use crate::models::schematic::{Schematic, SchematicImage, SchematicVersion};
 use crate::models::user::{User, Role};
 use crate::response::ApiResult;
 use crate::api::ApiContext;
@@ -35,6 +36,7 @@ pub struct CurrentUser {
     pub avatar: Option<String>,
     #[oai(validator(max_length=256))]
     pub about: Option<String>,
+    pub about_html: Option<String>,
     pub role: Role,
     pub email: Option<String>,
     pub updated_at: Option<OffsetDateTime>,
@@ -58,7 +60,7 @@ impl UsersApi {
             select 
                 user_id, displayname,
                 username, email, about, 
-                role, updated_at, avatar, 
+                about_html, role, updated_at, avatar, 
                 created_at
             from users
             where user_id = $1
@@ -73,6 +75,9 @@ impl UsersApi {
 
     /// Fetches a user by their username, for privacy their email will not be included
     /// 
+    /// This also includes the number of users following them and the number of
+    /// users they follow
+    /// 
     #[oai(path="/users/:username", method = "get")]
     async fn fetch_user_by_id(
         &self,
@@ -85,8 +90,10 @@ impl UsersApi {
             select 
                 user_id, username,
                 displayname, role,
-                avatar, about,
-                created_at, updated_at
+                avatar, about, about_html,
+                created_at, updated_at,
+                (select count(*) from follows where followed_id = user_id) as "follower_count!",
+                (select count(*) from follows where follower_id = user_id) as "following_count!"
             from users
             where username = $1
             "#,
@@ -116,9 +123,11 @@ impl UsersApi {
             Schematic,
             r#"
             select 
-                schematic_id, schematic_name, body, 
-                images, author, downloads,
-                create_version_id, game_version_id,
+                schematic_id, schematic_name, body, body_html,
+                schematic_images_of(schematics.schematic_id) as "images!: Vec<SchematicImage>",
+                author, downloads, status,
+                schematic_game_versions_of(schematics.schematic_id) as "game_versions!: Vec<SchematicVersion>",
+                schematic_create_versions_of(schematics.schematic_id) as "create_versions!: Vec<SchematicVersion>",
                 created_at, updated_at
             from 
                 schematics
@@ -159,14 +168,16 @@ impl UsersApi {
                     username = coalesce($1, username),
                     displayname = coalesce($2, displayname),
                     about = coalesce($3, about),
-                    avatar = coalesce($4, avatar)
+                    about_html = coalesce($4, about_html),
+                    avatar = coalesce($5, avatar)
                 where 
-                    user_id = $5
+                    user_id = $6
                 returning
                     user_id,
                     username,
                     displayname,
                     about,
+                    about_html,
                     email,
                     role,
                     avatar,
@@ -176,6 +187,7 @@ impl UsersApi {
             form.username,
             form.displayname,
             form.about,
+            form.about.as_deref().map(markdown::render),
             form.avatar_url,
             user_id
         )
@@ -0,0 +1,412 @@
+use poem::web::Data;
+use poem_openapi::OpenApi;
+use poem_openapi::param::{Path, Query};
+use poem_openapi::payload::Json;
+use poem_openapi_derive::{Multipart, Object};
+use time::Date;
+
+use crate::authentication::schemes::Session;
+use crate::error::{ApiError, ResultExt};
+use crate::response::ApiResult;
+use crate::api::ApiContext;
+
+pub (in crate::api::v1) struct VersionsApi;
+
+/// A version of either Minecraft or Create which schematics can support
+#[derive(Debug, Serialize, Object)]
+pub (in crate::api::v1) struct Version {
+    pub version_id: i32,
+    pub version_name: String,
+    pub release_date: Option<Date>,
+    /// Versions are listed by this from newest to oldest, as version names
+    /// cannot always be compared
+    pub sort_order: i32,
+    /// Deprecated versions are no longer listed and cannot be selected for
+    /// schematics which do not already support them
+    pub deprecated: bool
+}
+
+#[derive(Debug, Multipart)]
+pub (in crate::api::v1) struct VersionBuilder {
+    #[oai(validator(min_length=1, max_length=32))]
+    pub version_name: String,
+    pub release_date: Option<Date>,
+    /// Defaults to placing the version after every existing version
+    pub sort_order: Option<i32>
+}
+
+#[derive(Debug, Multipart)]
+pub (in crate::api::v1) struct UpdateVersion {
+    #[oai(validator(min_length=1, max_length=32))]
+    pub version_name: Option<String>,
+    pub release_date: Option<Date>,
+    pub sort_order: Option<i32>
+}
+
+#[OpenApi(prefix_path="/v1")]
+impl VersionsApi {
+
+    /// Fetches the versions of Minecraft which schematics can support, from
+    /// newest to oldest. Deprecated versions are only included if requested
+    ///
+    #[oai(path = "/versions/game", method = "get")]
+    async fn get_game_versions(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Query(include_deprecated): Query<Option<bool>>
+    ) -> ApiResult<Json<Vec<Version>>> {
+        let versions = sqlx::query_as!(
+            Version,
+            r#"
+            select
+                game_version_id as version_id,
+                game_version_name as version_name,
+                release_date, sort_order, deprecated
+            from game_versions
+            where $1 or not deprecated
+            order by sort_order desc, game_version_id desc
+            "#,
+            include_deprecated.unwrap_or(false)
+        )
+        .fetch_all(&ctx.pool)
+        .await?;
+
+        Ok(Json(versions))
+    }
+
+    /// Fetches the versions of Create which schematics can support, from
+    /// newest to oldest. Deprecated versions are only included if requested
+    ///
+    #[oai(path = "/versions/create", method = "get")]
+    async fn get_create_versions(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Query(include_deprecated): Query<Option<bool>>
+    ) -> ApiResult<Json<Vec<Version>>> {
+        let versions = sqlx::query_as!(
+            Version,
+            r#"
+            select
+                create_version_id as version_id,
+                create_version_name as version_name,
+                release_date, sort_order, deprecated
+            from create_versions
+            where $1 or not deprecated
+            order by sort_order desc, create_version_id desc
+            "#,
+            include_deprecated.unwrap_or(false)
+        )
+        .fetch_all(&ctx.pool)
+        .await?;
+
+        Ok(Json(versions))
+    }
+
+    /// Adds a new version of Minecraft, this requires for the current user to
+    /// be an administrator
+    ///
+    /// Version names must be unique, if the name is already used a `422
+    /// Unprocessable Entity` error will be returned
+    ///
+    #[oai(path = "/versions/game", method = "post")]
+    async fn create_game_version(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        form: VersionBuilder,
+        session: Session
+    ) -> ApiResult<Json<Version>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_administrator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        let version = sqlx::query_as!(
+            Version,
+            r#"
+            insert into game_versions (
+                game_version_name, release_date, sort_order
+            )
+            values (
+                $1, $2, coalesce($3, (select coalesce(max(sort_order), 0) + 1 from game_versions))
+            )
+            returning
+                game_version_id as version_id,
+                game_version_name as version_name,
+                release_date, sort_order, deprecated
+            "#,
+            form.version_name,
+            form.release_date,
+            form.sort_order
+        )
+        .fetch_one(&mut *transaction)
+        .await
+        .on_constraint("game_versions_game_version_name_key", |_| {
+            ApiError::unprocessable_entity([("version_name", "a version with this name already exists")])
+        })?;
+
+        transaction.commit().await?;
+
+        Ok(Json(version))
+    }
+
+    /// Adds a new version of Create, this requires for the current user to be
+    /// an administrator
+    ///
+    /// Version names must be unique, if the name is already used a `422
+    /// Unprocessable Entity` error will be returned
+    ///
+    #[oai(path = "/versions/create", method = "post")]
+    async fn create_create_version(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        form: VersionBuilder,
+        session: Session
+    ) -> ApiResult<Json<Version>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_administrator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        let version = sqlx::query_as!(
+            Version,
+            r#"
+            insert into create_versions (
+                create_version_name, release_date, sort_order
+            )
+            values (
+                $1, $2, coalesce($3, (select coalesce(max(sort_order), 0) + 1 from create_versions))
+            )
+            returning
+                create_version_id as version_id,
+                create_version_name as version_name,
+                release_date, sort_order, deprecated
+            "#,
+            form.version_name,
+            form.release_date,
+            form.sort_order
+        )
+        .fetch_one(&mut *transaction)
+        .await
+        .on_constraint("create_versions_create_version_name_key", |_| {
+            ApiError::unprocessable_entity([("version_name", "a version with this name already exists")])
+        })?;
+
+        transaction.commit().await?;
+
+        Ok(Json(version))
+    }
+
+    /// Updates a version of Minecraft, all fields are optional. This requires
+    /// for the current user to be an administrator
+    ///
+    #[oai(path = "/versions/game/:version_id", method = "patch")]
+    async fn update_game_version(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(version_id): Path<i32>,
+        form: UpdateVersion,
+        session: Session
+    ) -> ApiResult<Json<Version>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_administrator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        let version = sqlx::query_as!(
+            Version,
+            r#"
+            update game_versions
+                set
+                    game_version_name = coalesce($1, game_version_name),
+                    release_date = coalesce($2, release_date),
+                    sort_order = coalesce($3, sort_order)
+                where game_version_id = $4
+                returning
+                    game_version_id as version_id,
+                    game_version_name as version_name,
+                    release_date, sort_order, deprecated
+            "#,
+            form.version_name,
+            form.release_date,
+            form.sort_order,
+            version_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .on_constraint("game_versions_game_version_name_key", |_| {
+            ApiError::unprocessable_entity([("version_name", "a version with this name already exists")])
+        })?
+        .ok_or(ApiError::NotFound)?;
+
+        transaction.commit().await?;
+
+        Ok(Json(version))
+    }
+
+    /// Updates a version of Create, all fields are optional. This requires for
+    /// the current user to be an administrator
+    ///
+    #[oai(path = "/versions/create/:version_id", method = "patch")]
+    async fn update_create_version(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(version_id): Path<i32>,
+        form: UpdateVersion,
+        session: Session
+    ) -> ApiResult<Json<Version>> {
+        let mut transaction = ctx.pool.begin().await?;
+
+        if !session.is_administrator(&mut *transaction).await? {
+            return Err(ApiError::Forbidden);
+        }
+
+        let version = sqlx::query_as!(
+            Version,
+            r#"
+            update create_versions
+                set
+                    create_version_name = coalesce($1, create_version_name),
+                    release_date = coalesce($2, release_date),
+                    sort_order = coalesce($3, sort_order)
+                where create_version_id = $4
+                returning
+                    create_version_id as version_id,
+                    create_version_name as version_name,
+                    release_date, sort_order, deprecated
+            "#,
+            form.version_name,
+            form.release_date,
+            form.sort_order,
+            version_id
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .on_constraint("create_versions_create_version_name_key", |_| {
+            ApiError::unprocessable_entity([("version_name", "a version with this name already exists")])
+        })?
+        .ok_or(ApiError::NotFound)?;
+
+        transaction.commit().await?;
+
+        Ok(Json(version))
+    }
+
+    /// Deprecates a version of Minecraft, it will no longer be listed and can
+    /// only be kept by schematics which already support it. This requires for
+    /// the current user to be an administrator
+    ///
+    #[oai(path = "/versions/game/:version_id/deprecate", method = "put")]
+    async fn deprecate_game_version(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(version_id): Path<i32>,
+        session: Session
+    ) -> ApiResult<()> {
+        set_game_version_deprecated(ctx, version_id, true, session).await
+    }
+
+    /// Reverses `PUT /api/v1/versions/game/:id/deprecate`, this requires for
+    /// the current user to be an administrator
+    ///
+    #[oai(path = "/versions/game/:version_id/restore", method = "put")]
+    async fn restore_game_version(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(version_id): Path<i32>,
+        session: Session
+    ) -> ApiResult<()> {
+        set_game_version_deprecated(ctx, version_id, false, session).await
+    }
+
+    /// Deprecates a version of Create, it will no longer be listed and can
+    /// only be kept by schematics which already support it. This requires for
+    /// the current user to be an administrator
+    ///
+    #[oai(path = "/versions/create/:version_id/deprecate", method = "put")]
+    async fn deprecate_create_version(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(version_id): Path<i32>,
+        session: Session
+    ) -> ApiResult<()> {
+        set_create_version_deprecated(ctx, version_id, true, session).await
+    }
+
+    /// Reverses `PUT /api/v1/versions/create/:id/deprecate`, this requires for
+    /// the current user to be an administrator
+    ///
+    #[oai(path = "/versions/create/:version_id/restore", method = "put")]
+    async fn restore_create_version(
+        &self,
+        Data(ctx): Data<&ApiContext>,
+        Path(version_id): Path<i32>,
+        session: Session
+    ) -> ApiResult<()> {
+        set_create_version_deprecated(ctx, version_id, false, session).await
+    }
+}
+
+async fn set_game_version_deprecated(
+    ctx: &ApiContext,
+    version_id: i32,
+    deprecated: bool,
+    session: Session
+) -> ApiResult<()> {
+    let mut transaction = ctx.pool.begin().await?;
+
+    if !session.is_administrator(&mut *transaction).await? {
+        return Err(ApiError::Forbidden);
+    }
+
+    sqlx::query!(
+        r#"
+        update game_versions
+            set deprecated = $1
+            where game_version_id = $2
+            returning game_version_id
+        "#,
+        deprecated,
+        version_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+async fn set_create_version_deprecated(
+    ctx: &ApiContext,
+    version_id: i32,
+    deprecated: bool,
+    session: Session
+) -> ApiResult<()> {
+    let mut transaction = ctx.pool.begin().await?;
+
+    if !session.is_administrator(&mut *transaction).await? {
+        return Err(ApiError::Forbidden);
+    }
+
+    sqlx::query!(
+        r#"
+        update create_versions
+            set deprecated = $1
+            where create_version_id = $2
+            returning create_version_id
+        "#,
+        deprecated,
+        version_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+
+    transaction.commit().await?;
+
+    Ok(())
+}
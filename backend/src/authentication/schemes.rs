@@ -1,5 +1,7 @@
-use poem::Request;
+use poem::{Request, RequestBody};
 use poem_openapi::auth::ApiKey;
+use poem_openapi::registry::Registry;
+use poem_openapi::{ApiExtractor, ApiExtractorType, ExtractParamOptions};
 use poem_openapi::payload::Json;
 use poem_openapi_derive::SecurityScheme;
 use uuid::Uuid;
@@ -27,12 +29,35 @@ async fn session_check(req: &Request, session_id: ApiKey) -> poem::Result<Uuid>
     Ok(session.user_id)
 }
 
-#[derive(SecurityScheme)]
-#[oai(ty = "api_key", key_name = "session", key_in = "cookie", checker = "optional_session_check")]
+/// The current user if they are logged in. Unlike deriving `SecurityScheme`
+/// this does not reject requests without a session cookie, which would
+/// otherwise fail before the checker is reached
 pub struct OptionalSession(pub Option<Uuid>);
 
-async fn optional_session_check(req: &Request, session_id: ApiKey) -> poem::Result<Option<Uuid>> {
-    Ok(session_check(req, session_id).await.ok())
+#[poem::async_trait]
+impl<'a> ApiExtractor<'a> for OptionalSession {
+    const TYPES: &'static [ApiExtractorType] = &[ApiExtractorType::SecurityScheme];
+
+    type ParamType = ();
+    type ParamRawType = ();
+
+    fn register(registry: &mut Registry) {
+        Session::register(registry);
+    }
+
+    fn security_schemes() -> Vec<&'static str> {
+        Session::security_schemes()
+    }
+
+    async fn from_request(
+        req: &'a Request,
+        body: &mut RequestBody,
+        param_opts: ExtractParamOptions<Self::ParamType>,
+    ) -> poem::Result<Self> {
+        let session = Session::from_request(req, body, param_opts).await.ok();
+
+        Ok(Self(session.map(|Session(user_id)| user_id)))
+    }
 }
 
 async fn check_timeout(
@@ -62,8 +87,10 @@ impl Session {
             select 
                 user_id, username, 
                 displayname, about, 
-                role, avatar,
-                created_at, updated_at
+                about_html, role, avatar,
+                created_at, updated_at,
+                (select count(*) from follows where followed_id = user_id) as "follower_count!",
+                (select count(*) from follows where follower_id = user_id) as "following_count!"
             from 
                 users
             where 
@@ -5,6 +5,14 @@ use clap::{Parser, Subcommand};
 use crate::api;
 use crate::api::openapi::OpenApiSchemaCommandArguements;
 use crate::api::StartCommandServerArguments;
+use crate::helpers::markdown;
+use crate::helpers::markdown::RenderMarkdownCommandArguments;
+use crate::jobs::worker;
+use crate::jobs::worker::WorkerCommandArguments;
+use crate::storage::{blocks, dependencies, images};
+use crate::storage::blocks::ImportBlocksCommandArguments;
+use crate::storage::dependencies::RebuildDependenciesCommandArguments;
+use crate::storage::images::BackfillImagesCommandArguments;
 
 #[derive(Parser, Debug)]
 #[command(name = "Create schematics command line interface")]
@@ -20,7 +28,22 @@ enum Commands {
     Start(StartCommandServerArguments), 
 
     #[command(name = "openapi-schema")]
-    Openapi(OpenApiSchemaCommandArguements)
+    Openapi(OpenApiSchemaCommandArguements),
+
+    #[command(name = "worker")]
+    Worker(WorkerCommandArguments),
+
+    #[command(name = "rebuild-dependencies")]
+    RebuildDependencies(RebuildDependenciesCommandArguments),
+
+    #[command(name = "import-blocks")]
+    ImportBlocks(ImportBlocksCommandArguments),
+
+    #[command(name = "render-markdown")]
+    RenderMarkdown(RenderMarkdownCommandArguments),
+
+    #[command(name = "backfill-images")]
+    BackfillImages(BackfillImagesCommandArguments)
 }
 
 pub async fn init() -> ExitCode {
@@ -29,6 +52,11 @@ pub async fn init() -> ExitCode {
     let result = match cli.command {
         Commands::Start(args) => api::serve(args).await,
         Commands::Openapi(args) => api::openapi::save_schema(args),
+        Commands::Worker(args) => worker::run(args).await,
+        Commands::RebuildDependencies(args) => dependencies::rebuild(args).await,
+        Commands::ImportBlocks(args) => blocks::import(args).await,
+        Commands::RenderMarkdown(args) => markdown::render_stored(args).await,
+        Commands::BackfillImages(args) => images::backfill(args).await,
     };
         
     if let Err(e) = result {
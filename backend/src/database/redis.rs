@@ -2,7 +2,7 @@
 use std::fmt::Display;
 
 use clap::Args;
-use redis::{FromRedisValue, ToRedisArgs, Client, aio::ConnectionManager};
+use redis::{FromRedisValue, ToRedisArgs, Client, Script, aio::ConnectionManager};
 
 use crate::response::ApiResult;
 
@@ -107,6 +107,94 @@ impl RedisPool {
         self.set(namespace, key, value, expiry).await
     }
 
+    /// Sets the given key only if it does not already exist, returning whether
+    /// or not the value was set
+    pub async fn set_if_absent<T, K>(
+        &self,
+        namespace: &str,
+        key: K,
+        value: T,
+        expiry: u64
+    ) -> ApiResult<bool>
+    where
+        K: Display,
+        T: ToRedisArgs
+    {
+        let res = redis::cmd("SET")
+            .arg(Self::format_key(namespace, key))
+            .arg(value)
+            .arg("NX")
+            .arg("EX")
+            .arg(expiry)
+            .query_async::<_, Option<String>>(&mut self.manager.clone())
+            .await?;
+
+        Ok(res.is_some())
+    }
+
+    /// Increments the number stored at the given key, starting from zero if it
+    /// does not exist, returning the new value
+    pub async fn increment<K>(
+        &self,
+        namespace: &str,
+        key: K
+    ) -> ApiResult<i64>
+    where
+        K: Display
+    {
+        let res = redis::cmd("INCR")
+            .arg(Self::format_key(namespace, key))
+            .query_async::<_, i64>(&mut self.manager.clone())
+            .await?;
+
+        Ok(res)
+    }
+
+    /// Fetches the number of seconds until the given key expires, if the key
+    /// does not exist or has no expiry then `None` is returned
+    pub async fn ttl<K>(
+        &self,
+        namespace: &str,
+        key: K
+    ) -> ApiResult<Option<u64>>
+    where
+        K: Display
+    {
+        let res = redis::cmd("TTL")
+            .arg(Self::format_key(namespace, key))
+            .query_async::<_, i64>(&mut self.manager.clone())
+            .await?;
+
+        Ok(u64::try_from(res).ok())
+    }
+
+    /// Runs a lua script against the given keys, passing the given arguments
+    pub async fn run_script<T, K, A>(
+        &self,
+        script: &Script,
+        namespace: &str,
+        keys: impl IntoIterator<Item = K>,
+        args: A
+    ) -> ApiResult<T>
+    where
+        K: Display,
+        T: FromRedisValue,
+        A: ToRedisArgs
+    {
+        let mut invocation = script.prepare_invoke();
+
+        for key in keys {
+            invocation.key(Self::format_key(namespace, key));
+        }
+
+        let res = invocation
+            .arg(args)
+            .invoke_async::<_, T>(&mut self.manager.clone())
+            .await?;
+
+        Ok(res)
+    }
+
     pub async fn delete<K>(
         &self,
         namespace: &str,
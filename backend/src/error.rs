@@ -49,6 +49,23 @@ pub enum ApiError {
     #[oai(status = 404)]
     NotFound,
 
+    /// Return `409 Conflict`, for when a request could not be completed
+    /// because of a concurrent change to the same resource and can be
+    /// retried
+    #[oai(status = 409)]
+    Conflict,
+
+    /// Return `423 Locked`, for when the resource being modified has
+    /// been locked such as a schematic's comments
+    #[oai(status = 423)]
+    Locked,
+
+    /// Return `429 Too Many Requests`, for when the user has to wait
+    /// before performing an action again, returning when they are
+    /// next able to do so
+    #[oai(status = 429)]
+    TooManyRequests(Json<Cooldown>),
+
     /// Return `422 Unprocessable Entity`
     ///
     /// This also serializes the `errors` map provided to JSON
@@ -82,6 +99,11 @@ pub struct Punishment {
     pub reason: Option<String>,
 }
 
+#[derive(Debug, Object, Serialize, Deserialize)]
+pub struct Cooldown {
+    pub until: OffsetDateTime,
+}
+
 impl ApiError {
     /// Convenient constructor for `Error::UnprocessableEntity`.
     ///
@@ -175,13 +197,17 @@ impl From<redis::RedisError> for ApiError {
 ///     .on_constraint("user_username_key", |_| Error::unprocessable_entity([("username", "already taken")]))?;
 /// ```
 ///
+/// Calls can be chained to handle several constraints, the database error is only converted
+/// into an `ApiError` once it is returned with `?` so it is not logged if any of them match.
+///
+/// ```rust,ignore
+///     .on_constraint("user_username_key", |_| Error::unprocessable_entity([("username", "already taken")]))
+///     .on_constraint("user_email_key", |_| Error::unprocessable_entity([("email", "already taken")]))?;
+/// ```
+///
 /// Something like this would ideally live in a crate if it made sense to author one,
 /// however its definition is tied pretty intimately to the `ResponseError` type, which is itself
 /// tied directly to application semantics.
-///
-/// To actually make this work in a generic context would make it quite a bit more complex,
-/// as you'd need an intermediate error type to represent either a mapped or an unmapped error,
-/// and even then it's not clear how to handle `?` in the unmapped case without more boilerplate.
 pub trait ResultExt<T> {
     /// If `self` contains a SQLx database constraint error with the given name,
     /// transform the error.
@@ -191,7 +217,24 @@ pub trait ResultExt<T> {
         self,
         name: &str,
         f: impl FnOnce(Box<dyn DatabaseError>) -> ApiError,
-    ) -> Result<T, ApiError>;
+    ) -> Result<T, ConstraintError>;
+}
+
+/// A database error which may have been transformed by `ResultExt::on_constraint`,
+/// errors which haven't been are kept as is so that further constraints can be checked.
+#[derive(Debug)]
+pub enum ConstraintError {
+    Mapped(ApiError),
+    Unmapped(sqlx::Error),
+}
+
+impl From<ConstraintError> for ApiError {
+    fn from(error: ConstraintError) -> Self {
+        match error {
+            ConstraintError::Mapped(error) => error,
+            ConstraintError::Unmapped(error) => error.into(),
+        }
+    }
 }
 
 impl<T> ResultExt<T> for Result<T, sqlx::Error> {
@@ -199,10 +242,22 @@ impl<T> ResultExt<T> for Result<T, sqlx::Error> {
         self,
         name: &str,
         map_err: impl FnOnce(Box<dyn DatabaseError>) -> ApiError,
-    ) -> Result<T, ApiError> {
+    ) -> Result<T, ConstraintError> {
+        self.map_err(ConstraintError::Unmapped).on_constraint(name, map_err)
+    }
+}
+
+impl<T> ResultExt<T> for Result<T, ConstraintError> {
+    fn on_constraint(
+        self,
+        name: &str,
+        map_err: impl FnOnce(Box<dyn DatabaseError>) -> ApiError,
+    ) -> Result<T, ConstraintError> {
         self.map_err(|e| match e {
-            sqlx::Error::Database(dbe) if dbe.constraint() == Some(name) => map_err(dbe),
-            e => e.into(),
+            ConstraintError::Unmapped(sqlx::Error::Database(dbe)) if dbe.constraint() == Some(name) => {
+                ConstraintError::Mapped(map_err(dbe))
+            }
+            e => e,
         })
     }
 }
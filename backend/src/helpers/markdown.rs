@@ -0,0 +1,261 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+use ammonia::{Builder, UrlRelative};
+use clap::Args;
+use pulldown_cmark::{Options, Parser};
+use sqlx::PgPool;
+
+use crate::database::postgres;
+use crate::database::postgres::DatabaseArguments;
+
+/// The tags which are allowed to remain after sanitization, anything else
+/// will be stripped although its text content will be kept
+const ALLOWED_TAGS: &[&str] = &[
+    "a", "blockquote", "br", "code", "del", "em", "h1", "h2", "h3", "h4",
+    "h5", "h6", "hr", "img", "li", "ol", "p", "pre", "strong", "table",
+    "tbody", "td", "th", "thead", "tr", "ul",
+];
+
+const ALLOWED_SCHEMES: &[&str] = &["http", "https", "mailto"];
+
+static SANITIZER: OnceLock<Builder<'static>> = OnceLock::new();
+
+#[derive(Args, Debug)]
+pub struct MarkdownArguments {
+    #[arg(help = "The url of the proxy images within markdown are loaded through, if not set images are removed")]
+    #[arg(env = "IMAGE_PROXY_URL", long = "image_proxy_url")]
+    pub image_proxy_url: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct RenderMarkdownCommandArguments {
+    #[arg(help = "Render all markdown rather than only content without rendered html, such as after changing the image proxy")]
+    #[arg(long = "all")]
+    pub all: bool,
+
+    #[command(next_help_heading = "Database")]
+    #[command(flatten)]
+    pub postgres: DatabaseArguments,
+
+    #[command(next_help_heading = "Markdown")]
+    #[command(flatten)]
+    pub markdown: MarkdownArguments,
+}
+
+/// Sets how markdown is rendered, this should be called once before anything
+/// is rendered. Later calls have no effect
+pub fn configure(MarkdownArguments { image_proxy_url }: MarkdownArguments) {
+    let image_proxy = image_proxy_url.filter(|url| !url.is_empty());
+
+    let _ = SANITIZER.set(build_sanitizer(image_proxy));
+}
+
+/// Renders user provided markdown to html which is safe to be directly embeded
+/// within a page.
+///
+/// All links are given `rel="nofollow noopener noreferrer"` and images are
+/// rewritten to be loaded through the proxy set by `configure`, with the
+/// original url appended url encoded, so that viewing content does not leak
+/// the users ip to third parties. If a proxy is not configured images are
+/// removed entirely.
+///
+pub fn render(markdown: &str) -> String {
+    render_with(sanitizer(), markdown)
+}
+
+fn render_with(sanitizer: &Builder, markdown: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+
+    let mut html = String::with_capacity(markdown.len() * 3 / 2);
+    pulldown_cmark::html::push_html(&mut html, Parser::new_ext(markdown, options));
+
+    sanitizer.clean(&html).to_string()
+}
+
+fn sanitizer() -> &'static Builder<'static> {
+    SANITIZER.get_or_init(|| build_sanitizer(None))
+}
+
+fn build_sanitizer(image_proxy: Option<String>) -> Builder<'static> {
+    let mut tags: HashSet<&str> = ALLOWED_TAGS.iter().copied().collect();
+
+    if image_proxy.is_none() {
+        tags.remove("img");
+    }
+
+    let mut builder = Builder::default();
+
+    builder
+        .tags(tags)
+        .url_schemes(ALLOWED_SCHEMES.iter().copied().collect())
+        .url_relative(UrlRelative::Deny)
+        .link_rel(Some("nofollow noopener noreferrer"))
+        .attribute_filter(move |element, attribute, value| {
+            match (element, attribute, &image_proxy) {
+                ("img", "src", Some(proxy)) => {
+                    let encoded: String = url::form_urlencoded::byte_serialize(value.as_bytes()).collect();
+                    Some(Cow::Owned(format!("{proxy}{encoded}")))
+                }
+                _ => Some(Cow::Borrowed(value))
+            }
+        });
+
+    builder
+}
+
+/// Renders the markdown of schematics, comments and users which was written
+/// before rendered html was stored, or all of it if requested. Rendering does
+/// not count as an edit so `updated_at` is left unchanged, see the
+/// `set_updated_at` triggers. Each row is updated on its own so the tables
+/// remain usable while rendering
+pub async fn render_stored(
+    RenderMarkdownCommandArguments {
+        all,
+        postgres,
+        markdown
+    }: RenderMarkdownCommandArguments
+) -> Result<(), anyhow::Error> {
+    configure(markdown);
+
+    let pool = postgres::connect(postgres).await?;
+
+    render_schematics(&pool, all).await?;
+    render_comments(&pool, all).await?;
+    render_users(&pool, all).await?;
+
+    tracing::info!("Finished rendering markdown");
+
+    Ok(())
+}
+
+async fn render_schematics(pool: &PgPool, all: bool) -> Result<(), anyhow::Error> {
+    let schematics = sqlx::query!(
+        r#"select schematic_id, body from schematics where $1 or body_html is null"#,
+        all
+    )
+    .fetch_all(pool)
+    .await?;
+
+    tracing::info!("Rendering the bodies of {} schematics", schematics.len());
+
+    for schematic in schematics {
+        sqlx::query!(
+            r#"update schematics set body_html = $1 where schematic_id = $2"#,
+            render(&schematic.body),
+            schematic.schematic_id
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+async fn render_comments(pool: &PgPool, all: bool) -> Result<(), anyhow::Error> {
+    // Deleted comments have no body and are never rendered
+    let comments = sqlx::query!(
+        r#"
+        select comment_id, comment_body 
+        from comments 
+        where not is_deleted 
+        and ($1 or comment_html is null)
+        "#,
+        all
+    )
+    .fetch_all(pool)
+    .await?;
+
+    tracing::info!("Rendering the bodies of {} comments", comments.len());
+
+    for comment in comments {
+        sqlx::query!(
+            r#"update comments set comment_html = $1 where comment_id = $2"#,
+            render(&comment.comment_body),
+            comment.comment_id
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+async fn render_users(pool: &PgPool, all: bool) -> Result<(), anyhow::Error> {
+    let users = sqlx::query!(
+        r#"
+        select user_id, about as "about!" 
+        from users 
+        where about is not null 
+        and ($1 or about_html is null)
+        "#,
+        all
+    )
+    .fetch_all(pool)
+    .await?;
+
+    tracing::info!("Rendering the about sections of {} users", users.len());
+
+    for user in users {
+        sqlx::query!(
+            r#"update users set about_html = $1 where user_id = $2"#,
+            render(&user.about),
+            user.user_id
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROXY: &str = "https://proxy.example.com/?url=";
+
+    #[test]
+    fn removes_scripts_and_javascript_links() {
+        let html = render_with(
+            &build_sanitizer(None),
+            "<script>alert(1)</script>\n\n[click](javascript:alert(1))"
+        );
+
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("javascript:"));
+        assert!(html.contains("click"));
+    }
+
+    #[test]
+    fn adds_rel_to_links() {
+        let html = render_with(&build_sanitizer(None), "[site](https://example.com)");
+
+        assert!(html.contains(r#"href="https://example.com""#));
+        assert!(html.contains(r#"rel="nofollow noopener noreferrer""#));
+    }
+
+    #[test]
+    fn removes_images_without_proxy() {
+        let html = render_with(&build_sanitizer(None), "![alt](https://example.com/a.png)");
+
+        assert!(!html.contains("<img"));
+        assert!(!html.contains("example.com"));
+    }
+
+    #[test]
+    fn loads_images_through_proxy() {
+        let html = render_with(
+            &build_sanitizer(Some(PROXY.to_string())),
+            "![alt](https://example.com/a.png?b=c)"
+        );
+
+        assert!(html.contains(
+            r#"src="https://proxy.example.com/?url=https%3A%2F%2Fexample.com%2Fa.png%3Fb%3Dc""#
+        ));
+    }
+}
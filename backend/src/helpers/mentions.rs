@@ -0,0 +1,123 @@
+/// The length limits placed on usernames, anything outside of these cannot
+/// refer to a user so is not treated as a mention
+const MIN_USERNAME_LENGTH: usize = 3;
+const MAX_USERNAME_LENGTH: usize = 30;
+
+/// A single `@username` mention found within some text, the span covers the
+/// leading `@` and is measured in UTF-16 code units rather than bytes so that
+/// it matches string indices in javascript clients
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mention {
+    pub username: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Finds all `@username` mentions within the given text in the order they
+/// appear, usernames may contain ASCII letters, digits, underscores and dashes.
+///
+/// An `@` is only treated as the start of a mention when it is not directly
+/// preceded by a username character, this avoids email addresses such as
+/// `someone@example.com` being picked up. Mentions within inline code or code
+/// blocks are still included as we do not parse the markdown here.
+///
+pub fn parse(text: &str) -> Vec<Mention> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut mentions = Vec::new();
+
+    // The offset of each character in UTF-16 code units, characters outside of
+    // the basic multilingual plane such as emoji take up two
+    let mut offsets = Vec::with_capacity(chars.len() + 1);
+    let mut offset = 0;
+
+    for c in &chars {
+        offsets.push(offset);
+        offset += c.len_utf16();
+    }
+
+    offsets.push(offset);
+    let mut index = 0;
+
+    while index < chars.len() {
+        let preceded_by_word = index > 0 && is_username_char(chars[index - 1]);
+
+        if chars[index] != '@' || preceded_by_word {
+            index += 1;
+            continue;
+        }
+
+        let start = index;
+        let mut end = index + 1;
+
+        while end < chars.len() && is_username_char(chars[end]) {
+            end += 1;
+        }
+
+        let length = end - start - 1;
+
+        if (MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&length) {
+            mentions.push(Mention {
+                username: chars[start + 1..end].iter().collect(),
+                start: offsets[start],
+                end: offsets[end],
+            });
+        }
+
+        index = end;
+    }
+
+    mentions
+}
+
+fn is_username_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mention(username: &str, start: usize, end: usize) -> Mention {
+        Mention { username: username.to_string(), start, end }
+    }
+
+    #[test]
+    fn measures_spans_in_utf16() {
+        // The emoji takes up two UTF-16 code units
+        assert_eq!(parse("\u{1F600} @alice"), vec![mention("alice", 3, 9)]);
+    }
+
+    #[test]
+    fn ignores_email_addresses() {
+        assert_eq!(parse("mail someone@example.com"), vec![]);
+    }
+
+    #[test]
+    fn ignores_names_outside_length_limits() {
+        let long = "a".repeat(MAX_USERNAME_LENGTH + 1);
+        let longest = "a".repeat(MAX_USERNAME_LENGTH);
+
+        assert_eq!(parse("@ab"), vec![]);
+        assert_eq!(parse(&format!("@{long}")), vec![]);
+        assert_eq!(parse("@abc"), vec![mention("abc", 0, 4)]);
+        assert_eq!(parse(&format!("@{longest}")), vec![mention(&longest, 0, 31)]);
+    }
+
+    #[test]
+    fn finds_adjacent_mentions() {
+        assert_eq!(
+            parse("@alice @bob_1,@carol-d"),
+            vec![
+                mention("alice", 0, 6),
+                mention("bob_1", 7, 13),
+                mention("carol-d", 14, 22),
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_at_non_ascii_characters() {
+        assert_eq!(parse("@alicé"), vec![mention("alic", 0, 5)]);
+    }
+}
@@ -1 +1,3 @@
-pub mod cookies;
\ No newline at end of file
+pub mod cookies;
+pub mod markdown;
+pub mod mentions;
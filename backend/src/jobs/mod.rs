@@ -0,0 +1,181 @@
+use poem_openapi_derive::Enum;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::metadata::ModMetadataSources;
+use crate::response::ApiResult;
+
+pub mod mods;
+pub mod uploads;
+pub mod worker;
+
+/// Work which is too slow to be done within a request, jobs are stored in the
+/// `jobs` table and picked up by workers started by either the `server` or
+/// `worker` commands, see `worker::run`.
+///
+/// Jobs may be run more than once if they fail or a worker stops while running
+/// them so they should be safe to repeat.
+///
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Job {
+    /// Processes the images and schematic files uploaded to a schematic which
+    /// have been stored within the given pending upload directory, see
+    /// `storage::PENDING_PATH`
+    ProcessUpload {
+        schematic_id: Uuid,
+        directory: String,
+        #[serde(default)]
+        upload: uploads::UploadKind
+    },
+
+    /// Fetches the details of a mod from the platforms it is published on,
+    /// this is queued whenever a new mod is seen and again periodically to
+    /// keep them up to date, see `mods::enqueue_stale_mods`
+    SyncMod {
+        mod_id: Uuid
+    }
+}
+
+/// Everything needed to run jobs, shared between each worker
+#[derive(Clone)]
+pub struct JobContext {
+    pub pool: PgPool,
+    pub sources: ModMetadataSources
+}
+
+#[derive(Enum, Serialize, Debug)]
+#[serde(rename_all="snake_case")]
+#[oai(rename_all="snake_case")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Completed,
+    Failed
+}
+
+impl From<std::string::String> for JobStatus {
+    fn from(value: std::string::String) -> Self {
+        match value.as_str() {
+            "running" => Self::Running,
+            "completed" => Self::Completed,
+            "failed" => Self::Failed,
+            _ => Self::Pending,
+        }
+    }
+}
+
+/// An error raised while running a job, by default jobs will be retried with
+/// an increasing delay until they run out of attempts unless the error is
+/// fatal, for instance when an uploaded image cannot be decoded.
+#[derive(Debug)]
+pub enum JobError {
+    Retry(anyhow::Error),
+    Fatal(anyhow::Error)
+}
+
+impl From<anyhow::Error> for JobError {
+    fn from(error: anyhow::Error) -> Self {
+        JobError::Retry(error)
+    }
+}
+
+/// Foreign key violations mean something the job depends on has been deleted
+/// while it was running, these will not succeed if retried
+impl From<sqlx::Error> for JobError {
+    fn from(error: sqlx::Error) -> Self {
+        match &error {
+            sqlx::Error::Database(e) if e.kind() == sqlx::error::ErrorKind::ForeignKeyViolation => {
+                JobError::Fatal(error.into())
+            }
+            _ => JobError::Retry(error.into())
+        }
+    }
+}
+
+impl From<std::io::Error> for JobError {
+    fn from(error: std::io::Error) -> Self {
+        JobError::Retry(error.into())
+    }
+}
+
+/// Errors raised while validating uploads are returned as api errors, these
+/// will not succeed if retried
+impl From<ApiError> for JobError {
+    fn from(error: ApiError) -> Self {
+        match error {
+            ApiError::BadRequest | ApiError::UnprocessableEntity(_) => {
+                JobError::Fatal(anyhow::anyhow!("Invalid upload: {error:?}"))
+            }
+            error => JobError::Retry(anyhow::anyhow!("{error:?}"))
+        }
+    }
+}
+
+impl Job {
+    /// Queues this job to be run by the next available worker, returning the
+    /// id of the job which can be used to check its status.
+    ///
+    /// This should be called within the same transaction as any changes the
+    /// job depends on, this way the job will never run without them.
+    pub async fn enqueue<'a, E>(
+        &self,
+        executor: E,
+        user_id: Option<Uuid>
+    ) -> ApiResult<Uuid>
+    where
+        E: sqlx::Executor<'a, Database = sqlx::Postgres>,
+    {
+        let payload = serde_json::to_value(self).map_err(anyhow::Error::new)?;
+
+        let job_id = sqlx::query_scalar!(
+            r#"
+            insert into jobs (
+                user_id, payload
+            )
+            values (
+                $1, $2
+            )
+            returning job_id
+            "#,
+            user_id,
+            payload
+        )
+        .fetch_one(executor)
+        .await?;
+
+        Ok(job_id)
+    }
+
+    /// Runs the job, returning anything it produced which is stored as the
+    /// result of the job, see `GET /api/v1/jobs/:id`
+    async fn run(&self, ctx: &JobContext) -> Result<Option<serde_json::Value>, JobError> {
+        match self {
+            Job::ProcessUpload { schematic_id, directory, upload } => {
+                let report = uploads::process_upload(&ctx.pool, *schematic_id, directory, *upload).await?;
+
+                let result = report.map(serde_json::to_value)
+                    .transpose()
+                    .map_err(anyhow::Error::new)?;
+
+                Ok(result)
+            }
+            Job::SyncMod { mod_id } => {
+                mods::sync_mod(ctx, *mod_id).await?;
+
+                Ok(None)
+            }
+        }
+    }
+
+    /// Called once a job has failed and will no longer be retried
+    async fn on_failure(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        match self {
+            Job::ProcessUpload { schematic_id, directory, upload } => {
+                uploads::fail_upload(pool, *schematic_id, directory, *upload).await
+            }
+            Job::SyncMod { .. } => Ok(())
+        }
+    }
+}
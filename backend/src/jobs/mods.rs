@@ -0,0 +1,140 @@
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::metadata::ModMetadata;
+use crate::response::ApiResult;
+
+use super::{Job, JobContext, JobError};
+
+/// The most mods which will be queued to be refreshed at once, the rest will
+/// be picked up on the following refresh
+const REFRESH_BATCH_SIZE: i64 = 100;
+
+/// Looks up a mod on each configured platform and records its name, icon,
+/// links and supported loaders and versions.
+///
+/// Names and slugs which have already been set, either by a moderator or an
+/// approved proposal, are kept as is and are used to look the mod up instead
+/// of its namespace. Mods which cannot be found anywhere are still marked as
+/// synced so that they are not looked up again until the next refresh, the
+/// Modrinth id of a mod is kept unless a different one is found.
+pub async fn sync_mod(ctx: &JobContext, mod_id: Uuid) -> Result<(), JobError> {
+    let Some(current) = sqlx::query!(
+        r#"
+        select
+            mod_slug, mod_name,
+            modrinth_slug, curseforge_slug
+        from
+            mods
+        where
+            mod_id = $1
+        "#,
+        mod_id
+    )
+    .fetch_optional(&ctx.pool)
+    .await? else {
+        return Ok(());
+    };
+
+    let known = ModMetadata {
+        mod_name: current.mod_name,
+        modrinth_slug: current.modrinth_slug,
+        curseforge_slug: current.curseforge_slug,
+        ..Default::default()
+    };
+
+    let mut found = ModMetadata::default();
+
+    for source in ctx.sources.iter() {
+        if let Some(metadata) = source.resolve(&current.mod_slug, &known).await? {
+            found.merge(metadata);
+        }
+    }
+
+    // Slugs are unique so are only filled in where no other mod already uses
+    // them, for instance when two namespaces belong to the same project
+    sqlx::query!(
+        r#"
+        update mods
+            set
+                mod_name = coalesce(mod_name, $1),
+                modrinth_slug = coalesce(modrinth_slug, (
+                    select $2::text
+                    where not exists (select 1 from mods where modrinth_slug = $2)
+                )),
+                curseforge_slug = coalesce(curseforge_slug, (
+                    select $3::int
+                    where not exists (select 1 from mods where curseforge_slug = $3)
+                )),
+                modrinth_id = coalesce((
+                    select $4::text
+                    where not exists (select 1 from mods where modrinth_id = $4 and mod_id != $11)
+                ), modrinth_id),
+                icon_url = $5,
+                source_url = $6,
+                issues_url = $7,
+                wiki_url = $8,
+                loaders = $9,
+                game_versions = $10,
+                last_synced_at = now()
+            where
+                mod_id = $11
+        "#,
+        found.mod_name,
+        found.modrinth_slug,
+        found.curseforge_slug,
+        found.modrinth_id,
+        found.icon_url,
+        found.source_url,
+        found.issues_url,
+        found.wiki_url,
+        &found.loaders[..],
+        &found.game_versions[..],
+        mod_id
+    )
+    .execute(&ctx.pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Queues mods which have never been synced, or were last synced more than
+/// the given number of hours ago, to be synced again. Mods which are already
+/// waiting to be synced are skipped, as syncing is safe to repeat it does not
+/// matter if several processes queue the same mod at once
+pub async fn enqueue_stale_mods(pool: &PgPool, refresh_interval: u64) -> ApiResult<usize> {
+    let mut transaction = pool.begin().await?;
+
+    let mod_ids = sqlx::query_scalar!(
+        r#"
+        select mod_id
+        from mods
+        where
+            (last_synced_at is null or last_synced_at < now() - make_interval(hours => $1))
+            and not exists (
+                select 1
+                from jobs
+                where
+                    status in ('pending', 'running')
+                    and payload->>'kind' = 'sync_mod'
+                    and payload->>'mod_id' = mods.mod_id::text
+            )
+        order by last_synced_at nulls first
+        limit $2
+        "#,
+        refresh_interval as i32,
+        REFRESH_BATCH_SIZE
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+
+    for mod_id in &mod_ids {
+        Job::SyncMod { mod_id: *mod_id }
+            .enqueue(&mut *transaction, None)
+            .await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(mod_ids.len())
+}
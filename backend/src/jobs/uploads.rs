@@ -0,0 +1,359 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::storage;
+use crate::storage::images::{self, ImageFormat, ImageSize};
+use crate::storage::blocks::{self, SuggestedTag};
+use crate::storage::{dependencies, upload};
+
+use super::JobError;
+
+/// What was uploaded by a `Job::ProcessUpload`, this decides what needs to be
+/// cleaned up if the upload cannot be processed
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadKind {
+    /// A new schematic along with its images and files
+    #[default]
+    Schematic,
+    /// Images added to an existing schematic
+    Images,
+    /// Schematic files added to an existing schematic
+    Files
+}
+
+/// What was found while processing a new schematic, this is stored as the
+/// result of its job as the files are only read once they have been validated
+#[derive(Serialize, Debug)]
+pub struct UploadReport {
+    pub compatibility: BlockCompatibility,
+    /// Tags which may apply to the schematic based on the blocks it uses, see
+    /// `GET /api/v1/schematics/{id}/tag-suggestions`
+    pub suggested_tags: Vec<SuggestedTag>
+}
+
+/// Warnings about the Create blocks used by a schematic, these are only given
+/// for versions of Create where the blocks they contain are known
+#[derive(Serialize, Debug)]
+pub struct BlockCompatibility {
+    /// Create blocks which are used but do not exist within the selected Create
+    /// version, such as blocks which were renamed or added in later versions
+    pub unknown_blocks: Vec<String>,
+    /// The ids of the Create versions which contain every Create block used
+    pub compatible_create_versions: Vec<i32>
+}
+
+/// Encodes the images and compresses the schematic files staged within the
+/// given pending directory, recording their details and the mods they depend
+/// on, then publishes the schematic if it was waiting on them. New schematics
+/// are reported on once their files have been read, see `UploadReport`.
+///
+/// If the pending directory no longer exists then it has already been
+/// processed by a previous attempt. If the schematic has been deleted the job
+/// fails without being retried.
+pub async fn process_upload(
+    pool: &PgPool,
+    schematic_id: Uuid,
+    directory: &str,
+    kind: UploadKind
+) -> Result<Option<UploadReport>, JobError> {
+    let pending = PathBuf::from(storage::PENDING_PATH).join(directory);
+
+    if !tokio::fs::try_exists(&pending).await? {
+        return Ok(None);
+    }
+
+    // Checked up front as well as once processed so that the work isn't done
+    // at all for schematics which have already been deleted
+    lock_schematic(pool, schematic_id).await?;
+
+    let location = pending.clone();
+
+    let processed = tokio::task::spawn_blocking(move || upload::process_pending(&location))
+        .await
+        .map_err(anyhow::Error::new)??;
+
+    let mut transaction = pool.begin().await?;
+
+    // The schematic is locked until the files have been moved into place, this
+    // way it cannot be deleted part way through leaving the files behind
+    lock_schematic(&mut *transaction, schematic_id).await?;
+
+    let images = &processed.images;
+    let file_names: Vec<String> = images.iter().map(|(file_name, _)| file_name.clone()).collect();
+    let widths: Vec<i32> = images.iter().map(|(_, metadata)| metadata.width as i32).collect();
+    let heights: Vec<i32> = images.iter().map(|(_, metadata)| metadata.height as i32).collect();
+    let blurhashes: Vec<String> = images.iter().map(|(_, metadata)| metadata.blurhash.clone()).collect();
+
+    sqlx::query!(
+        r#"
+        update schematic_images
+            set width = image.width,
+                height = image.height,
+                blurhash = image.blurhash
+            from unnest($2::text[], $3::int[], $4::int[], $5::text[])
+                as image(file_name, width, height, blurhash)
+            where schematic_images.schematic_id = $1
+            and schematic_images.file_name = image.file_name
+        "#,
+        schematic_id,
+        &file_names[..],
+        &widths[..],
+        &heights[..],
+        &blurhashes[..]
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    let files = &processed.files;
+    let file_names: Vec<String> = files.iter().map(|(file_name, _)| file_name.clone()).collect();
+    let file_sizes: Vec<i32> = files.iter().map(|(_, metadata)| metadata.file_size).collect();
+    let fingerprints: Vec<String> = files.iter().map(|(_, metadata)| metadata.fingerprint.clone()).collect();
+    let previews: Vec<Option<String>> = files.iter()
+        .map(|(file_name, metadata)| metadata.preview.as_ref().map(|_| upload::preview_file_name(file_name)))
+        .collect();
+    let schematics: Vec<_> = files.iter().map(|(_, metadata)| metadata.schematic.as_ref()).collect();
+    let widths: Vec<Option<i32>> = schematics.iter().map(|schematic| schematic.map(|s| s.width)).collect();
+    let heights: Vec<Option<i32>> = schematics.iter().map(|schematic| schematic.map(|s| s.height)).collect();
+    let lengths: Vec<Option<i32>> = schematics.iter().map(|schematic| schematic.map(|s| s.length)).collect();
+    let block_counts: Vec<Option<i32>> = schematics.iter().map(|schematic| schematic.map(|s| s.block_count)).collect();
+
+    sqlx::query!(
+        r#"
+        update schematic_files
+            set file_size = file.file_size,
+                width = file.width,
+                height = file.height,
+                length = file.length,
+                block_count = file.block_count,
+                fingerprint = file.fingerprint,
+                preview = file.preview
+            from unnest($2::text[], $3::int[], $4::int[], $5::int[], $6::int[], $7::int[], $8::text[], $9::text[])
+                as file(file_name, file_size, width, height, length, block_count, fingerprint, preview)
+            where schematic_files.schematic_id = $1
+            and schematic_files.file_name = file.file_name
+        "#,
+        schematic_id,
+        &file_names[..],
+        &file_sizes[..],
+        &widths[..] as &[Option<i32>],
+        &heights[..] as &[Option<i32>],
+        &lengths[..] as &[Option<i32>],
+        &block_counts[..] as &[Option<i32>],
+        &fingerprints[..],
+        &previews[..] as &[Option<String>]
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    for (file_name, metadata) in files {
+        let mods: Vec<String> = metadata.schematic.as_ref()
+            .map(|schematic| schematic.mods.iter().cloned().collect())
+            .unwrap_or_default();
+
+        dependencies::store_file_dependencies(&mut transaction, schematic_id, file_name, &mods).await?;
+
+        let no_blocks = HashMap::new();
+        let block_counts = metadata.schematic.as_ref()
+            .map_or(&no_blocks, |schematic| &schematic.block_counts);
+
+        blocks::store_file_blocks(&mut transaction, schematic_id, file_name, block_counts).await?;
+    }
+
+    dependencies::update_schematic_dependencies(&mut transaction, schematic_id).await?;
+
+    let report = match kind {
+        UploadKind::Schematic => Some(report_upload(&mut transaction, schematic_id).await?),
+        UploadKind::Images | UploadKind::Files => None
+    };
+
+    sqlx::query!(
+        r#"
+        update schematics
+            set status = 'published'
+            where schematic_id = $1
+            and status = 'processing'
+        "#,
+        schematic_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    let location = pending.clone();
+
+    tokio::task::spawn_blocking(move || upload::publish_processed(&location, &schematic_id))
+        .await
+        .map_err(anyhow::Error::new)??;
+
+    transaction.commit().await?;
+
+    tokio::fs::remove_dir_all(pending).await?;
+
+    Ok(report)
+}
+
+/// Checks the blocks used across the files of a schematic against its Create
+/// versions and suggests tags from them, this must be called once the blocks
+/// of each file have been stored
+async fn report_upload(conn: &mut PgConnection, schematic_id: Uuid) -> Result<UploadReport, JobError> {
+    let create_versions = sqlx::query_scalar!(
+        r#"select create_version_id from schematic_create_versions where schematic_id = $1"#,
+        schematic_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let block_counts = blocks::schematic_block_counts(&mut *conn, schematic_id).await?;
+    let used_blocks: HashSet<String> = block_counts.keys().cloned().collect();
+
+    let check = blocks::check_blocks(&mut *conn, &create_versions, &used_blocks).await?;
+    let suggested_tags = blocks::suggest_tags(&mut *conn, schematic_id, &block_counts).await?;
+
+    Ok(UploadReport {
+        compatibility: BlockCompatibility {
+            unknown_blocks: check.unknown_blocks,
+            compatible_create_versions: check.compatible_versions
+        },
+        suggested_tags
+    })
+}
+
+/// Cleans up after an upload which could not be processed. A new schematic is
+/// marked as failed and the author will need to upload it again, images or
+/// files added to an existing schematic are removed from it. Any of the files
+/// which made it into the upload directory are removed along with the files
+/// that were staged
+pub async fn fail_upload(
+    pool: &PgPool,
+    schematic_id: Uuid,
+    directory: &str,
+    kind: UploadKind
+) -> Result<(), sqlx::Error> {
+    let pending = PathBuf::from(storage::PENDING_PATH).join(directory);
+    let (image_names, file_names) = upload::staged_file_names(&pending);
+
+    let mut transaction = pool.begin().await?;
+
+    match kind {
+        UploadKind::Schematic => {
+            sqlx::query!(
+                r#"
+                update schematics
+                    set status = 'failed'
+                    where schematic_id = $1
+                    and status = 'processing'
+                "#,
+                schematic_id
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
+        UploadKind::Images => {
+            sqlx::query!(
+                r#"
+                delete from schematic_images
+                    where schematic_id = $1
+                    and file_name = any($2)
+                "#,
+                schematic_id,
+                &image_names[..]
+            )
+            .execute(&mut *transaction)
+            .await?;
+
+            // The first image added to a schematic becomes its cover
+            sqlx::query!(
+                r#"
+                update schematic_images
+                    set is_cover = true
+                    where schematic_id = $1
+                    and file_name = (
+                        select file_name
+                        from schematic_images
+                        where schematic_id = $1
+                        order by position
+                        limit 1
+                    )
+                    and not exists (
+                        select 1
+                        from schematic_images
+                        where schematic_id = $1 and is_cover
+                    )
+                "#,
+                schematic_id
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
+        UploadKind::Files => {
+            sqlx::query!(
+                r#"
+                delete from schematic_files
+                    where schematic_id = $1
+                    and file_name = any($2)
+                "#,
+                schematic_id,
+                &file_names[..]
+            )
+            .execute(&mut *transaction)
+            .await?;
+
+            dependencies::update_schematic_dependencies(&mut transaction, schematic_id).await?;
+        }
+    }
+
+    transaction.commit().await?;
+
+    let image_path = storage::schematic_image_path(&schematic_id);
+    let file_path = storage::schematic_file_path(&schematic_id);
+
+    let mut published = Vec::new();
+
+    for file_name in &image_names {
+        for size in ImageSize::ALL {
+            for format in ImageFormat::ALL {
+                published.push(image_path.join(images::variant_file_name(file_name, size, format)));
+            }
+        }
+    }
+
+    for file_name in &file_names {
+        published.push(file_path.join(file_name));
+        published.push(file_path.join(upload::preview_file_name(file_name)));
+    }
+
+    for path in published {
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                tracing::warn!("Failed to remove processed upload {:?}: {:?}", path, e);
+            }
+            _ => {}
+        }
+    }
+
+    if let Err(e) = tokio::fs::remove_dir_all(pending).await {
+        tracing::warn!("Failed to remove pending upload: {:?}", e);
+    }
+
+    Ok(())
+}
+
+/// Locks a schematic for the rest of the transaction, failing the job without
+/// retrying it if the schematic no longer exists
+async fn lock_schematic<'a, E>(executor: E, schematic_id: Uuid) -> Result<(), JobError>
+where
+    E: sqlx::Executor<'a, Database = sqlx::Postgres>
+{
+    sqlx::query!(
+        r#"select schematic_id from schematics where schematic_id = $1 for update"#,
+        schematic_id
+    )
+    .fetch_optional(executor)
+    .await?
+    .ok_or_else(|| JobError::Fatal(anyhow::anyhow!("Schematic {schematic_id} no longer exists")))?;
+
+    Ok(())
+}
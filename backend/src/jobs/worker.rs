@@ -0,0 +1,293 @@
+use std::time::Duration;
+
+use clap::Args;
+use sqlx::PgPool;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::database::postgres;
+use crate::database::postgres::DatabaseArguments;
+use crate::metadata::ModMetadataArguments;
+
+use super::{mods, Job, JobContext, JobError};
+
+/// How long a job can be running for before it is assumed that the worker
+/// running it has stopped and it can be picked up by another worker
+const LOCK_TIMEOUT_SECONDS: f64 = 15.0 * 60.0;
+
+/// The delay before the first retry of a failed job, this is doubled for
+/// each subsequent attempt
+const RETRY_DELAY_SECONDS: f64 = 10.0;
+
+/// How often to check for mods whose details are out of date, see
+/// `mods::enqueue_stale_mods`
+const MOD_REFRESH_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Args, Debug, Clone)]
+pub struct WorkerArguments {
+    #[arg(help = "The number of jobs to run at once, set to 0 to disable running jobs")]
+    #[arg(env = "WORKERS", long = "workers")]
+    #[arg(default_value = "2")]
+    pub workers: usize,
+
+    #[arg(help = "How many seconds to wait before checking for new jobs when there are none")]
+    #[arg(env = "WORKER_POLL_INTERVAL", long = "worker_poll_interval")]
+    #[arg(default_value = "5")]
+    pub poll_interval: u64,
+
+    #[command(next_help_heading = "Mod metadata")]
+    #[command(flatten)]
+    pub metadata: ModMetadataArguments,
+}
+
+#[derive(Args, Debug)]
+pub struct WorkerCommandArguments {
+    #[command(next_help_heading = "Database")]
+    #[command(flatten)]
+    pub postgres: DatabaseArguments,
+
+    #[command(next_help_heading = "Workers")]
+    #[command(flatten)]
+    pub workers: WorkerArguments,
+}
+
+struct ClaimedJob {
+    job_id: Uuid,
+    payload: serde_json::Value,
+    attempts: i32,
+    max_attempts: i32
+}
+
+/// Runs workers without serving the api until the process is stopped, this
+/// allows for heavier processing to be moved onto separate machines. When doing
+/// so the server should be started with `--workers 0`
+pub async fn run(
+    WorkerCommandArguments {
+        postgres,
+        workers
+    }: WorkerCommandArguments
+) -> Result<(), anyhow::Error> {
+    let pool = postgres::connect(postgres).await?;
+    let count = workers.workers;
+    let handles = spawn(pool, workers)?;
+
+    tracing::info!("Started {} workers", count);
+
+    tokio::signal::ctrl_c().await?;
+
+    for handle in handles {
+        handle.abort();
+    }
+
+    Ok(())
+}
+
+/// Starts the requested number of workers in the background, each worker runs
+/// a single job at a time. Alongside these mods are periodically queued to have
+/// their details refreshed
+pub fn spawn(
+    pool: PgPool,
+    WorkerArguments {
+        workers,
+        poll_interval,
+        metadata
+    }: WorkerArguments
+) -> Result<Vec<JoinHandle<()>>, anyhow::Error> {
+    if workers == 0 {
+        return Ok(Vec::new());
+    }
+
+    let poll_interval = Duration::from_secs(poll_interval);
+
+    let ctx = JobContext {
+        sources: metadata.build_sources()?,
+        pool
+    };
+
+    let mut handles: Vec<JoinHandle<()>> = (0..workers)
+        .map(|_| tokio::spawn(work(ctx.clone(), poll_interval)))
+        .collect();
+
+    handles.push(tokio::spawn(refresh_mods(ctx.pool.clone(), metadata.refresh_interval)));
+
+    Ok(handles)
+}
+
+async fn work(ctx: JobContext, poll_interval: Duration) {
+    loop {
+        match claim_job(&ctx.pool).await {
+            Ok(Some(job)) => {
+                if let Err(e) = run_job(&ctx, job).await {
+                    tracing::error!("Failed to update job: {:?}", e);
+                }
+            }
+            Ok(None) => {
+                if let Err(e) = fail_abandoned_jobs(&ctx.pool).await {
+                    tracing::error!("Failed to fail abandoned jobs: {:?}", e);
+                }
+
+                tokio::time::sleep(poll_interval).await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to claim job: {:?}", e);
+                tokio::time::sleep(poll_interval).await;
+            }
+        }
+    }
+}
+
+async fn refresh_mods(pool: PgPool, refresh_interval: u64) {
+    let mut interval = tokio::time::interval(MOD_REFRESH_CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        match mods::enqueue_stale_mods(&pool, refresh_interval).await {
+            Ok(0) => {}
+            Ok(queued) => tracing::info!("Queued {} mods to be refreshed", queued),
+            Err(e) => tracing::error!("Failed to queue mods to be refreshed: {:?}", e)
+        }
+    }
+}
+
+async fn claim_job(pool: &PgPool) -> Result<Option<ClaimedJob>, sqlx::Error> {
+    sqlx::query_as!(
+        ClaimedJob,
+        r#"
+        update jobs
+            set
+                status = 'running',
+                attempts = attempts + 1,
+                locked_at = now()
+            where job_id = (
+                select job_id
+                from jobs
+                where
+                    (status = 'pending' and run_at <= now())
+                    or (
+                        status = 'running'
+                        and locked_at < now() - make_interval(secs => $1)
+                        and attempts < max_attempts
+                    )
+                order by run_at
+                for update skip locked
+                limit 1
+            )
+            returning
+                job_id,
+                payload,
+                attempts,
+                max_attempts
+        "#,
+        LOCK_TIMEOUT_SECONDS
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Jobs whose worker stopped while running their last attempt are never
+/// claimed again, these are failed instead so that they are cleaned up
+async fn fail_abandoned_jobs(pool: &PgPool) -> Result<(), sqlx::Error> {
+    let abandoned = sqlx::query!(
+        r#"
+        update jobs
+            set
+                status = 'failed',
+                locked_at = null,
+                last_error = 'The worker running this job stopped'
+            where status = 'running'
+            and locked_at < now() - make_interval(secs => $1)
+            and attempts >= max_attempts
+            returning job_id, payload
+        "#,
+        LOCK_TIMEOUT_SECONDS
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for row in abandoned {
+        tracing::error!(job_id = %row.job_id, "Job failed, its worker stopped while running it");
+
+        if let Ok(job) = serde_json::from_value::<Job>(row.payload) {
+            job.on_failure(pool).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_job(ctx: &JobContext, claimed: ClaimedJob) -> Result<(), sqlx::Error> {
+    let pool = &ctx.pool;
+    let job = serde_json::from_value::<Job>(claimed.payload);
+
+    let result = match &job {
+        Ok(job) => job.run(ctx).await,
+        Err(e) => Err(JobError::Fatal(anyhow::anyhow!("Invalid job payload: {e}")))
+    };
+
+    match result {
+        Ok(result) => {
+            sqlx::query!(
+                r#"
+                update jobs
+                    set
+                        status = 'completed',
+                        locked_at = null,
+                        last_error = null,
+                        result = $1
+                    where job_id = $2
+                "#,
+                result,
+                claimed.job_id
+            )
+            .execute(pool)
+            .await?;
+        }
+        Err(JobError::Retry(e)) if claimed.attempts < claimed.max_attempts => {
+            tracing::warn!(job_id = %claimed.job_id, "Job failed, retrying: {:?}", e);
+
+            let delay = RETRY_DELAY_SECONDS * 2f64.powi(claimed.attempts - 1);
+
+            sqlx::query!(
+                r#"
+                update jobs
+                    set
+                        status = 'pending',
+                        locked_at = null,
+                        last_error = $1,
+                        run_at = now() + make_interval(secs => $2)
+                    where job_id = $3
+                "#,
+                e.to_string(),
+                delay,
+                claimed.job_id
+            )
+            .execute(pool)
+            .await?;
+        }
+        Err(JobError::Retry(e) | JobError::Fatal(e)) => {
+            tracing::error!(job_id = %claimed.job_id, "Job failed: {:?}", e);
+
+            sqlx::query!(
+                r#"
+                update jobs
+                    set
+                        status = 'failed',
+                        locked_at = null,
+                        last_error = $1
+                    where job_id = $2
+                "#,
+                e.to_string(),
+                claimed.job_id
+            )
+            .execute(pool)
+            .await?;
+
+            if let Ok(job) = &job {
+                job.on_failure(pool).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
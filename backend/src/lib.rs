@@ -10,6 +10,8 @@ pub mod cli;
 pub mod database;
 pub mod error;
 pub mod helpers;
+pub mod jobs;
+pub mod metadata;
 pub mod middleware;
 pub mod models;
 pub mod redirect;
@@ -0,0 +1,353 @@
+use reqwest::{Client, StatusCode};
+use url::Url;
+
+use super::{ModMetadata, ModMetadataSource};
+
+/// The id of Minecraft within the CurseForge api
+const MINECRAFT_GAME_ID: &str = "432";
+
+/// The id of the mods category within Minecraft, this excludes modpacks,
+/// resource packs and so on which may share a slug with a mod
+const MODS_CLASS_ID: &str = "6";
+
+/// Looks up mods using the CurseForge api, see https://docs.curseforge.com.
+/// This requires an api key, mods are found by searching for a project with
+/// the namespace as its slug
+pub struct CurseForgeClient {
+    client: Client,
+    base_url: String,
+    api_key: String
+}
+
+#[derive(Deserialize, Debug)]
+struct CurseForgeResponse<T> {
+    data: T
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct CurseForgeMod {
+    id: i32,
+    name: String,
+    links: Option<CurseForgeLinks>,
+    logo: Option<CurseForgeLogo>,
+    #[serde(default)]
+    latest_files_indexes: Vec<CurseForgeFileIndex>
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct CurseForgeLinks {
+    wiki_url: Option<String>,
+    issues_url: Option<String>,
+    source_url: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+struct CurseForgeLogo {
+    url: String
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct CurseForgeFileIndex {
+    game_version: String,
+    mod_loader: Option<i32>
+}
+
+impl CurseForgeClient {
+    pub fn new(client: Client, base_url: &str, api_key: &str) -> Self {
+        Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: api_key.to_string()
+        }
+    }
+
+    fn url(&self, segments: &[&str]) -> Result<Url, anyhow::Error> {
+        let mut url = Url::parse(&self.base_url)?;
+
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("Invalid CurseForge api url {}", self.base_url))?
+            .extend(segments);
+
+        Ok(url)
+    }
+
+    async fn fetch_by_id(&self, id: i32) -> Result<Option<CurseForgeMod>, anyhow::Error> {
+        let response = self.client
+            .get(self.url(&["mods", &id.to_string()])?)
+            .header("x-api-key", &self.api_key)
+            .send()
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let response = response
+            .error_for_status()?
+            .json::<CurseForgeResponse<CurseForgeMod>>()
+            .await?;
+
+        Ok(Some(response.data))
+    }
+
+    async fn search_by_slug(&self, slug: &str) -> Result<Option<CurseForgeMod>, anyhow::Error> {
+        let mut url = self.url(&["mods", "search"])?;
+
+        url.query_pairs_mut()
+            .append_pair("gameId", MINECRAFT_GAME_ID)
+            .append_pair("classId", MODS_CLASS_ID)
+            .append_pair("slug", slug);
+
+        let response = self.client
+            .get(url)
+            .header("x-api-key", &self.api_key)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<CurseForgeResponse<Vec<CurseForgeMod>>>()
+            .await?;
+
+        Ok(response.data.into_iter().next())
+    }
+}
+
+#[poem::async_trait]
+impl ModMetadataSource for CurseForgeClient {
+    async fn resolve(
+        &self,
+        namespace: &str,
+        known: &ModMetadata
+    ) -> Result<Option<ModMetadata>, anyhow::Error> {
+        let found = match known.curseforge_slug {
+            Some(id) => self.fetch_by_id(id).await?,
+            None => self.search_by_slug(namespace).await?
+        };
+
+        let Some(found) = found else {
+            return Ok(None);
+        };
+
+        let mut loaders: Vec<String> = Vec::new();
+        let mut game_versions: Vec<String> = Vec::new();
+
+        for index in found.latest_files_indexes {
+            if let Some(loader) = index.mod_loader.and_then(loader_name) {
+                if !loaders.iter().any(|l| l == loader) {
+                    loaders.push(loader.to_string());
+                }
+            }
+
+            if !game_versions.contains(&index.game_version) {
+                game_versions.push(index.game_version);
+            }
+        }
+
+        // Links which have not been set are returned as empty strings
+        let links = found.links;
+        let link = |url: Option<&String>| url.filter(|url| !url.is_empty()).cloned();
+
+        Ok(Some(ModMetadata {
+            mod_name: Some(found.name),
+            modrinth_id: None,
+            modrinth_slug: None,
+            curseforge_slug: Some(found.id),
+            icon_url: found.logo.map(|logo| logo.url),
+            source_url: link(links.as_ref().and_then(|links| links.source_url.as_ref())),
+            issues_url: link(links.as_ref().and_then(|links| links.issues_url.as_ref())),
+            wiki_url: link(links.as_ref().and_then(|links| links.wiki_url.as_ref())),
+            loaders,
+            game_versions
+        }))
+    }
+}
+
+/// Converts CurseForge's mod loader ids into the names used by Modrinth
+fn loader_name(id: i32) -> Option<&'static str> {
+    match id {
+        1 => Some("forge"),
+        4 => Some("fabric"),
+        5 => Some("quilt"),
+        6 => Some("neoforge"),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::http::StatusCode;
+    use poem::web::{Json, Path, Query};
+    use poem::{get, handler, IntoResponse, Request, Response, Route};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::metadata::mock;
+
+    const API_KEY: &str = "test-key";
+
+    fn create(id: i32) -> Value {
+        json!({
+            "id": id,
+            "name": "Create",
+            "links": {
+                "websiteUrl": "https://www.curseforge.com/minecraft/mc-mods/create",
+                "wikiUrl": "https://wiki.createmod.net",
+                "issuesUrl": "",
+                "sourceUrl": "https://github.com/Creators-of-Create/Create"
+            },
+            "logo": { "url": "https://media.forgecdn.net/create.png" },
+            "latestFilesIndexes": [
+                { "gameVersion": "1.20.1", "modLoader": 1 },
+                { "gameVersion": "1.20.1", "modLoader": 4 },
+                { "gameVersion": "1.21.1", "modLoader": 6 },
+                { "gameVersion": "1.21.1", "modLoader": 1 },
+                { "gameVersion": "1.19.2", "modLoader": 99 },
+                { "gameVersion": "1.18.2", "modLoader": null }
+            ]
+        })
+    }
+
+    #[derive(Deserialize)]
+    struct SearchQuery {
+        #[serde(rename = "gameId")]
+        game_id: String,
+        #[serde(rename = "classId")]
+        class_id: String,
+        slug: String
+    }
+
+    #[handler]
+    fn search(request: &Request, Query(query): Query<SearchQuery>) -> Response {
+        if request.header("x-api-key") != Some(API_KEY) {
+            return StatusCode::FORBIDDEN.into_response();
+        }
+
+        assert_eq!(query.game_id, MINECRAFT_GAME_ID);
+        assert_eq!(query.class_id, MODS_CLASS_ID);
+
+        let found = match query.slug.as_str() {
+            "create" => vec![create(328085)],
+            "unlinked" => vec![json!({ "id": 1, "name": "Unlinked" })],
+            "broken" => return StatusCode::BAD_GATEWAY.into_response(),
+            _ => vec![]
+        };
+
+        Json(json!({ "data": found })).into_response()
+    }
+
+    #[handler]
+    fn fetch(request: &Request, Path(id): Path<i32>) -> Response {
+        if request.header("x-api-key") != Some(API_KEY) {
+            return StatusCode::FORBIDDEN.into_response();
+        }
+
+        match id {
+            328085 => Json(json!({ "data": create(id) })).into_response(),
+            500 => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            _ => StatusCode::NOT_FOUND.into_response()
+        }
+    }
+
+    async fn client() -> CurseForgeClient {
+        let app = Route::new()
+            .at("/mods/search", get(search))
+            .at("/mods/:id", get(fetch));
+
+        let base_url = mock::serve(app).await;
+
+        CurseForgeClient::new(Client::new(), &base_url, API_KEY)
+    }
+
+    fn known(id: i32) -> ModMetadata {
+        ModMetadata {
+            curseforge_slug: Some(id),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn resolves_mod_by_namespace() {
+        let found = client().await
+            .resolve("create", &ModMetadata::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(found.mod_name.as_deref(), Some("Create"));
+        assert_eq!(found.curseforge_slug, Some(328085));
+        assert_eq!(found.modrinth_id, None);
+        assert_eq!(found.icon_url.as_deref(), Some("https://media.forgecdn.net/create.png"));
+        assert_eq!(found.source_url.as_deref(), Some("https://github.com/Creators-of-Create/Create"));
+        assert_eq!(found.wiki_url.as_deref(), Some("https://wiki.createmod.net"));
+    }
+
+    #[tokio::test]
+    async fn maps_loaders_to_modrinth_names() {
+        let found = client().await
+            .resolve("create", &ModMetadata::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(found.loaders, ["forge", "fabric", "neoforge"]);
+        assert_eq!(found.game_versions, ["1.20.1", "1.21.1", "1.19.2", "1.18.2"]);
+    }
+
+    #[tokio::test]
+    async fn empty_links_are_missing() {
+        let found = client().await
+            .resolve("create", &ModMetadata::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(found.issues_url, None);
+
+        let unlinked = client().await
+            .resolve("unlinked", &ModMetadata::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(unlinked.source_url, None);
+        assert_eq!(unlinked.icon_url, None);
+        assert!(unlinked.loaders.is_empty());
+    }
+
+    #[tokio::test]
+    async fn resolves_known_mod_by_id() {
+        let found = client().await
+            .resolve("not-the-slug", &known(328085))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(found.curseforge_slug, Some(328085));
+    }
+
+    #[tokio::test]
+    async fn missing_mod_is_not_found() {
+        let client = client().await;
+
+        assert!(client.resolve("missing", &ModMetadata::default()).await.unwrap().is_none());
+        assert!(client.resolve("create", &known(404)).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let client = client().await;
+
+        assert!(client.resolve("broken", &ModMetadata::default()).await.is_err());
+        assert!(client.resolve("create", &known(500)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn invalid_api_key_is_an_error() {
+        let base_url = mock::serve(Route::new().at("/mods/search", get(search))).await;
+        let client = CurseForgeClient::new(Client::new(), &base_url, "wrong-key");
+
+        assert!(client.resolve("create", &ModMetadata::default()).await.is_err());
+    }
+}
@@ -0,0 +1,22 @@
+use poem::listener::{Acceptor, Listener, TcpListener};
+use poem::{Endpoint, Server};
+
+/// Serves the given endpoint on an unused local port for as long as the test
+/// runs, returning the base url to point a client at
+pub async fn serve<E>(endpoint: E) -> String
+where
+    E: Endpoint + 'static
+{
+    let acceptor = TcpListener::bind("127.0.0.1:0")
+        .into_acceptor()
+        .await
+        .expect("Failed to bind mock server");
+
+    let addr = *acceptor.local_addr()[0]
+        .as_socket_addr()
+        .expect("Mock server is not listening on a socket");
+
+    tokio::spawn(Server::new_with_acceptor(acceptor).run(endpoint));
+
+    format!("http://{addr}")
+}
@@ -0,0 +1,171 @@
+use std::sync::Arc;
+
+use clap::Args;
+
+use self::curseforge::CurseForgeClient;
+use self::modrinth::ModrinthClient;
+
+pub mod curseforge;
+pub mod modpacks;
+pub mod modrinth;
+
+#[cfg(test)]
+mod mock;
+
+/// The user agent sent with every request to the mod platforms, Modrinth asks
+/// for this to identify the project making requests
+const USER_AGENT: &str = "Create-Schematics (https://github.com/Create-Schematics)";
+
+#[derive(Args, Debug, Clone)]
+pub struct ModMetadataArguments {
+    #[arg(help = "The base url of the Modrinth api used to look up mods")]
+    #[arg(env = "MODRINTH_API_URL", long = "modrinth_api_url")]
+    #[arg(default_value = "https://api.modrinth.com/v2")]
+    pub modrinth_api_url: String,
+
+    #[arg(help = "The base url of the CurseForge api used to look up mods")]
+    #[arg(env = "CURSEFORGE_API_URL", long = "curseforge_api_url")]
+    #[arg(default_value = "https://api.curseforge.com/v1")]
+    pub curseforge_api_url: String,
+
+    #[arg(help = "The key used to access the CurseForge api, CurseForge is not searched without one")]
+    #[arg(env = "CURSEFORGE_API_KEY", long = "curseforge_api_key")]
+    pub curseforge_api_key: Option<String>,
+
+    #[arg(help = "How many hours to wait before fetching the details of a mod again")]
+    #[arg(env = "MOD_REFRESH_INTERVAL", long = "mod_refresh_interval")]
+    #[arg(default_value = "24")]
+    pub refresh_interval: u64,
+}
+
+/// What is currently known about a mod, used both to look it up on each
+/// platform and to record what was found
+#[derive(Debug, Default, Clone)]
+pub struct ModMetadata {
+    pub mod_name: Option<String>,
+    pub modrinth_id: Option<String>,
+    pub modrinth_slug: Option<String>,
+    pub curseforge_slug: Option<i32>,
+    pub icon_url: Option<String>,
+    pub source_url: Option<String>,
+    pub issues_url: Option<String>,
+    pub wiki_url: Option<String>,
+    pub loaders: Vec<String>,
+    pub game_versions: Vec<String>,
+}
+
+/// A platform which mods can be looked up on, implementations should return
+/// `None` when no matching mod exists and only error when the platform could
+/// not be reached or responded unexpectedly, in which case the lookup will
+/// be retried later.
+#[poem::async_trait]
+pub trait ModMetadataSource: Send + Sync {
+    /// Finds the mod with the given namespace, as used within block ids. If
+    /// the slug or id of the mod on this platform is already known then that
+    /// is used instead of guessing from the namespace
+    async fn resolve(
+        &self,
+        namespace: &str,
+        known: &ModMetadata
+    ) -> Result<Option<ModMetadata>, anyhow::Error>;
+}
+
+pub type ModMetadataSources = Arc<[Box<dyn ModMetadataSource>]>;
+
+impl ModMetadataArguments {
+    /// Builds a client for each platform which is configured, in the order in
+    /// which their results are preferred
+    pub fn build_sources(&self) -> Result<ModMetadataSources, anyhow::Error> {
+        let client = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .build()?;
+
+        let mut sources: Vec<Box<dyn ModMetadataSource>> = vec![
+            Box::new(ModrinthClient::new(client.clone(), &self.modrinth_api_url))
+        ];
+
+        if let Some(api_key) = &self.curseforge_api_key {
+            sources.push(Box::new(CurseForgeClient::new(client, &self.curseforge_api_url, api_key)));
+        }
+
+        Ok(sources.into())
+    }
+}
+
+impl ModMetadata {
+    /// Fills in anything missing from this with what was found on another
+    /// platform, supported loaders and versions are combined as a mod may
+    /// publish different versions to each
+    pub fn merge(&mut self, other: ModMetadata) {
+        self.mod_name = self.mod_name.take().or(other.mod_name);
+        self.modrinth_id = self.modrinth_id.take().or(other.modrinth_id);
+        self.modrinth_slug = self.modrinth_slug.take().or(other.modrinth_slug);
+        self.curseforge_slug = self.curseforge_slug.or(other.curseforge_slug);
+        self.icon_url = self.icon_url.take().or(other.icon_url);
+        self.source_url = self.source_url.take().or(other.source_url);
+        self.issues_url = self.issues_url.take().or(other.issues_url);
+        self.wiki_url = self.wiki_url.take().or(other.wiki_url);
+
+        for loader in other.loaders {
+            if !self.loaders.contains(&loader) {
+                self.loaders.push(loader);
+            }
+        }
+
+        for version in other.game_versions {
+            if !self.game_versions.contains(&version) {
+                self.game_versions.push(version);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_prefers_existing_details() {
+        let mut found = ModMetadata {
+            mod_name: Some("Create".into()),
+            modrinth_id: Some("LNytGWDc".into()),
+            modrinth_slug: Some("create".into()),
+            source_url: Some("https://github.com/Creators-of-Create/Create".into()),
+            ..Default::default()
+        };
+
+        found.merge(ModMetadata {
+            mod_name: Some("Create (CurseForge)".into()),
+            curseforge_slug: Some(328085),
+            source_url: Some("https://example.com".into()),
+            wiki_url: Some("https://wiki.createmod.net".into()),
+            ..Default::default()
+        });
+
+        assert_eq!(found.mod_name.as_deref(), Some("Create"));
+        assert_eq!(found.modrinth_id.as_deref(), Some("LNytGWDc"));
+        assert_eq!(found.modrinth_slug.as_deref(), Some("create"));
+        assert_eq!(found.curseforge_slug, Some(328085));
+        assert_eq!(found.source_url.as_deref(), Some("https://github.com/Creators-of-Create/Create"));
+        assert_eq!(found.wiki_url.as_deref(), Some("https://wiki.createmod.net"));
+        assert_eq!(found.icon_url, None);
+    }
+
+    #[test]
+    fn merge_combines_loaders_and_versions() {
+        let mut found = ModMetadata {
+            loaders: vec!["forge".into(), "fabric".into()],
+            game_versions: vec!["1.20.1".into()],
+            ..Default::default()
+        };
+
+        found.merge(ModMetadata {
+            loaders: vec!["fabric".into(), "neoforge".into()],
+            game_versions: vec!["1.20.1".into(), "1.21.1".into()],
+            ..Default::default()
+        });
+
+        assert_eq!(found.loaders, ["forge", "fabric", "neoforge"]);
+        assert_eq!(found.game_versions, ["1.20.1", "1.21.1"]);
+    }
+}
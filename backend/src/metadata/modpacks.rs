@@ -0,0 +1,89 @@
+use url::Url;
+
+use crate::error::ApiError;
+
+/// The mods included within a modpack as referenced by its manifest, these
+/// are matched against the `modrinth_id` and `curseforge_slug` of known mods
+#[derive(Debug, Default)]
+pub struct ModpackMods {
+    pub modrinth_ids: Vec<String>,
+    pub curseforge_ids: Vec<i32>,
+}
+
+/// The `modrinth.index.json` file within a `.mrpack`, see
+/// https://support.modrinth.com/en/articles/8802351-modrinth-modpack-format-mrpack
+#[derive(Deserialize, Debug)]
+struct ModrinthIndex {
+    files: Vec<ModrinthIndexFile>
+}
+
+#[derive(Deserialize, Debug)]
+struct ModrinthIndexFile {
+    downloads: Vec<String>
+}
+
+/// The `manifest.json` file within a CurseForge modpack export
+#[derive(Deserialize, Debug)]
+struct CurseForgeManifest {
+    files: Vec<CurseForgeManifestFile>
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct CurseForgeManifestFile {
+    #[serde(rename = "projectID")]
+    project_id: i32,
+    #[serde(default = "required_by_default")]
+    required: bool
+}
+
+/// Reads the mods included within either a Modrinth modpack index or a
+/// CurseForge modpack manifest, as these are json files the format is
+/// detected from the shape of the files they list rather than their name.
+///
+/// Files within a Modrinth index are only included where they are downloaded
+/// from Modrinth's cdn as the project id is read from the download url,
+/// optional files within a CurseForge manifest are not included.
+pub fn read_manifest(contents: &[u8]) -> Result<ModpackMods, ApiError> {
+    if let Ok(index) = serde_json::from_slice::<ModrinthIndex>(contents) {
+        let modrinth_ids = index.files.iter()
+            .filter_map(|file| file.downloads.iter().find_map(|url| modrinth_project_id(url)))
+            .collect();
+
+        return Ok(ModpackMods { modrinth_ids, ..Default::default() });
+    }
+
+    if let Ok(manifest) = serde_json::from_slice::<CurseForgeManifest>(contents) {
+        let curseforge_ids = manifest.files.iter()
+            .filter(|file| file.required)
+            .map(|file| file.project_id)
+            .collect();
+
+        return Ok(ModpackMods { curseforge_ids, ..Default::default() });
+    }
+
+    Err(ApiError::unprocessable_entity([
+        ("manifest", "expected a modrinth.index.json or curseforge manifest.json file")
+    ]))
+}
+
+/// Reads the project id from a Modrinth cdn url such as
+/// `https://cdn.modrinth.com/data/{project_id}/versions/{version_id}/{file_name}`
+fn modrinth_project_id(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+
+    if url.host_str() != Some("cdn.modrinth.com") {
+        return None;
+    }
+
+    let mut segments = url.path_segments()?;
+
+    match (segments.next(), segments.next()) {
+        (Some("data"), Some(project_id)) => Some(project_id.to_string()),
+        _ => None
+    }
+}
+
+fn required_by_default() -> bool {
+    true
+}
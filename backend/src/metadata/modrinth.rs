@@ -0,0 +1,188 @@
+use reqwest::{Client, StatusCode};
+use url::Url;
+
+use super::{ModMetadata, ModMetadataSource};
+
+/// Looks up mods using the Modrinth api, see https://docs.modrinth.com/api.
+/// Mod namespaces are usually the same as their project slug on Modrinth
+/// so these are tried directly, projects which aren't mods such as modpacks
+/// or resource packs are ignored.
+pub struct ModrinthClient {
+    client: Client,
+    base_url: String
+}
+
+#[derive(Deserialize, Debug)]
+struct ModrinthProject {
+    id: String,
+    slug: String,
+    title: String,
+    project_type: String,
+    icon_url: Option<String>,
+    source_url: Option<String>,
+    issues_url: Option<String>,
+    wiki_url: Option<String>,
+    #[serde(default)]
+    loaders: Vec<String>,
+    #[serde(default)]
+    game_versions: Vec<String>,
+}
+
+impl ModrinthClient {
+    pub fn new(client: Client, base_url: &str) -> Self {
+        Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string()
+        }
+    }
+}
+
+#[poem::async_trait]
+impl ModMetadataSource for ModrinthClient {
+    async fn resolve(
+        &self,
+        namespace: &str,
+        known: &ModMetadata
+    ) -> Result<Option<ModMetadata>, anyhow::Error> {
+        let slug = known.modrinth_slug.as_deref().unwrap_or(namespace);
+
+        let mut url = Url::parse(&self.base_url)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("Invalid Modrinth api url {}", self.base_url))?
+            .extend(["project", slug]);
+
+        let response = self.client
+            .get(url)
+            .send()
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let project = response
+            .error_for_status()?
+            .json::<ModrinthProject>()
+            .await?;
+
+        if project.project_type != "mod" {
+            return Ok(None);
+        }
+
+        Ok(Some(ModMetadata {
+            mod_name: Some(project.title),
+            modrinth_id: Some(project.id),
+            modrinth_slug: Some(project.slug),
+            curseforge_slug: None,
+            icon_url: project.icon_url,
+            source_url: project.source_url,
+            issues_url: project.issues_url,
+            wiki_url: project.wiki_url,
+            loaders: project.loaders,
+            game_versions: project.game_versions
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::http::StatusCode;
+    use poem::web::{Json, Path};
+    use poem::{get, handler, IntoResponse, Response, Route};
+    use serde_json::json;
+
+    use super::*;
+    use crate::metadata::mock;
+
+    #[handler]
+    fn project(Path(slug): Path<String>) -> Response {
+        let project_type = match slug.as_str() {
+            "create" | "create-fabric" => "mod",
+            "create-modpack" => "modpack",
+            "broken" => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            _ => return StatusCode::NOT_FOUND.into_response()
+        };
+
+        Json(json!({
+            "id": format!("{slug}-id"),
+            "slug": slug,
+            "title": "Create",
+            "project_type": project_type,
+            "icon_url": "https://cdn.modrinth.com/create.png",
+            "source_url": "https://github.com/Creators-of-Create/Create",
+            "issues_url": null,
+            "wiki_url": null,
+            "loaders": ["forge", "neoforge"],
+            "game_versions": ["1.20.1", "1.21.1"]
+        }))
+        .into_response()
+    }
+
+    async fn client() -> ModrinthClient {
+        let base_url = mock::serve(Route::new().at("/project/:slug", get(project))).await;
+
+        ModrinthClient::new(Client::new(), &base_url)
+    }
+
+    #[tokio::test]
+    async fn resolves_mod_by_namespace() {
+        let found = client().await
+            .resolve("create", &ModMetadata::default())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(found.mod_name.as_deref(), Some("Create"));
+        assert_eq!(found.modrinth_id.as_deref(), Some("create-id"));
+        assert_eq!(found.modrinth_slug.as_deref(), Some("create"));
+        assert_eq!(found.icon_url.as_deref(), Some("https://cdn.modrinth.com/create.png"));
+        assert_eq!(found.issues_url, None);
+        assert_eq!(found.loaders, ["forge", "neoforge"]);
+        assert_eq!(found.game_versions, ["1.20.1", "1.21.1"]);
+    }
+
+    #[tokio::test]
+    async fn prefers_known_slug() {
+        let known = ModMetadata {
+            modrinth_slug: Some("create-fabric".into()),
+            ..Default::default()
+        };
+
+        let found = client().await
+            .resolve("create", &known)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(found.modrinth_slug.as_deref(), Some("create-fabric"));
+    }
+
+    #[tokio::test]
+    async fn missing_project_is_not_found() {
+        let found = client().await
+            .resolve("missing", &ModMetadata::default())
+            .await
+            .unwrap();
+
+        assert!(found.is_none());
+    }
+
+    #[tokio::test]
+    async fn ignores_projects_which_are_not_mods() {
+        let found = client().await
+            .resolve("create-modpack", &ModMetadata::default())
+            .await
+            .unwrap();
+
+        assert!(found.is_none());
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let found = client().await
+            .resolve("broken", &ModMetadata::default())
+            .await;
+
+        assert!(found.is_err());
+    }
+}
@@ -1,3 +1,4 @@
 pub mod validators;
 pub mod files;
-pub mod logging;
\ No newline at end of file
+pub mod logging;
+pub mod ratelimit;
\ No newline at end of file
@@ -0,0 +1,432 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::Args;
+use poem::http::{HeaderValue, Method};
+use poem::{Endpoint, EndpointExt, IntoResponse, Request, Response};
+use poem_openapi::payload::Json;
+use redis::Script;
+use time::OffsetDateTime;
+
+use crate::api::ApiContext;
+use crate::authentication::session::UserSession;
+use crate::database::redis::RedisPool;
+use crate::error::{ApiError, Cooldown};
+
+pub const RATELIMIT_NAMESPACE: &str = "ratelimit";
+
+/// Takes a single token from every bucket if each of them has one available,
+/// refilling them based on the time since they were last used. Returns whether
+/// the request is allowed, the number of whole tokens remaining, the number of
+/// milliseconds until the next token is available and the number of milliseconds
+/// until the buckets are full again, taken from the most restrictive bucket.
+///
+/// KEYS     - The buckets
+/// ARGV[1]  - The capacity of each bucket
+/// ARGV[2]  - The number of milliseconds it takes to refill a single token
+/// ARGV[3]  - The current unix time in milliseconds
+///
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local capacity = tonumber(ARGV[1])
+local refill = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+
+local buckets = {}
+local allowed = 1
+
+for i, key in ipairs(KEYS) do
+    local bucket = redis.call('HMGET', key, 'tokens', 'updated_at')
+    local tokens = tonumber(bucket[1]) or capacity
+    local updated_at = tonumber(bucket[2]) or now
+
+    buckets[i] = math.min(capacity, tokens + math.max(0, now - updated_at) / refill)
+
+    if buckets[i] < 1 then
+        allowed = 0
+    end
+end
+
+local remaining = capacity
+local retry = 0
+local reset = 0
+
+for i, key in ipairs(KEYS) do
+    local tokens = buckets[i]
+    if allowed == 1 then
+        tokens = tokens - 1
+    elseif tokens < 1 then
+        retry = math.max(retry, math.ceil((1 - tokens) * refill))
+    end
+
+    local full = math.ceil((capacity - tokens) * refill)
+    remaining = math.min(remaining, math.floor(tokens))
+    reset = math.max(reset, full)
+
+    redis.call('HSET', key, 'tokens', tostring(tokens), 'updated_at', now)
+    redis.call('PEXPIRE', key, math.max(full, 1))
+end
+
+return { allowed, remaining, retry, reset }
+"#;
+
+#[derive(Args, Debug, Clone)]
+pub struct RateLimitArguments {
+    #[arg(help = "The number of read requests a client can make per minute")]
+    #[arg(env = "RATELIMIT_READS", long = "ratelimit_reads")]
+    #[arg(default_value = "300")]
+    pub reads: u64,
+
+    #[arg(help = "The number of write requests a client can make per minute")]
+    #[arg(env = "RATELIMIT_WRITES", long = "ratelimit_writes")]
+    #[arg(default_value = "60")]
+    pub writes: u64,
+
+    #[arg(help = "The number of uploads a client can make per minute")]
+    #[arg(env = "RATELIMIT_UPLOADS", long = "ratelimit_uploads")]
+    #[arg(default_value = "5")]
+    pub uploads: u64,
+
+    #[arg(help = "The number of authentication requests a client can make per minute")]
+    #[arg(env = "RATELIMIT_AUTH", long = "ratelimit_auth")]
+    #[arg(default_value = "20")]
+    pub auth: u64,
+
+    #[arg(help = "Whether to identify clients by the X-Forwarded-For header, only enable this behind a trusted proxy")]
+    #[arg(env = "RATELIMIT_TRUST_PROXY", long = "ratelimit_trust_proxy")]
+    #[arg(default_value = "false")]
+    pub trust_proxy: bool,
+}
+
+/// The class of an operation, each class has its own limit and bucket so that
+/// for instance browsing schematics does not prevent a user from commenting.
+/// Every request is either a read or a write, operations which are more
+/// expensive are additionally limited by a stricter class, see `upload_limit`
+/// and `auth_limit`
+#[derive(Debug, Clone, Copy)]
+enum RouteClass {
+    Read,
+    Write,
+    Upload,
+    Auth
+}
+
+impl fmt::Display for RouteClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteClass::Read => write!(f, "read"),
+            RouteClass::Write => write!(f, "write"),
+            RouteClass::Upload => write!(f, "upload"),
+            RouteClass::Auth => write!(f, "auth")
+        }
+    }
+}
+
+impl RouteClass {
+    fn classify(method: &Method) -> RouteClass {
+        match *method {
+            Method::GET | Method::HEAD => RouteClass::Read,
+            _ => RouteClass::Write
+        }
+    }
+
+    fn limit(&self, args: &RateLimitArguments) -> u64 {
+        match self {
+            RouteClass::Read => args.reads,
+            RouteClass::Write => args.writes,
+            RouteClass::Upload => args.uploads,
+            RouteClass::Auth => args.auth
+        }
+    }
+}
+
+struct Bucket {
+    allowed: bool,
+    limit: u64,
+    remaining: u64,
+    retry_after: u64,
+    reset: u64
+}
+
+/// Applies a token bucket rate limit to every request made to the api, clients
+/// are identified by their ip address and, if they are logged in, by their
+/// session as well. A request is only allowed if both buckets have a token left
+/// so that neither switching accounts nor switching networks gets around the
+/// limit. Requests are limited as either reads or writes, see `RateLimitArguments`
+///
+/// Every response includes the `RateLimit-Limit`, `RateLimit-Remaining` and
+/// `RateLimit-Reset` headers, if the limit is exceeded then a `429 Too Many
+/// Requests` response is returned with a `Retry-After` header.
+///
+/// If redis is unavailable requests are allowed through rather than failing
+/// every request to the api.
+///
+pub async fn middleware_ratelimit<E>(next: E, req: Request) -> poem::Result<Response>
+where
+    E: Endpoint
+{
+    if !req.original_uri().path().starts_with("/api") || req.method() == Method::OPTIONS {
+        return Ok(next.get_response(req).await);
+    }
+
+    let class = RouteClass::classify(req.method());
+
+    apply_limit(next, req, class).await
+}
+
+/// Limits an operation which receives whole files by the upload limit on top of
+/// the write limit, applied with `#[oai(transform = "upload_limit")]`
+pub fn upload_limit<E>(ep: E) -> impl Endpoint
+where
+    E: Endpoint + 'static
+{
+    ep.around(|next, req| apply_limit(next, req, RouteClass::Upload))
+}
+
+/// Limits an authentication operation by the authentication limit on top of the
+/// read or write limit, applied with `#[oai(transform = "auth_limit")]`
+pub fn auth_limit<E>(ep: E) -> impl Endpoint
+where
+    E: Endpoint + 'static
+{
+    ep.around(|next, req| apply_limit(next, req, RouteClass::Auth))
+}
+
+async fn apply_limit<E>(next: E, req: Request, class: RouteClass) -> poem::Result<Response>
+where
+    E: Endpoint
+{
+    let Some(ctx) = req.data::<ApiContext>().cloned() else {
+        return Ok(next.get_response(req).await);
+    };
+
+    let limit = class.limit(&ctx.ratelimits);
+    let clients = client_identifiers(&req, &ctx).await;
+
+    if clients.is_empty() {
+        return Ok(next.get_response(req).await);
+    }
+
+    let bucket = match take_token(&ctx.redis_pool, class, &clients, limit).await {
+        Some(bucket) => bucket,
+        None => return Ok(next.get_response(req).await)
+    };
+
+    let response = if bucket.allowed {
+        next.get_response(req).await
+    } else {
+        limited_response(&bucket)
+    };
+
+    Ok(with_limit_headers(response, &bucket))
+}
+
+fn limited_response(bucket: &Bucket) -> Response {
+    let until = OffsetDateTime::now_utc() + Duration::from_secs(bucket.retry_after);
+    let mut response = ApiError::TooManyRequests(Json(Cooldown { until })).into_response();
+
+    response.headers_mut().insert(
+        "Retry-After",
+        HeaderValue::from(bucket.retry_after.max(1))
+    );
+
+    response
+}
+
+fn with_limit_headers(mut response: Response, bucket: &Bucket) -> Response {
+    let headers = response.headers_mut();
+
+    // Operations with a stricter class are limited within the read or write
+    // limit, the headers describe the stricter of the two
+    if !headers.contains_key("RateLimit-Limit") {
+        headers.insert("RateLimit-Limit", HeaderValue::from(bucket.limit));
+        headers.insert("RateLimit-Remaining", HeaderValue::from(bucket.remaining));
+        headers.insert("RateLimit-Reset", HeaderValue::from(bucket.reset));
+    }
+
+    response
+}
+
+async fn client_identifiers(req: &Request, ctx: &ApiContext) -> Vec<String> {
+    let mut clients = Vec::with_capacity(2);
+
+    if let Some(cookie) = req.cookie().get(UserSession::NAMESPACE) {
+        let session = UserSession::from_id(cookie.value_str().to_owned(), &ctx.redis_pool).await;
+
+        if let Ok(session) = session {
+            clients.push(format!("user:{}", session.user_id));
+        }
+    }
+
+    if let Some(address) = client_address(req, ctx.ratelimits.trust_proxy) {
+        clients.push(format!("ip:{address}"));
+    }
+
+    clients
+}
+
+/// The address of the client, this is only taken from the first hop of the
+/// `X-Forwarded-For` header if the proxy in front of us is trusted to set it
+fn client_address(req: &Request, trust_proxy: bool) -> Option<IpAddr> {
+    let forwarded = trust_proxy
+        .then(|| req.header("X-Forwarded-For"))
+        .flatten()
+        .and_then(|header| header.split(',').next())
+        .and_then(|address| address.trim().parse::<IpAddr>().ok());
+
+    forwarded.or_else(|| {
+        req.remote_addr()
+            .as_socket_addr()
+            .map(|address| address.ip())
+    })
+}
+
+async fn take_token(
+    redis_pool: &RedisPool,
+    class: RouteClass,
+    clients: &[String],
+    limit: u64
+) -> Option<Bucket> {
+    static SCRIPT: OnceLock<Script> = OnceLock::new();
+    let script = SCRIPT.get_or_init(|| Script::new(TOKEN_BUCKET_SCRIPT));
+
+    let limit = limit.max(1);
+    let refill = (60_000 / limit).max(1);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or_default();
+
+    let result = redis_pool
+        .run_script::<(u64, u64, u64, u64), _, _>(
+            script,
+            RATELIMIT_NAMESPACE,
+            clients.iter().map(|client| format!("{class}:{client}")),
+            &[limit, refill, now]
+        )
+        .await;
+
+    match result {
+        Ok((allowed, remaining, retry_after, reset)) => Some(Bucket {
+            allowed: allowed == 1,
+            limit,
+            remaining,
+            retry_after: retry_after.div_ceil(1000),
+            reset: reset.div_ceil(1000)
+        }),
+        Err(_) => {
+            tracing::warn!("Failed to apply rate limit, allowing request");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::http::StatusCode;
+
+    use super::*;
+    use crate::database::redis::{self, RedisArguments};
+
+    fn bucket(allowed: bool) -> Bucket {
+        Bucket { allowed, limit: 60, remaining: 0, retry_after: 3, reset: 60 }
+    }
+
+    #[test]
+    fn ignores_forwarded_for_unless_trusted() {
+        let req = Request::builder()
+            .header("X-Forwarded-For", "203.0.113.7")
+            .finish();
+
+        assert_eq!(client_address(&req, false), None);
+    }
+
+    #[test]
+    fn uses_first_forwarded_hop_when_trusted() {
+        let req = Request::builder()
+            .header("X-Forwarded-For", "203.0.113.7, 10.0.0.1")
+            .finish();
+
+        assert_eq!(client_address(&req, true), "203.0.113.7".parse().ok());
+    }
+
+    #[test]
+    fn limited_response_is_too_many_requests() {
+        let response = with_limit_headers(limited_response(&bucket(false)), &bucket(false));
+        let headers = response.headers();
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(headers["Retry-After"], "3");
+        assert_eq!(headers["RateLimit-Limit"], "60");
+        assert_eq!(headers["RateLimit-Remaining"], "0");
+        assert_eq!(headers["RateLimit-Reset"], "60");
+    }
+
+    #[test]
+    fn keeps_headers_of_stricter_class() {
+        let upload = Bucket { allowed: true, limit: 5, remaining: 4, retry_after: 0, reset: 12 };
+        let response = with_limit_headers(Response::default(), &upload);
+        let response = with_limit_headers(response, &bucket(true));
+
+        assert_eq!(response.headers()["RateLimit-Limit"], "5");
+        assert_eq!(response.headers()["RateLimit-Remaining"], "4");
+        assert!(!response.headers().contains_key("Retry-After"));
+    }
+
+    // The token bucket script can only be run by redis itself, run these with
+    // `cargo test -- --ignored` against the instance set by `REDIS_URL`
+    async fn redis_pool() -> RedisPool {
+        let redis_url = std::env::var("REDIS_URL")
+            .unwrap_or_else(|_| "redis://localhost".to_string());
+
+        redis::connect(RedisArguments { redis_url })
+            .await
+            .expect("Failed to connect to redis")
+    }
+
+    fn client(name: &str) -> String {
+        format!("test:{name}:{}", uuid::Uuid::new_v4())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires redis"]
+    async fn takes_tokens_until_empty() {
+        let redis_pool = redis_pool().await;
+        let clients = [client("empty")];
+
+        for remaining in [1, 0] {
+            let bucket = take_token(&redis_pool, RouteClass::Write, &clients, 2).await.unwrap();
+
+            assert!(bucket.allowed);
+            assert_eq!(bucket.remaining, remaining);
+        }
+
+        let bucket = take_token(&redis_pool, RouteClass::Write, &clients, 2).await.unwrap();
+
+        assert!(!bucket.allowed);
+        assert_eq!(bucket.remaining, 0);
+        assert_eq!(bucket.retry_after, 30);
+        assert_eq!(bucket.reset, 60);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires redis"]
+    async fn only_takes_tokens_if_every_bucket_allows() {
+        let redis_pool = redis_pool().await;
+        let clients = [client("user"), client("address")];
+
+        // Empty the bucket of the user, but not the one of their address
+        take_token(&redis_pool, RouteClass::Write, &clients[..1], 1).await.unwrap();
+
+        let bucket = take_token(&redis_pool, RouteClass::Write, &clients, 1).await.unwrap();
+
+        assert!(!bucket.allowed);
+
+        // The request was rejected so the address keeps its token
+        let bucket = take_token(&redis_pool, RouteClass::Write, &clients[1..], 1).await.unwrap();
+
+        assert!(bucket.allowed);
+    }
+}
@@ -8,6 +8,7 @@ pub struct Comment {
     pub parent: Option<Uuid>,
     pub comment_author: Uuid,
     pub comment_body: String,
+    pub comment_html: Option<String>,
     pub schematic_id: String,
     pub updated_at: Option<OffsetDateTime>,
     pub created_at: OffsetDateTime
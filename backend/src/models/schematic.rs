@@ -1,4 +1,8 @@
-use poem_openapi_derive::Object;
+use poem_openapi_derive::{Enum, Object};
+use sqlx::error::BoxDynError;
+use sqlx::postgres::{PgHasArrayType, PgTypeInfo, PgValueRef};
+use sqlx::postgres::types::PgRecordDecoder;
+use sqlx::{Decode, Postgres, Type};
 use time::OffsetDateTime;
 use uuid::Uuid;
 
@@ -6,12 +10,139 @@ use uuid::Uuid;
 pub struct Schematic {
     pub schematic_id: Uuid,
     pub body: String,
+    pub body_html: Option<String>,
     pub schematic_name: String,
-    pub game_version_id: i32,
-    pub create_version_id: i32,
+    pub game_versions: Vec<SchematicVersion>,
+    pub create_versions: Vec<SchematicVersion>,
     pub author: Uuid,
-    pub images: Vec<String>,
+    pub images: Vec<SchematicImage>,
     pub downloads: i64,
+    pub status: SchematicStatus,
     pub updated_at: Option<OffsetDateTime>,
     pub created_at: OffsetDateTime
 }
+
+/// An image attached to a schematic, these are always ordered with the cover
+/// image first followed by the rest by their position.
+/// 
+/// The dimensions and blurhash are only known once the image has been
+/// processed, for the available sizes see `GET /api/v1/schematics/:id/images`
+#[derive(Debug, Serialize, Object)]
+pub struct SchematicImage {
+    pub file_name: String,
+    pub position: i32,
+    pub caption: Option<String>,
+    pub alt_text: Option<String>,
+    pub is_cover: bool,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub blurhash: Option<String>
+}
+
+// Decoding is implemented by hand as the derived implementation adds bounds
+// which cannot be satisfied by the optional fields
+impl Type<Postgres> for SchematicImage {
+    fn type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("schematic_image")
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for SchematicImage {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        let mut decoder = PgRecordDecoder::new(value)?;
+
+        Ok(Self {
+            file_name: decoder.try_decode()?,
+            position: decoder.try_decode()?,
+            caption: decoder.try_decode()?,
+            alt_text: decoder.try_decode()?,
+            is_cover: decoder.try_decode()?,
+            width: decoder.try_decode()?,
+            height: decoder.try_decode()?,
+            blurhash: decoder.try_decode()?
+        })
+    }
+}
+
+impl PgHasArrayType for SchematicImage {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_schematic_image")
+    }
+}
+
+/// A game or Create version supported by a schematic
+#[derive(Debug, Serialize, Object, sqlx::Type)]
+#[sqlx(type_name = "schematic_version")]
+pub struct SchematicVersion {
+    pub version_id: i32,
+    pub version_name: String
+}
+
+impl PgHasArrayType for SchematicVersion {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_schematic_version")
+    }
+}
+
+/// A schematic file attached to a schematic, schematics split into several
+/// parts will have one for each ordered by their position.
+/// 
+/// The size, dimensions and block count are only known once the file has
+/// been processed
+#[derive(Debug, Serialize, Object)]
+pub struct SchematicFile {
+    pub file_name: String,
+    /// A name to show in place of the file name
+    pub display_name: Option<String>,
+    pub description: Option<String>,
+    pub position: i32,
+    pub format: FileFormat,
+    /// The size of the file in bytes as it is stored and downloaded, files may
+    /// be recompressed when processed so this can differ from the size of the
+    /// file which was uploaded
+    pub file_size: Option<i32>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub length: Option<i32>,
+    /// The number of blocks in the schematic excluding air
+    pub block_count: Option<i32>,
+    /// A sha256 hash of the decompressed file, files containing the same
+    /// structure share a fingerprint
+    pub fingerprint: Option<String>,
+    /// The file name of a top down preview of the structure, this is stored
+    /// alongside the file itself and is missing for files which could not
+    /// be read as a structure
+    pub preview: Option<String>
+}
+
+/// Currently only structure files as saved by structure blocks are supported,
+/// reading any other stored format is an error rather than being mistaken for
+/// a structure
+#[derive(Enum, Serialize, Debug, sqlx::Type)]
+#[serde(rename_all="snake_case")]
+#[oai(rename_all="snake_case")]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+pub enum FileFormat {
+    Structure
+}
+
+/// Newly uploaded schematics are processed in the background, until this is
+/// complete they will not appear in search results or feeds
+#[derive(Enum, Serialize, Debug)]
+#[serde(rename_all="snake_case")]
+#[oai(rename_all="snake_case")]
+pub enum SchematicStatus {
+    Processing,
+    Published,
+    Failed
+}
+
+impl From<std::string::String> for SchematicStatus {
+    fn from(value: std::string::String) -> Self {
+        match value.as_str() {
+            "processing" => Self::Processing,
+            "failed" => Self::Failed,
+            _ => Self::Published,
+        }
+    }
+}
@@ -9,7 +9,12 @@ pub struct User {
     pub displayname: String,
     pub avatar: Option<String>,
     pub about: Option<String>,
+    pub about_html: Option<String>,
     pub role: Role,
+    /// The number of users following this user
+    pub follower_count: i64,
+    /// The number of users this user follows
+    pub following_count: i64,
     pub updated_at: Option<OffsetDateTime>,
     pub created_at: OffsetDateTime
 }
@@ -0,0 +1,316 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use clap::Args;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::database::postgres;
+use crate::database::postgres::DatabaseArguments;
+
+/// The namespace of blocks added by Create, only these blocks are checked
+/// against the registry as addons are versioned separately
+pub const CREATE_NAMESPACE: &str = "create";
+
+#[derive(Args, Debug)]
+pub struct ImportBlocksCommandArguments {
+    #[arg(help = "The name of the Create version to import blocks for, such as 0.5.1")]
+    #[arg(long = "create_version")]
+    pub create_version: String,
+
+    #[arg(help = "A file listing the block ids within this version, one per line")]
+    pub file: PathBuf,
+
+    #[arg(help = "Remove any blocks previously imported for this version which are not in the file")]
+    #[arg(long = "replace")]
+    pub replace: bool,
+
+    #[command(next_help_heading = "Database")]
+    #[command(flatten)]
+    pub postgres: DatabaseArguments,
+}
+
+/// The result of `check_blocks`
+#[derive(Debug, Default)]
+pub struct BlockCheck {
+    /// Create blocks which are used but do not exist in one or more of the
+    /// selected versions
+    pub unknown_blocks: Vec<String>,
+    /// The Create versions which contain every Create block which is used
+    pub compatible_versions: Vec<i32>,
+}
+
+/// Checks the Create blocks used by a schematic against those known to exist
+/// within each of its selected versions, along with finding every version which
+/// has all of them. Versions which have no blocks imported are never considered
+/// as it is not known what they contain
+pub async fn check_blocks(
+    conn: &mut PgConnection,
+    create_version_ids: &[i32],
+    blocks: &HashSet<String>
+) -> Result<BlockCheck, sqlx::Error> {
+    let prefix = format!("{CREATE_NAMESPACE}:");
+
+    let mut blocks: Vec<String> = blocks.iter()
+        .filter(|block| block.starts_with(&prefix))
+        .cloned()
+        .collect();
+
+    blocks.sort();
+
+    let unknown_blocks = sqlx::query_scalar!(
+        r#"
+        select block_id as "block_id!"
+        from unnest($2::text[]) as block_id
+        where exists (
+            select 1
+            from unnest($1::int[]) as selected(create_version_id)
+            where exists (
+                select 1
+                from create_version_blocks
+                where create_version_blocks.create_version_id = selected.create_version_id
+            )
+            and not exists (
+                select 1
+                from create_version_blocks
+                where create_version_blocks.create_version_id = selected.create_version_id
+                and create_version_blocks.block_id = block_id.block_id
+            )
+        )
+        "#,
+        create_version_ids,
+        &blocks[..]
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let compatible_versions = sqlx::query_scalar!(
+        r#"
+        select create_version_id
+        from create_versions
+        where exists (
+            select 1
+            from create_version_blocks
+            where create_version_blocks.create_version_id = create_versions.create_version_id
+        )
+        and not exists (
+            select 1
+            from unnest($1::text[]) as used(block_id)
+            where not exists (
+                select 1
+                from create_version_blocks
+                where create_version_blocks.create_version_id = create_versions.create_version_id
+                and create_version_blocks.block_id = used.block_id
+            )
+        )
+        order by sort_order, create_version_id
+        "#,
+        &blocks[..]
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(BlockCheck { unknown_blocks, compatible_versions })
+}
+
+/// A tag suggested for a schematic, see `suggest_tags`
+#[derive(Debug, Serialize)]
+pub struct SuggestedTag {
+    pub tag_id: i64,
+    pub tag_name: String,
+    pub description: Option<String>,
+    pub category_id: Option<i64>,
+    /// The blocks within the schematic which caused this tag to be suggested
+    pub matched_blocks: Vec<String>
+}
+
+/// Totals the number of each block placed across every file of a schematic,
+/// files which have not been processed yet are not included
+pub async fn schematic_block_counts(
+    conn: &mut PgConnection,
+    schematic_id: Uuid
+) -> Result<HashMap<String, i64>, sqlx::Error> {
+    let block_counts = sqlx::query!(
+        r#"
+        select block_id, sum(block_count)::bigint as "block_count!"
+        from file_blocks
+        where schematic_id = $1
+        group by block_id
+        "#,
+        schematic_id
+    )
+    .fetch_all(conn)
+    .await?
+    .into_iter()
+    .map(|row| (row.block_id, row.block_count))
+    .collect();
+
+    Ok(block_counts)
+}
+
+/// Suggests tags from the number of each block placed within a schematic using
+/// the rules within `tag_rules`, tags already applied to the schematic are not
+/// suggested
+pub async fn suggest_tags(
+    conn: &mut PgConnection,
+    schematic_id: Uuid,
+    block_counts: &HashMap<String, i64>
+) -> Result<Vec<SuggestedTag>, sqlx::Error> {
+    let block_ids: Vec<&str> = block_counts.keys().map(String::as_str).collect();
+    let counts: Vec<i64> = block_counts.values().copied().collect();
+
+    sqlx::query_as!(
+        SuggestedTag,
+        r#"
+        select
+            tags.tag_id, tags.tag_name,
+            tags.description, tags.category_id,
+            array_agg(tag_rules.block_id order by tag_rules.block_id) as "matched_blocks!"
+        from
+            unnest($2::text[], $3::bigint[]) as used(block_id, block_count)
+            inner join tag_rules on tag_rules.block_id = used.block_id
+            inner join tags on tags.tag_id = tag_rules.tag_id
+        where
+            used.block_count >= tag_rules.min_count
+            and not exists (
+                select 1
+                from applied_tags
+                where applied_tags.schematic_id = $1
+                and applied_tags.tag_id = tags.tag_id
+            )
+        group by
+            tags.tag_id
+        order by
+            tags.tag_name
+        "#,
+        schematic_id,
+        &block_ids[..] as &[&str],
+        &counts[..]
+    )
+    .fetch_all(conn)
+    .await
+}
+
+/// Replaces the number of each block placed within a single schematic file,
+/// these are used to suggest tags. Nothing is stored if the file has since
+/// been removed from the schematic
+pub async fn store_file_blocks(
+    conn: &mut PgConnection,
+    schematic_id: Uuid,
+    file_name: &str,
+    block_counts: &HashMap<String, i32>
+) -> Result<(), sqlx::Error> {
+    let block_ids: Vec<&str> = block_counts.keys().map(String::as_str).collect();
+    let counts: Vec<i32> = block_counts.values().copied().collect();
+
+    sqlx::query!(
+        r#"
+        delete from file_blocks
+            where schematic_id = $1
+            and file_name = $2
+        "#,
+        schematic_id,
+        file_name
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        insert into file_blocks (
+            schematic_id, file_name, block_id, block_count
+        )
+        select $1, $2, block.block_id, block.block_count
+        from unnest($3::text[], $4::int[]) as block(block_id, block_count)
+        where exists (
+            select 1
+            from schematic_files
+            where schematic_id = $1
+            and file_name = $2
+        )
+        "#,
+        schematic_id,
+        file_name,
+        &block_ids[..] as &[&str],
+        &counts[..]
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Imports the blocks within a version of Create from a file listing their ids,
+/// one per line. Blank lines and lines starting with `#` are ignored and ids
+/// without a namespace are assumed to be Create blocks
+pub async fn import(
+    ImportBlocksCommandArguments {
+        create_version,
+        file,
+        replace,
+        postgres
+    }: ImportBlocksCommandArguments
+) -> Result<(), anyhow::Error> {
+    let contents = tokio::fs::read_to_string(&file).await?;
+
+    let blocks: Vec<String> = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|block| match block.contains(':') {
+            true => block.to_string(),
+            false => format!("{CREATE_NAMESPACE}:{block}")
+        })
+        .collect();
+
+    let pool = postgres::connect(postgres).await?;
+    let mut transaction = pool.begin().await?;
+
+    let create_version_id = sqlx::query_scalar!(
+        r#"select create_version_id from create_versions where create_version_name = $1"#,
+        create_version
+    )
+    .fetch_optional(&mut *transaction)
+    .await?
+    .ok_or_else(|| anyhow::anyhow!("Create version {} does not exist", create_version))?;
+
+    if replace {
+        sqlx::query!(
+            r#"
+            delete from create_version_blocks
+                where create_version_id = $1
+                and block_id != all($2)
+            "#,
+            create_version_id,
+            &blocks[..]
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    let imported = sqlx::query!(
+        r#"
+        insert into create_version_blocks (
+            create_version_id, block_id
+        )
+        select $1, block_id
+        from unnest($2::text[]) as block_id
+        on conflict do nothing
+        "#,
+        create_version_id,
+        &blocks[..]
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    transaction.commit().await?;
+
+    tracing::info!(
+        "Imported {} new blocks for Create {} from {} listed",
+        imported.rows_affected(),
+        create_version,
+        blocks.len()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,334 @@
+use clap::Args;
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::database::postgres;
+use crate::database::postgres::DatabaseArguments;
+use crate::error::ApiError;
+use crate::jobs::Job;
+use crate::response::ApiResult;
+
+use super::blocks;
+use super::schematics::{read_metadata, SchematicMetadata};
+
+#[cfg(feature="compression")]
+use super::schematics::decompress;
+
+#[derive(Args, Debug)]
+pub struct RebuildDependenciesCommandArguments {
+    #[command(next_help_heading = "Database")]
+    #[command(flatten)]
+    pub postgres: DatabaseArguments,
+}
+
+/// Reads the metadata of a schematic file as it is stored, if the file has not
+/// been processed yet then `None` is returned. Files which cannot be read as a
+/// structure have no dependencies or blocks.
+/// 
+/// This is blocking so should be called from `spawn_blocking`
+pub fn stored_metadata(schematic_id: &Uuid, file_name: &str) -> Result<Option<SchematicMetadata>, ApiError> {
+    let location = super::schematic_file_path(schematic_id);
+
+    let contents = match std::fs::read(location.join(file_name)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(anyhow::Error::new(e).into())
+    };
+
+    #[cfg(feature="compression")]
+    let contents = decompress(&contents)?;
+
+    let metadata = read_metadata(&contents).unwrap_or_default();
+
+    Ok(Some(metadata))
+}
+
+/// Sets the mods required by a single schematic file from the namespaces used
+/// within it, creating any mods which haven't been seen before.
+/// `update_schematic_dependencies` should be called afterwards to update the
+/// dependencies of the schematic as a whole
+/// 
+/// Ignored namespaces, such as `minecraft`, are skipped and aliased namespaces
+/// are recorded as a dependency on the mod they are an alias of, see
+/// `mod_aliases` and `ignored_namespaces`
+/// 
+/// Nothing is stored if the file has since been removed from the schematic,
+/// new mods are queued to have their details fetched, see `Job::SyncMod`
+pub async fn store_file_dependencies(
+    conn: &mut PgConnection,
+    schematic_id: Uuid,
+    file_name: &str,
+    namespaces: &[String]
+) -> ApiResult<()> {
+    let created = sqlx::query_scalar!(
+        r#"
+        insert into mods (
+            mod_slug
+        )
+        select namespace
+        from unnest($1::text[]) as namespace
+        where namespace not in (select namespace from ignored_namespaces)
+        and namespace not in (select namespace from mod_aliases)
+        on conflict do nothing
+        returning mod_id
+        "#,
+        namespaces
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    for mod_id in created {
+        Job::SyncMod { mod_id }.enqueue(&mut *conn, None).await?;
+    }
+
+    sqlx::query!(
+        r#"
+        delete from file_dependencies
+            where schematic_id = $1
+            and file_name = $2
+        "#,
+        schematic_id,
+        file_name
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        update schematic_files
+            set dependencies_read = true
+            where schematic_id = $1
+            and file_name = $2
+        "#,
+        schematic_id,
+        file_name
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        insert into file_dependencies (
+            schematic_id, file_name, mod_id
+        )
+        select distinct $1::uuid, $2::text, coalesce(mod_aliases.mod_id, mods.mod_id)
+        from unnest($3::text[]) as namespace
+        left join mod_aliases using (namespace)
+        left join mods on mods.mod_slug = namespace
+        where coalesce(mod_aliases.mod_id, mods.mod_id) is not null
+        and namespace not in (select namespace from ignored_namespaces)
+        and exists (
+            select 1
+            from schematic_files
+            where schematic_id = $1 and file_name = $2
+        )
+        on conflict do nothing
+        "#,
+        schematic_id,
+        file_name,
+        namespaces
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Recomputes the mods a schematic depends on from the dependencies of each
+/// of its files, this should be called whenever a file is added, replaced or
+/// removed
+/// 
+/// Mods are only removed once the dependencies of every file have been read,
+/// files uploaded before they were recorded per file or still waiting to be
+/// processed would otherwise appear to require nothing
+pub async fn update_schematic_dependencies(
+    conn: &mut PgConnection,
+    schematic_id: Uuid
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        delete from mod_dependencies
+            where schematic_id = $1
+            and mod_id not in (
+                select mod_id
+                from file_dependencies
+                where schematic_id = $1
+            )
+            and not exists (
+                select 1
+                from schematic_files
+                where schematic_id = $1
+                and not dependencies_read
+            )
+        "#,
+        schematic_id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        insert into mod_dependencies (
+            schematic_id, mod_id
+        )
+        select distinct schematic_id, mod_id
+        from file_dependencies
+        where schematic_id = $1
+        on conflict do nothing
+        "#,
+        schematic_id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Merges the mod created for a namespace into the mod it has since been made
+/// an alias of, moving its dependencies and aliases over before removing it.
+/// This does nothing if no mod was created for the namespace
+pub async fn merge_aliased_mod(
+    conn: &mut PgConnection,
+    namespace: &str,
+    mod_id: Uuid
+) -> Result<(), sqlx::Error> {
+    let Some(aliased_id) = sqlx::query_scalar!(
+        r#"select mod_id from mods where mod_slug = $1 and mod_id != $2"#,
+        namespace,
+        mod_id
+    )
+    .fetch_optional(&mut *conn)
+    .await? else {
+        return Ok(());
+    };
+
+    sqlx::query!(
+        r#"
+        update mod_aliases
+            set mod_id = $1
+            where mod_id = $2
+        "#,
+        mod_id,
+        aliased_id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        insert into file_dependencies (
+            schematic_id, file_name, mod_id
+        )
+        select schematic_id, file_name, $1
+        from file_dependencies
+        where mod_id = $2
+        on conflict do nothing
+        "#,
+        mod_id,
+        aliased_id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        insert into mod_dependencies (
+            schematic_id, mod_id
+        )
+        select schematic_id, $1
+        from mod_dependencies
+        where mod_id = $2
+        on conflict do nothing
+        "#,
+        mod_id,
+        aliased_id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    // Any remaining dependencies on the aliased mod are removed with it
+    sqlx::query!(
+        r#"delete from mods where mod_id = $1"#,
+        aliased_id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Re-reads every stored schematic file and rebuilds the dependencies of each
+/// file and schematic from them, along with the blocks placed within each file.
+/// This is intended for maintenance, such as after changes to how mods are
+/// detected or after aliases or ignored namespaces have been removed, and can
+/// be run while the server is running
+pub async fn rebuild(
+    RebuildDependenciesCommandArguments {
+        postgres
+    }: RebuildDependenciesCommandArguments
+) -> Result<(), anyhow::Error> {
+    let pool = postgres::connect(postgres).await?;
+
+    let schematic_ids = sqlx::query_scalar!(
+        r#"select distinct schematic_id from schematic_files"#
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    tracing::info!("Rebuilding dependencies of {} schematics", schematic_ids.len());
+
+    let mut failed = 0;
+
+    for schematic_id in schematic_ids {
+        if let Err(e) = rebuild_schematic(&pool, schematic_id).await {
+            tracing::warn!("Failed to rebuild dependencies of {}: {:?}", schematic_id, e);
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("Failed to rebuild dependencies of {} schematics", failed);
+    }
+
+    tracing::info!("Finished rebuilding dependencies");
+
+    Ok(())
+}
+
+async fn rebuild_schematic(pool: &PgPool, schematic_id: Uuid) -> Result<(), ApiError> {
+    let mut transaction = pool.begin().await?;
+
+    let file_names = sqlx::query_scalar!(
+        r#"
+        select file_name
+        from schematic_files
+        where schematic_id = $1
+        for update
+        "#,
+        schematic_id
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+
+    for file_name in file_names {
+        let name = file_name.clone();
+
+        let metadata = tokio::task::spawn_blocking(move || stored_metadata(&schematic_id, &name))
+            .await
+            .map_err(anyhow::Error::new)??;
+
+        // Files still waiting to be processed will have their dependencies
+        // stored once they are
+        if let Some(metadata) = metadata {
+            let mods: Vec<String> = metadata.mods.into_iter().collect();
+            store_file_dependencies(&mut transaction, schematic_id, &file_name, &mods).await?;
+            blocks::store_file_blocks(&mut transaction, schematic_id, &file_name, &metadata.block_counts).await?;
+        }
+    }
+
+    update_schematic_dependencies(&mut transaction, schematic_id).await?;
+
+    transaction.commit().await?;
+
+    Ok(())
+}
@@ -0,0 +1,609 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::path::Path;
+
+use clap::Args;
+use image::imageops::FilterType;
+use image::io::{Limits, Reader};
+use image::DynamicImage;
+use poem_openapi_derive::Enum;
+use ravif::{Encoder as AvifEncoder, Img, RGB8, RGBA8};
+use uuid::Uuid;
+use webp::Encoder as WebpEncoder;
+
+use crate::database::postgres;
+use crate::database::postgres::DatabaseArguments;
+use crate::error::ApiError;
+
+/// Limits on the decoded size of an image, these are checked against the
+/// image header before any pixels are decoded as a small compressed file can
+/// describe a very large canvas
+const MAX_IMAGE_DIMENSION: u32 = 8192;
+const MAX_IMAGE_PIXELS: u64 = 40_000_000;
+const MAX_DECODE_ALLOCATION: u64 = 256 * 1024 * 1024; // 256mb
+
+/// Markup which has no business being inside of an image, files containing
+/// any of these are likely crafted to also be interpreted as another format
+const POLYGLOT_MARKERS: [&[u8]; 6] = [
+    b"<script", b"<html", b"<!doctype", b"<?php", b"<svg", b"<iframe"
+];
+
+const WEBP_QUALITY: f32 = 90.0;
+const AVIF_QUALITY: f32 = 70.0;
+
+/// rav1e is very slow at its lower speeds, this trades a slightly larger file
+/// for much faster encoding
+const AVIF_SPEED: u8 = 8;
+
+/// Blurhashes are generated from a small copy of the image as the result is
+/// heavily blurred anyway, this keeps generating them cheap
+const BLURHASH_SOURCE_SIZE: u32 = 32;
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+
+/// The sizes each image is stored in, images are scaled down to fit within a
+/// square of the given size but are never scaled up.
+#[derive(Enum, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all="snake_case")]
+#[oai(rename_all="snake_case")]
+pub enum ImageSize {
+    Thumbnail,
+    Card,
+    Full
+}
+
+#[derive(Enum, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all="snake_case")]
+#[oai(rename_all="snake_case")]
+pub enum ImageFormat {
+    Webp,
+    Avif
+}
+
+#[derive(Args, Debug)]
+pub struct BackfillImagesCommandArguments {
+    #[command(next_help_heading = "Database")]
+    #[command(flatten)]
+    pub postgres: DatabaseArguments,
+}
+
+pub struct ImageMetadata {
+    pub width: u32,
+    pub height: u32,
+    pub blurhash: String
+}
+
+impl ImageSize {
+    pub const ALL: [ImageSize; 3] = [ImageSize::Thumbnail, ImageSize::Card, ImageSize::Full];
+
+    pub fn max_dimension(&self) -> u32 {
+        match self {
+            ImageSize::Thumbnail => 256,
+            ImageSize::Card => 640,
+            ImageSize::Full => 1920
+        }
+    }
+
+    /// The dimensions an image of the given size will be stored at, preserving
+    /// its aspect ratio
+    pub fn dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        let max = self.max_dimension();
+
+        if width <= max && height <= max {
+            return (width, height);
+        }
+
+        let scale = max as f64 / width.max(height) as f64;
+
+        let width = ((width as f64 * scale).round() as u32).max(1);
+        let height = ((height as f64 * scale).round() as u32).max(1);
+
+        (width, height)
+    }
+}
+
+impl ImageFormat {
+    pub const ALL: [ImageFormat; 2] = [ImageFormat::Webp, ImageFormat::Avif];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Webp => "webp",
+            ImageFormat::Avif => "avif"
+        }
+    }
+}
+
+/// The name of the file a given variant of an image is stored as. Variants
+/// keep the whole original name so that images which only differ by their
+/// extension do not collide, for `cannon.png` this would be `cannon.png.webp`,
+/// `cannon.png.card.webp` and so on
+pub fn variant_file_name(file_name: &str, size: ImageSize, format: ImageFormat) -> String {
+    match size {
+        ImageSize::Full => format!("{file_name}.{}", format.extension()),
+        ImageSize::Thumbnail => format!("{file_name}.thumbnail.{}", format.extension()),
+        ImageSize::Card => format!("{file_name}.card.{}", format.extension())
+    }
+}
+
+/// The names images were stored under before `variant_file_name`, originally
+/// only a full size webp was kept with the extension of the original replaced
+fn legacy_variant_file_names(file_name: &str) -> Vec<String> {
+    let mut names = Vec::new();
+
+    for size in ImageSize::ALL {
+        for format in ImageFormat::ALL {
+            let extension = match size {
+                ImageSize::Full => format.extension().to_string(),
+                ImageSize::Thumbnail => format!("thumbnail.{}", format.extension()),
+                ImageSize::Card => format!("card.{}", format.extension())
+            };
+
+            names.push(Path::new(file_name).with_extension(extension).to_string_lossy().into_owned());
+        }
+    }
+
+    names
+}
+
+/// Checks that an uploaded image is one we're willing to decode without
+/// decoding the pixel data, rejecting it with a `422 Unprocessable Entity`
+/// describing the problem if not. This is cheap enough to be called while
+/// handling a request.
+/// 
+/// Only still png, jpeg, gif and webp images are accepted, animated images
+/// and those with markup or other data hidden after the image are rejected.
+pub fn validate(file_name: &str, contents: &[u8]) -> Result<(), ApiError> {
+    let reject = |reason: &str| {
+        ApiError::unprocessable_entity([("images", format!("{file_name}: {reason}"))])
+    };
+
+    let format = Reader::new(Cursor::new(contents))
+        .with_guessed_format()
+        .map_err(anyhow::Error::new)?
+        .format();
+
+    let structure = match format {
+        Some(image::ImageFormat::Png) => check_png(contents),
+        Some(image::ImageFormat::Jpeg) => check_jpeg(contents),
+        Some(image::ImageFormat::Gif) => check_gif(contents),
+        Some(image::ImageFormat::WebP) => check_webp(contents),
+        _ => Err("unsupported image format, only png, jpeg, gif and webp are accepted")
+    };
+
+    structure.map_err(reject)?;
+
+    if contains_markup(contents) {
+        return Err(reject("image contains embedded markup"));
+    }
+
+    let (width, height) = reader(contents)?
+        .into_dimensions()
+        .map_err(|_| reject("image could not be read"))?;
+
+    if width > MAX_IMAGE_DIMENSION || height > MAX_IMAGE_DIMENSION {
+        return Err(reject("image dimensions are too large"));
+    }
+
+    if width as u64 * height as u64 > MAX_IMAGE_PIXELS {
+        return Err(reject("image has too many pixels"));
+    }
+
+    Ok(())
+}
+
+/// Validates and then decodes an image with limits on how much the decoder
+/// is allowed to allocate.
+/// 
+/// Only the decoded pixels are kept, any EXIF, XMP or ICC metadata such as
+/// GPS locations or camera details is discarded here and never makes it into
+/// the encoded variants.
+pub fn decode(file_name: &str, contents: &[u8]) -> Result<DynamicImage, ApiError> {
+    validate(file_name, contents)?;
+
+    reader(contents)?
+        .decode()
+        .map_err(|_| ApiError::unprocessable_entity([("images", format!("{file_name}: image could not be decoded"))]))
+}
+
+fn reader(contents: &[u8]) -> Result<Reader<Cursor<&[u8]>>, ApiError> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOCATION);
+
+    let mut reader = Reader::new(Cursor::new(contents))
+        .with_guessed_format()
+        .map_err(anyhow::Error::new)?;
+
+    reader.limits(limits);
+    Ok(reader)
+}
+
+/// Walks the png chunks, an `acTL` chunk marks an animated png and there
+/// should be nothing after the `IEND` chunk
+fn check_png(contents: &[u8]) -> Result<(), &'static str> {
+    let mut offset = 8;
+
+    while offset + 8 <= contents.len() {
+        let length = u32::from_be_bytes(contents[offset..offset + 4].try_into().unwrap()) as usize;
+        let kind = &contents[offset + 4..offset + 8];
+
+        // length, type, data and crc
+        offset = offset
+            .checked_add(12 + length)
+            .ok_or("image is malformed")?;
+
+        match kind {
+            b"acTL" => return Err("animated images are not supported"),
+            b"IEND" if offset == contents.len() => return Ok(()),
+            b"IEND" => return Err("image contains trailing data"),
+            _ => {}
+        }
+    }
+
+    Err("image is malformed")
+}
+
+/// Jpeg files end with an end of image marker, anything after it besides
+/// padding is considered trailing data
+fn check_jpeg(contents: &[u8]) -> Result<(), &'static str> {
+    let end = contents.iter()
+        .rposition(|byte| *byte != 0)
+        .ok_or("image is malformed")?;
+
+    if end < 1 || contents[end - 1..=end] != [0xFF, 0xD9] {
+        return Err("image contains trailing data");
+    }
+
+    Ok(())
+}
+
+/// Walks the gif blocks without decoding any of the frames, each image
+/// descriptor is a frame so more than one means the image is animated. There
+/// should be nothing after the trailer
+fn check_gif(contents: &[u8]) -> Result<(), &'static str> {
+    let byte = |offset: usize| contents.get(offset).copied().ok_or("image is malformed");
+
+    // The header and logical screen descriptor, followed by the global colour
+    // table if there is one
+    let mut offset = 13 + colour_table_size(byte(10)?);
+    let mut frames = 0;
+
+    loop {
+        match byte(offset)? {
+            0x2C => {
+                frames += 1;
+
+                if frames > 1 {
+                    return Err("animated images are not supported");
+                }
+
+                // The descriptor, local colour table and lzw minimum code size
+                offset += 10 + colour_table_size(byte(offset + 9)?) + 1;
+                offset = skip_sub_blocks(contents, offset)?;
+            }
+            // The introducer and label of the extension
+            0x21 => offset = skip_sub_blocks(contents, offset + 2)?,
+            0x3B if frames == 0 => return Err("image is malformed"),
+            0x3B if offset + 1 == contents.len() => return Ok(()),
+            0x3B => return Err("image contains trailing data"),
+            _ => return Err("image is malformed")
+        }
+    }
+}
+
+fn colour_table_size(flags: u8) -> usize {
+    match flags & 0x80 {
+        0 => 0,
+        _ => 3 << ((flags & 0x07) + 1)
+    }
+}
+
+/// Skips over a run of gif data sub blocks, each is prefixed by its length and
+/// the run is ended by an empty block. Returns the offset after the run
+fn skip_sub_blocks(contents: &[u8], mut offset: usize) -> Result<usize, &'static str> {
+    loop {
+        let length = *contents.get(offset).ok_or("image is malformed")? as usize;
+        offset += 1 + length;
+
+        if length == 0 {
+            return Ok(offset);
+        }
+    }
+}
+
+/// The riff header records the size of the file so trailing data is easy to
+/// spot, animated webp images set a flag in the extended `VP8X` header
+fn check_webp(contents: &[u8]) -> Result<(), &'static str> {
+    if contents.len() < 21 {
+        return Err("image is malformed");
+    }
+
+    let size = u32::from_le_bytes(contents[4..8].try_into().unwrap()) as usize;
+
+    if size + 8 != contents.len() {
+        return Err("image contains trailing data");
+    }
+
+    if &contents[12..16] == b"VP8X" && contents[20] & 0x02 != 0 {
+        return Err("animated images are not supported");
+    }
+
+    Ok(())
+}
+
+fn contains_markup(contents: &[u8]) -> bool {
+    let lowercase = contents.to_ascii_lowercase();
+
+    POLYGLOT_MARKERS.iter().any(|marker| {
+        lowercase.windows(marker.len()).any(|window| window == *marker)
+    })
+}
+
+/// Scales an image down to the given size, images which already fit are
+/// borrowed rather than copied
+pub fn resize(img: &DynamicImage, size: ImageSize) -> Cow<'_, DynamicImage> {
+    let (width, height) = size.dimensions(img.width(), img.height());
+
+    if width == img.width() && height == img.height() {
+        return Cow::Borrowed(img);
+    }
+
+    Cow::Owned(img.resize_exact(width, height, FilterType::Lanczos3))
+}
+
+pub fn encode(img: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>, anyhow::Error> {
+    match format {
+        ImageFormat::Webp => encode_webp(img),
+        ImageFormat::Avif => encode_avif(img)
+    }
+}
+
+fn encode_webp(img: &DynamicImage) -> Result<Vec<u8>, anyhow::Error> {
+    // The Webp Encoder doesnt support all image colour formats so standardize to
+    // rgb8, or rgba8 to keep transparency for images which have it
+    let img = match img.color().has_alpha() {
+        true => DynamicImage::ImageRgba8(img.to_rgba8()),
+        false => DynamicImage::ImageRgb8(img.to_rgb8())
+    };
+
+    let encoder = WebpEncoder::from_image(&img)
+        .map_err(|e| anyhow::anyhow!("Failed to encode webp: {e}"))?;
+
+    Ok(encoder.encode(WEBP_QUALITY).to_vec())
+}
+
+fn encode_avif(img: &DynamicImage) -> Result<Vec<u8>, anyhow::Error> {
+    let encoder = AvifEncoder::new()
+        .with_quality(AVIF_QUALITY)
+        .with_speed(AVIF_SPEED);
+
+    if img.color().has_alpha() {
+        let rgba = img.to_rgba8();
+
+        let pixels: Vec<RGBA8> = rgba
+            .pixels()
+            .map(|pixel| RGBA8::new(pixel[0], pixel[1], pixel[2], pixel[3]))
+            .collect();
+
+        let encoded = encoder
+            .encode_rgba(Img::new(&pixels[..], rgba.width() as usize, rgba.height() as usize))?;
+
+        return Ok(encoded.avif_file);
+    }
+
+    let rgb = img.to_rgb8();
+
+    let pixels: Vec<RGB8> = rgb
+        .pixels()
+        .map(|pixel| RGB8::new(pixel[0], pixel[1], pixel[2]))
+        .collect();
+
+    let encoded = encoder.encode_rgb(Img::new(&pixels[..], rgb.width() as usize, rgb.height() as usize))?;
+
+    Ok(encoded.avif_file)
+}
+
+/// Generates a compact placeholder for the image which clients can show
+/// while it is loading, see https://blurha.sh
+pub fn blurhash(img: &DynamicImage) -> Result<String, anyhow::Error> {
+    let small = img
+        .thumbnail(BLURHASH_SOURCE_SIZE, BLURHASH_SOURCE_SIZE)
+        .to_rgba8();
+
+    let (components_x, components_y) = BLURHASH_COMPONENTS;
+
+    blurhash::encode(components_x, components_y, small.width(), small.height(), small.as_raw())
+        .map_err(|e| anyhow::anyhow!("Failed to generate blurhash: {e:?}"))
+}
+
+/// Generates the variants of images stored before variants were introduced or
+/// under their previous names, see `variant_file_name`. Each is generated from
+/// the full size webp that was stored and has its dimensions and blurhash
+/// recorded in the same way as new uploads. Images which already have every
+/// variant are skipped so this can be run again if interrupted
+pub async fn backfill(
+    BackfillImagesCommandArguments {
+        postgres
+    }: BackfillImagesCommandArguments
+) -> Result<(), anyhow::Error> {
+    let pool = postgres::connect(postgres).await?;
+
+    let schematic_ids = sqlx::query_scalar!(
+        r#"select distinct schematic_id from schematic_images"#
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    tracing::info!("Backfilling the images of {} schematics", schematic_ids.len());
+
+    let mut failed = 0;
+
+    for schematic_id in schematic_ids {
+        if let Err(e) = backfill_schematic(&pool, schematic_id).await {
+            tracing::warn!("Failed to backfill the images of {}: {:?}", schematic_id, e);
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("Failed to backfill the images of {} schematics", failed);
+    }
+
+    tracing::info!("Finished backfilling images");
+
+    Ok(())
+}
+
+async fn backfill_schematic(pool: &sqlx::PgPool, schematic_id: Uuid) -> Result<(), anyhow::Error> {
+    let file_names = sqlx::query_scalar!(
+        r#"select file_name from schematic_images where schematic_id = $1"#,
+        schematic_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let location = super::schematic_image_path(&schematic_id);
+
+    for file_name in &file_names {
+        let path = location.clone();
+        let name = file_name.clone();
+
+        let metadata = tokio::task::spawn_blocking(move || backfill_image(&path, &name))
+            .await??;
+
+        if let Some(metadata) = metadata {
+            sqlx::query!(
+                r#"
+                update schematic_images
+                    set width = $1,
+                        height = $2,
+                        blurhash = $3
+                    where schematic_id = $4
+                    and file_name = $5
+                "#,
+                metadata.width as i32,
+                metadata.height as i32,
+                metadata.blurhash,
+                schematic_id,
+                file_name
+            )
+            .execute(pool)
+            .await?;
+        }
+    }
+
+    // Old names are only removed once every image has been backfilled as two
+    // images differing only by their extension shared the same old names
+    let current: HashSet<String> = file_names.iter()
+        .flat_map(|file_name| {
+            ImageSize::ALL.into_iter().flat_map(move |size| {
+                ImageFormat::ALL.into_iter().map(move |format| variant_file_name(file_name, size, format))
+            })
+        })
+        .collect();
+
+    let legacy: HashSet<String> = file_names.iter()
+        .flat_map(|file_name| legacy_variant_file_names(file_name))
+        .filter(|name| !current.contains(name))
+        .collect();
+
+    for name in legacy {
+        match tokio::fs::remove_file(location.join(name)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Generates the variants of a single image if any are missing, returning its
+/// metadata if it was backfilled
+fn backfill_image(location: &Path, file_name: &str) -> Result<Option<ImageMetadata>, anyhow::Error> {
+    let complete = ImageSize::ALL.iter()
+        .flat_map(|size| ImageFormat::ALL.iter().map(move |format| variant_file_name(file_name, *size, *format)))
+        .all(|variant| location.join(variant).exists());
+
+    if complete {
+        return Ok(None);
+    }
+
+    let full = variant_file_name(file_name, ImageSize::Full, ImageFormat::Webp);
+    let legacy = Path::new(file_name).with_extension(ImageFormat::Webp.extension());
+
+    let source = [location.join(full), location.join(legacy)]
+        .into_iter()
+        .find(|source| source.exists())
+        .ok_or_else(|| anyhow::anyhow!("No stored image to generate the variants of {file_name} from"))?;
+
+    let contents = std::fs::read(source)?;
+
+    let metadata = super::upload::save_image(location, file_name, &contents)
+        .map_err(|e| anyhow::anyhow!("Failed to generate the variants of {file_name}: {e:?}"))?;
+
+    Ok(Some(metadata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a gif with a two colour global palette and the given number of
+    /// single pixel frames, each preceded by a graphic control extension
+    fn gif(width: u16, height: u16, frames: usize) -> Vec<u8> {
+        let mut gif = b"GIF89a".to_vec();
+
+        gif.extend(width.to_le_bytes());
+        gif.extend(height.to_le_bytes());
+        gif.extend([0x80, 0, 0]);
+        gif.extend([0, 0, 0, 255, 255, 255]);
+
+        for _ in 0..frames {
+            gif.extend([0x21, 0xF9, 4, 0, 10, 0, 0, 0]);
+            gif.extend([0x2C, 0, 0, 0, 0, 1, 0, 1, 0, 0]);
+            gif.extend([2, 2, 0x44, 0x01, 0]);
+        }
+
+        gif.push(0x3B);
+        gif
+    }
+
+    #[test]
+    fn accepts_still_gif() {
+        let contents = gif(1, 1, 1);
+
+        assert!(validate("still.gif", &contents).is_ok());
+        assert!(decode("still.gif", &contents).is_ok());
+    }
+
+    #[test]
+    fn rejects_animated_gif() {
+        assert_eq!(check_gif(&gif(1, 1, 2)), Err("animated images are not supported"));
+        assert!(validate("animated.gif", &gif(1, 1, 2)).is_err());
+    }
+
+    #[test]
+    fn rejects_huge_gif_canvas() {
+        // A few dozen bytes describing a canvas of over four billion pixels,
+        // this must be rejected from the header alone
+        let contents = gif(u16::MAX, u16::MAX, 1);
+
+        assert_eq!(check_gif(&contents), Ok(()));
+        assert!(validate("huge.gif", &contents).is_err());
+        assert!(decode("huge.gif", &contents).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_gif() {
+        let mut trailing = gif(1, 1, 1);
+        trailing.extend(b"<?php");
+
+        let mut truncated = gif(1, 1, 1);
+        truncated.truncate(truncated.len() - 4);
+
+        assert_eq!(check_gif(&trailing), Err("image contains trailing data"));
+        assert_eq!(check_gif(&truncated), Err("image is malformed"));
+        assert_eq!(check_gif(&gif(1, 1, 0)), Err("image is malformed"));
+    }
+}
@@ -3,6 +3,9 @@ use std::path::PathBuf;
 use uuid::Uuid;
 
 pub mod upload;
+pub mod images;
+pub mod dependencies;
+pub mod blocks;
 pub mod schematics;
 
 #[cfg(feature="compression")]
@@ -12,6 +15,10 @@ pub const UPLOAD_PATH: &'static str = "static/upload/schematics";
 pub const SCHEMATIC_PATH: &'static str = "schematics";
 pub const IMAGE_PATH: &'static str = "images";
 
+/// Uploads are kept here until they have been processed, this is outside of
+/// the static directory so that unprocessed files are never served
+pub const PENDING_PATH: &str = "pending/schematics";
+
 pub fn schematic_image_path(schematic_id: &Uuid) -> PathBuf {
     schematic_upload_path(schematic_id).join(IMAGE_PATH)
 }
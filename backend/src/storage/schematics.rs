@@ -1,13 +1,50 @@
 use std::borrow::Cow;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use image::{Rgba, RgbaImage};
 use zune_inflate::DeflateDecoder as GzDecoder;
 
 use crate::error::ApiError;
 use crate::response::ApiResult;
 
+// Blocks which are stored within structure files but dont count towards the
+// number of blocks in the build
+const EMPTY_BLOCKS: [&str; 3] = ["minecraft:air", "minecraft:cave_air", "minecraft:void_air"];
+
+/// Structures wider or longer than this are not given a preview, vanilla
+/// structure blocks are limited to 48 but other tools can save far larger ones
+const MAX_PREVIEW_SIZE: i32 = 512;
+
 #[derive(Deserialize, Debug)]
 pub struct Schematic<'a> {
-    pub palette: Vec<PaletteEntry<'a>>
+    #[serde(default)]
+    pub size: Vec<i32>,
+    pub palette: Vec<PaletteEntry<'a>>,
+    #[serde(default)]
+    pub blocks: Vec<BlockEntry>
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BlockEntry {
+    /// The index of this blocks state within the palette
+    pub state: usize,
+    /// The x, y and z position of this block within the structure
+    #[serde(default)]
+    pub pos: Vec<i32>
+}
+
+/// Details read from a schematic file, see `read_metadata`
+#[derive(Debug, Default)]
+pub struct SchematicMetadata {
+    pub mods: HashSet<String>,
+    /// The ids of every block used, excluding air
+    pub blocks: HashSet<String>,
+    /// The number of each block placed, excluding air. Blocks within the
+    /// palette which are never placed are not included
+    pub block_counts: HashMap<String, i32>,
+    pub width: i32,
+    pub height: i32,
+    pub length: i32,
+    pub block_count: i32
 }
 
 #[derive(Deserialize, Debug)]
@@ -16,19 +53,115 @@ pub struct PaletteEntry<'a> {
     pub name: Cow<'a, str>
 }
 
-pub fn extract_modlist(contents: &Vec<u8>) -> Result<HashSet<String>, ApiError> {
-    let decompressed = decompress(&contents)?;
-    
-    let schematic = fastnbt::from_bytes::<Schematic>(&decompressed)
+/// Reads the size, number of non air blocks and the blocks and mods used by a
+/// decompressed structure file
+pub fn read_metadata(decompressed: &[u8]) -> Result<SchematicMetadata, ApiError> {
+    let schematic = fastnbt::from_bytes::<Schematic>(decompressed)
         .map_err(|_| ApiError::BadRequest)?;
 
-    let mod_list: HashSet<String> = schematic.palette
+    let mods: HashSet<String> = schematic.palette
         .iter()
         .filter_map(|e| e.name.split(":").next())
         .map(|mod_id| mod_id.to_string())
         .collect();
 
-    Ok(mod_list)
+    let blocks: HashSet<String> = schematic.palette
+        .iter()
+        .filter(|entry| !EMPTY_BLOCKS.contains(&&*entry.name))
+        .map(|entry| entry.name.to_string())
+        .collect();
+
+    // Blocks are counted by their index within the palette first as the same
+    // block can appear several times in the palette with different states
+    let mut state_counts = vec![0; schematic.palette.len()];
+
+    for block in &schematic.blocks {
+        if let Some(count) = state_counts.get_mut(block.state) {
+            *count += 1;
+        }
+    }
+
+    let mut block_counts: HashMap<String, i32> = HashMap::new();
+
+    for (entry, count) in schematic.palette.iter().zip(state_counts) {
+        if count > 0 && !EMPTY_BLOCKS.contains(&&*entry.name) {
+            *block_counts.entry(entry.name.to_string()).or_default() += count;
+        }
+    }
+
+    let block_count: i32 = block_counts.values().sum();
+
+    let (width, height, length) = match schematic.size[..] {
+        [width, height, length] => (width, height, length),
+        _ => (0, 0, 0)
+    };
+
+    Ok(SchematicMetadata {
+        mods,
+        blocks,
+        block_counts,
+        width,
+        height,
+        length,
+        block_count
+    })
+}
+
+/// Renders a top down view of a decompressed structure file, each column is
+/// coloured by the highest block within it and shaded by its height so that
+/// the shape of a build can be made out. Structures without any blocks or
+/// which are too large return `None`
+pub fn render_preview(decompressed: &[u8]) -> Result<Option<RgbaImage>, ApiError> {
+    let schematic = fastnbt::from_bytes::<Schematic>(decompressed)
+        .map_err(|_| ApiError::BadRequest)?;
+
+    let (width, height, length) = match schematic.size[..] {
+        [width, height, length] if (1..=MAX_PREVIEW_SIZE).contains(&width)
+            && (1..=MAX_PREVIEW_SIZE).contains(&length)
+            && height > 0 => (width, height, length),
+        _ => return Ok(None)
+    };
+
+    // The height of the highest block in each column and its palette entry
+    let mut columns: Vec<Option<(i32, &PaletteEntry)>> = vec![None; (width * length) as usize];
+
+    for block in &schematic.blocks {
+        let [x, y, z] = block.pos[..] else {
+            continue;
+        };
+
+        let Some(entry) = schematic.palette.get(block.state) else {
+            continue;
+        };
+
+        if !(0..width).contains(&x) || !(0..length).contains(&z) || EMPTY_BLOCKS.contains(&&*entry.name) {
+            continue;
+        }
+
+        let column = &mut columns[(z * width + x) as usize];
+
+        if column.is_none_or(|(top, _)| y > top) {
+            *column = Some((y, entry));
+        }
+    }
+
+    if columns.iter().all(Option::is_none) {
+        return Ok(None);
+    }
+
+    let preview = RgbaImage::from_fn(width as u32, length as u32, |x, z| {
+        match columns[(z * width as u32 + x) as usize] {
+            Some((y, entry)) => {
+                let shade = 0.5 + 0.5 * (y + 1).clamp(1, height) as f32 / height as f32;
+                let [r, g, b] = block_colour(&entry.name).map(|channel| (channel as f32 * shade) as u8);
+
+                Rgba([r, g, b, 255])
+            }
+            None => Rgba([0, 0, 0, 0])
+        }
+    });
+
+    Ok(Some(preview))
 }
 
 pub fn decompress(data: &Vec<u8>) -> ApiResult<Vec<u8>> {
@@ -36,4 +169,17 @@ pub fn decompress(data: &Vec<u8>) -> ApiResult<Vec<u8>> {
     let decoded = decoder.decode_gzip().map_err(|_| ApiError::BadRequest)?;
 
     Ok(decoded)
-}
\ No newline at end of file
+}
+
+/// Structure files only store the ids of blocks so a colour is derived from
+/// the id instead, the same block is always drawn in the same colour. Colours
+/// are kept away from black so that shading them by height stays visible
+fn block_colour(name: &str) -> [u8; 3] {
+    let hash = name.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    });
+
+    let [r, g, b, _] = hash.to_le_bytes();
+
+    [r / 2 + 96, g / 2 + 96, b / 2 + 96]
+}
@@ -1,9 +1,10 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::collections::HashSet;
-use image::DynamicImage;
 
-use rayon::iter::{ParallelIterator, IntoParallelIterator, IntoParallelRefIterator};
-use webp::Encoder as WebpEncoder;
+use image::imageops::{self, FilterType};
+use image::DynamicImage;
+use rayon::iter::{ParallelIterator, IntoParallelRefIterator, IntoParallelIterator};
+use sha2::{Digest, Sha256};
 
 use tempfile::{Builder, TempDir};
 use uuid::Uuid;
@@ -12,7 +13,8 @@ use crate::{error::ApiError, middleware::files::FileUpload};
 #[cfg(feature="compression")]
 use crate::storage::compression;
 
-use super::schematics::{decompress, extract_modlist};
+use super::images::{self, ImageFormat, ImageMetadata, ImageSize};
+use super::schematics::{decompress, read_metadata, render_preview, SchematicMetadata};
 
 // https://gist.github.com/leommoore/f9e57ba2aa4bf197ebc5#archive-files
 const GZIP_SIGNATURE: [u8; 2] = [0x1f, 0x8b];
@@ -20,107 +22,369 @@ const GZIP_SIGNATURE: [u8; 2] = [0x1f, 0x8b];
 const MAX_FILE_SIZE: usize = 256 * 1024; // 256kb
 const MAX_IMAGE_SIZE: usize = 5 * 1024 * 1024; // 5mb
 
+/// Processed files are written here within the pending directory, they are
+/// only moved into the upload directory once the schematic is known to still
+/// exist, see `publish_processed`
+const PROCESSED_PATH: &str = "processed";
+
 pub struct SchematicTransfer {
     pub file_name: String,
     pub requirements: HashSet<String>
 }
 
-pub fn build_upload_directory(
+/// The result of `process_pending`, details about each of the images and
+/// schematic files along with their file names
+pub struct ProcessedUpload {
+    pub images: Vec<(String, ImageMetadata)>,
+    pub files: Vec<(String, FileMetadata)>
+}
+
+pub struct FileMetadata {
+    /// The size of the file as it is stored
+    pub file_size: i32,
+    /// A sha256 hash of the decompressed file, identical structures share the
+    /// same fingerprint however they were compressed
+    pub fingerprint: String,
+    /// This is only present for files which could be read as a structure
+    pub schematic: Option<SchematicMetadata>,
+    /// A top down view of the structure encoded as webp, see `preview_file_name`
+    pub preview: Option<Vec<u8>>
+}
+
+/// Creates a directory to hold uploaded files until they have been processed,
+/// if the request fails before the directory is persisted it is removed.
+pub fn build_pending_directory(
     schematic_id: &Uuid
 ) -> Result<TempDir, anyhow::Error> {
+    std::fs::create_dir_all(super::PENDING_PATH)?;
+
     Builder::new()
         .prefix(&schematic_id.to_string())
-        .tempdir_in(super::UPLOAD_PATH)
+        .tempdir_in(super::PENDING_PATH)
         .map_err(anyhow::Error::new)
 }
 
-pub async fn save_images(location: &TempDir, images: Vec<FileUpload>) -> Result<Vec<String>, ApiError> {
+/// The name of a pending directory relative to `PENDING_PATH`, this is what
+/// should be passed to `Job::ProcessUpload`
+pub fn pending_directory_name(location: &TempDir) -> Result<String, anyhow::Error> {
+    location.path()
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| anyhow::anyhow!("Pending directory has no name"))
+}
+
+/// Stores uploaded images as they are within a pending directory so that they
+/// can be encoded later by a `Job::ProcessUpload`, returning their file names.
+/// Only inexpensive checks are made here, see `images::validate`, images which
+/// cannot be decoded will cause the job to fail.
+pub async fn stage_images(location: &TempDir, images: Vec<FileUpload>) -> Result<Vec<String>, ApiError> {
     let path = location.path().join(super::IMAGE_PATH);
     tokio::fs::create_dir(&path).await.map_err(anyhow::Error::new)?;
-    
-    // When uploading multiple images we'll want to parallize processing them since this can
+
+    let mut file_names = Vec::with_capacity(images.len());
+
+    for image in images {
+        let file_name = image.file_name.ok_or(ApiError::BadRequest)?;
+
+        if image.contents.len() > MAX_IMAGE_SIZE {
+            return Err(ApiError::BadRequest);
+        }
+
+        images::validate(&file_name, &image.contents)?;
+
+        tokio::fs::write(path.join(&file_name), &image.contents)
+            .await
+            .map_err(anyhow::Error::new)?;
+
+        file_names.push(file_name);
+    }
+
+    Ok(file_names)
+}
+
+/// Stores uploaded schematic files within a pending directory in the same way
+/// as `stage_images`, returning their file names
+pub async fn stage_schematics(location: &TempDir, files: Vec<FileUpload>) -> Result<Vec<String>, ApiError> {
+    let path = location.path().join(super::SCHEMATIC_PATH);
+    tokio::fs::create_dir(&path).await.map_err(anyhow::Error::new)?;
+
+    let mut file_names = Vec::with_capacity(files.len());
+
+    for file in files {
+        let file_name = file.file_name.ok_or(ApiError::BadRequest)?;
+
+        if file.contents.len() > MAX_FILE_SIZE || !is_nbt(&file_name, &file.contents) {
+            return Err(ApiError::BadRequest);
+        }
+
+        if file_names.contains(&file_name) {
+            return Err(ApiError::unprocessable_entity([("files", "file names must be unique")]));
+        }
+
+        tokio::fs::write(path.join(&file_name), &file.contents)
+            .await
+            .map_err(anyhow::Error::new)?;
+
+        file_names.push(file_name);
+    }
+
+    Ok(file_names)
+}
+
+/// Processes all of the images and schematic files within a pending directory
+/// created by `build_pending_directory`, saving them alongside the staged files
+/// and returning the metadata of each image and schematic file. Nothing is
+/// written to the upload directory here, see `publish_processed`
+/// 
+/// This is expensive and blocking so should only be called from a worker.
+pub fn process_pending(pending: &Path) -> Result<ProcessedUpload, ApiError> {
+    let images = read_pending(&pending.join(super::IMAGE_PATH))?;
+    let files = read_pending(&pending.join(super::SCHEMATIC_PATH))?;
+
+    let image_path = pending.join(PROCESSED_PATH).join(super::IMAGE_PATH);
+    let file_path = pending.join(PROCESSED_PATH).join(super::SCHEMATIC_PATH);
+
+    std::fs::create_dir_all(&image_path).map_err(anyhow::Error::new)?;
+    std::fs::create_dir_all(&file_path).map_err(anyhow::Error::new)?;
+
+    // When processing multiple images we'll want to parallize processing them since this can
     // be quite slow especially for larger images. In testing within the limits of enforced
     // higher up (10 images up to 5mb) this allows for all images to be processed within the
-    // timespan of most costly image signifigantly improving response times 
-    images.into_par_iter()
-        .map(|image| -> Result<String, ApiError> {
-            // We consume the files vector here so we dont need to clone the file 
-            // name
-            let file_name = image.file_name.ok_or(ApiError::BadRequest)?;
-            save_image(&path, &file_name, &image.contents)?;
-
-            Ok(file_name)
+    // timespan of most costly image signifigantly improving processing times 
+    let images = images.par_iter()
+        .map(|(file_name, contents)| {
+            save_image(&image_path, file_name, contents)
+                .map(|metadata| (file_name.clone(), metadata))
+        })
+        .collect::<Result<Vec<_>, ApiError>>()?;
+
+    // Unlike images processing nbt files is much cheaper, although if the feature is
+    // enabled they will be compressed so we still process them in parralel
+    let files = files.par_iter()
+        .map(|(file_name, contents)| {
+            save_schematic(&file_path, file_name, contents)
+                .map(|metadata| (file_name.clone(), metadata))
         })
-        .collect::<Result<Vec<String>, ApiError>>()
+        .collect::<Result<Vec<_>, ApiError>>()?;
+
+    Ok(ProcessedUpload { images, files })
 }
 
-pub fn save_image(location: &PathBuf, file_name: &str, contents: &Vec<u8>) -> Result<(), ApiError> {
+/// Moves the files written by `process_pending` into the upload directory of
+/// the schematic, replacing any left by a previous attempt. This should be
+/// called while the schematic is locked so that it cannot be deleted part way
+/// through, leaving the files behind
+pub fn publish_processed(pending: &Path, schematic_id: &Uuid) -> Result<(), anyhow::Error> {
+    let processed = pending.join(PROCESSED_PATH);
+
+    let locations = [
+        (processed.join(super::IMAGE_PATH), super::schematic_image_path(schematic_id)),
+        (processed.join(super::SCHEMATIC_PATH), super::schematic_file_path(schematic_id))
+    ];
+
+    for (from, to) in locations {
+        if !from.exists() {
+            continue;
+        }
+
+        std::fs::create_dir_all(&to)?;
+
+        for entry in std::fs::read_dir(&from)? {
+            let entry = entry?;
+            std::fs::rename(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The names of the images and schematic files staged within a pending
+/// directory, this is used to clean up after uploads which could not be
+/// processed
+pub fn staged_file_names(pending: &Path) -> (Vec<String>, Vec<String>) {
+    let names = |location: &Path| -> Vec<String> {
+        std::fs::read_dir(location)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    (names(&pending.join(super::IMAGE_PATH)), names(&pending.join(super::SCHEMATIC_PATH)))
+}
+
+fn read_pending(location: &Path) -> Result<Vec<(String, Vec<u8>)>, ApiError> {
+    if !location.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut files = Vec::new();
+
+    for entry in std::fs::read_dir(location).map_err(anyhow::Error::new)? {
+        let entry = entry.map_err(anyhow::Error::new)?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let contents = std::fs::read(entry.path()).map_err(anyhow::Error::new)?;
+
+        files.push((file_name, contents));
+    }
+
+    Ok(files)
+}
+
+/// Decodes an image and stores it in every `ImageSize` and `ImageFormat`,
+/// see `images::variant_file_name` for how each variant is named
+pub fn save_image(location: &Path, file_name: &str, contents: &[u8]) -> Result<ImageMetadata, ApiError> {
     if contents.len() > MAX_IMAGE_SIZE {
         return Err(ApiError::BadRequest)
     }
 
-    let path = location.join(&file_name).with_extension("webp");
+    let img = images::decode(file_name, contents)?;
 
-    let img = image::load_from_memory(&contents)
-        .map_err(|_| ApiError::BadRequest)?;
-    
-    // The Webp Encoder doesnt support all image colour formats so standardize to rgb8
-    let img = DynamicImage::ImageRgb8(img.into_rgb8());
+    // Avif encoding is far slower than webp so each variant is encoded in
+    // parallel, rayon will share the threads with the other images. Each size
+    // is only resized once and shared between its formats
+    ImageSize::ALL.into_par_iter()
+        .map(|size| {
+            let resized = images::resize(&img, size);
 
-    let encoder = WebpEncoder::from_image(&img).map_err(|_| ApiError::BadRequest)?;
-    let webp = encoder.encode(90f32).to_vec();
+            ImageFormat::ALL.into_par_iter()
+                .map(|format| {
+                    let encoded = images::encode(&resized, format)?;
+                    let path = location.join(images::variant_file_name(file_name, size, format));
 
-    std::fs::write(&path, &webp).map_err(anyhow::Error::new)?;
+                    std::fs::write(path, encoded)?;
+                    Ok(())
+                })
+                .collect::<Result<(), anyhow::Error>>()
+        })
+        .collect::<Result<(), anyhow::Error>>()?;
 
-    Ok(())
+    Ok(ImageMetadata {
+        width: img.width(),
+        height: img.height(),
+        blurhash: images::blurhash(&img)?
+    })
 }
 
-// todo: replace this, it has numerous issues. First the schematics are decoded twice,
-// once when extracting the file list, the again when being uploaded. Second, all files
-// will be decoded then checked for their size, format etc. 
-pub async fn save_schematics(location: &TempDir, files: Vec<FileUpload>) -> Result<(Vec<String>, HashSet<String>), ApiError> {
-    let path = location.path().join(super::SCHEMATIC_PATH);
-    tokio::fs::create_dir(&path).await.map_err(anyhow::Error::new)?;
+/// Decompresses a schematic file, reading its metadata, before storing it
+/// compressed if the feature is enabled along with its preview
+pub fn save_schematic(location: &Path, file_name: &str, contents: &Vec<u8>) -> Result<FileMetadata, ApiError> {
+    let (contents, metadata) = prepare_schematic(file_name, contents)?;
 
-    let mods: HashSet<String> = files.par_iter()
-        .filter_map(|file| extract_modlist(&file.contents).ok())
-        .flatten()
-        .collect();
-
-    // Unlike image uploads processing nbt files is much cheaper, although if the feature is
-    // enabled they will be compressed so we still upload them in parralel
-    let files = files.into_par_iter()
-        .map(|file| -> Result<String, ApiError> {
-            // We consume the files vector here so we dont need to clone the file 
-            // name
-            let file_name = file.file_name.ok_or(ApiError::BadRequest)?;
-            save_schematic(&path, &file_name, &file.contents)?;
-
-            Ok(file_name)
-        })
-        .collect::<Result<Vec<String>, ApiError>>()?;
+    std::fs::write(location.join(file_name), &contents).map_err(anyhow::Error::new)?;
+
+    if let Some(preview) = &metadata.preview {
+        std::fs::write(location.join(preview_file_name(file_name)), preview).map_err(anyhow::Error::new)?;
+    }
+
+    Ok(metadata)
+}
 
-    Ok((files, mods))
+/// The name of the file the preview of a schematic file is stored as, this is
+/// kept alongside the file itself so for `cannon.nbt` it would be
+/// `cannon.nbt.preview.webp`
+pub fn preview_file_name(file_name: &str) -> String {
+    format!("{file_name}.preview.webp")
 }
 
-pub fn save_schematic(location: &PathBuf, file_name: &str, contents: &Vec<u8>) -> Result<(), ApiError> {
-    if contents.len() > MAX_FILE_SIZE || !is_nbt(&file_name, &contents) {
+/// Validates and reads the metadata of a schematic file, returning the
+/// contents as they should be stored. See `save_schematic`
+pub fn prepare_schematic(file_name: &str, contents: &Vec<u8>) -> Result<(Vec<u8>, FileMetadata), ApiError> {
+    if contents.len() > MAX_FILE_SIZE || !is_nbt(file_name, contents) {
         return Err(ApiError::BadRequest)
     }
 
-    let path = location.join(&file_name);
+    let contents = decompress(contents)?;
 
-    if path.exists() {
-        return Err(ApiError::BadRequest);
-    }
+    let fingerprint = format!("{:x}", Sha256::digest(&contents));
+
+    // Files which cannot be read as a structure are still stored, they just
+    // wont have any metadata, dependencies or preview
+    let schematic = match read_metadata(&contents) {
+        Ok(metadata) => Some(metadata),
+        Err(e) => {
+            tracing::warn!("Storing {} without metadata, it could not be read as a structure: {:?}", file_name, e);
+            None
+        }
+    };
 
-    let contents = decompress(&contents)?;
+    let preview = match schematic {
+        Some(_) => render_preview(&contents)?.map(encode_preview).transpose()?,
+        None => None
+    };
 
     #[cfg(feature="compression")]
     let contents = compression::compress(&contents)?;
 
-    std::fs::write(path, &contents).map_err(anyhow::Error::new)?;
-    Ok(())
+    let metadata = FileMetadata {
+        file_size: contents.len() as i32,
+        fingerprint,
+        schematic,
+        preview
+    };
+
+    Ok((contents, metadata))
+}
+
+/// New contents for a stored file which have been written alongside it but not
+/// yet moved into place, see `stage_replacement`. If this is dropped without
+/// being applied the new contents are removed and the original is untouched
+pub struct Replacement {
+    temporary: Option<PathBuf>,
+    destination: PathBuf
+}
+
+/// Writes the new contents of a stored file alongside the original without
+/// replacing it, this way the original is kept if anything else fails. Once
+/// everything else has succeeded `Replacement::apply` renames it over the
+/// original so that the file is never partially written
+pub async fn stage_replacement(location: &Path, file_name: &str, contents: &[u8]) -> Result<Replacement, anyhow::Error> {
+    let temporary = location.join(format!(".{file_name}.{}.replacing", Uuid::new_v4()));
+
+    let replacement = Replacement {
+        temporary: Some(temporary.clone()),
+        destination: location.join(file_name)
+    };
+
+    tokio::fs::write(&temporary, contents).await?;
+
+    Ok(replacement)
+}
+
+impl Replacement {
+    /// Moves the new contents over the original file
+    pub async fn apply(mut self) -> Result<(), anyhow::Error> {
+        if let Some(temporary) = self.temporary.take() {
+            if let Err(e) = tokio::fs::rename(&temporary, &self.destination).await {
+                let _ = tokio::fs::remove_file(&temporary).await;
+                return Err(e.into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Replacement {
+    fn drop(&mut self) {
+        if let Some(temporary) = self.temporary.take() {
+            let _ = std::fs::remove_file(temporary);
+        }
+    }
+}
+
+/// Structures are usually only a few dozen blocks across so previews are scaled
+/// up to around the size of a thumbnail, keeping each block a sharp square
+fn encode_preview(preview: image::RgbaImage) -> Result<Vec<u8>, anyhow::Error> {
+    let longest = preview.width().max(preview.height());
+    let scale = (ImageSize::Thumbnail.max_dimension() / longest).max(1);
+
+    let scaled = imageops::resize(&preview, preview.width() * scale, preview.height() * scale, FilterType::Nearest);
+
+    images::encode(&DynamicImage::ImageRgba8(scaled), ImageFormat::Webp)
 }
 
 fn is_nbt(file_name: &str, contents: &Vec<u8>) -> bool {